@@ -0,0 +1,38 @@
+// Tokenizer throughput on scripts of growing size, up to 1 MB, alone and
+// as part of eval.
+// Run with `cargo bench --bench lexer`: the time per byte should stay flat.
+
+use std::time::Instant;
+
+use elk_rs::elk::Js;
+use elk_rs::lexer::Lexer;
+
+const CHUNK: &str = "x = x + 1.5; // counter\n/* block comment */ y = x * 2 - (x >>> 3);\n";
+
+fn script(size: usize) -> String {
+    let mut code = String::from("let x = 0, y = 0;\n");
+    while code.len() < size {
+        code.push_str(CHUNK);
+    }
+    code
+}
+
+fn main() {
+    for kb in [64, 128, 256, 512, 1024] {
+        let code = script(kb * 1024);
+        let start = Instant::now();
+        let n = Lexer::new(&code).count();
+        let elapsed = start.elapsed();
+        println!("{:5} KB: {:8.2} ms, {:6.2} ns/byte, {} tokens",
+                 kb, elapsed.as_secs_f64() * 1e3, elapsed.as_nanos() as f64 / code.len() as f64, n);
+
+        let start = Instant::now();
+        let mut buf = vec![0u8; 8192];
+        Js::new(&mut buf, |js| {
+            js.eval(&code).unwrap();
+            let elapsed = start.elapsed();
+            println!("{:5} KB: {:8.2} ms, {:6.2} ns/byte, eval",
+                     kb, elapsed.as_secs_f64() * 1e3, elapsed.as_nanos() as f64 / code.len() as f64);
+        }).unwrap();
+    }
+}
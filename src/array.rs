@@ -0,0 +1,232 @@
+// Array methods: `arr.push(x)` and friends.
+//
+// A method is a `Type::BUILTIN` value, see `builtin.rs`. It is called like
+// a native function, with the array as `this`. Callbacks can run GC, which
+// moves entities: methods that call them read the array, the callback and
+// their own results back from the stack after each call.
+
+use crate::builtin::{self, Member, Table};
+use crate::core::*;
+use crate::elk::Js;
+use crate::error::ErrorKind;
+
+pub(crate) const METHODS: Table = Table { call: None, members: &[
+    ("push", Member::Fn(push)),
+    ("pop", Member::Fn(pop)),
+    ("slice", Member::Fn(slice)),
+    ("indexOf", Member::Fn(index_of)),
+    ("join", Member::Fn(join)),
+    ("forEach", Member::Fn(for_each)),
+    ("map", Member::Fn(map)),
+    ("filter", Member::Fn(filter)),
+    ("reduce", Member::Fn(reduce)),
+]};
+
+/// What a member key of an array or a string refers to. Methods are
+/// indexes in their table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Key {
+    Index(JsOff),
+    Length,
+    Method(usize),
+    None,
+}
+
+// Key for a number: an index if it is an integer below 2^32 - 1
+pub(crate) fn index(d: f64) -> Key {
+    if d >= 0.0 && d < JsOff::MAX as f64 && d == d as JsOff as f64 { Key::Index(d as JsOff) } else { Key::None }
+}
+
+// Key for a name: a canonical index like "12", `length` or a method from
+// the table
+pub(crate) fn key(name: &str, methods: usize) -> Key {
+    let digits = !name.is_empty() && name.bytes().all(|c| c.is_ascii_digit());
+    if digits && (name == "0" || !name.starts_with('0')) {
+        return match name.parse::<JsOff>() {
+            Ok(i) if i < JsOff::MAX => Key::Index(i),
+            _ => Key::None,
+        }
+    }
+    if name == "length" { return Key::Length }
+    builtin::find(methods, name).map_or(Key::None, Key::Method)
+}
+
+// The array `this`, or a TypeError
+fn this(js: &mut Js<'_, '_>, argv: JsOff) -> JsVal {
+    let arr = js.this_arg(argv);
+    if v_type(arr) == Type::ARR { arr } else { js.mk_err(ErrorKind::Type, "not an array") }
+}
+
+// Index from a relative position argument: negative ones count from the
+// end, and it is clamped to the array
+fn position(v: JsVal, len: JsOff, default: JsOff) -> JsOff {
+    if v_type(v) == Type::UNDEF { return default }
+    let d = v_num(v);
+    if d.is_nan() { return 0 }
+    if d < 0.0 { (len as f64 + d).max(0.0) as JsOff } else { d.min(len as f64) as JsOff }
+}
+
+fn push(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = this(js, argv);
+    if is_err(arr) { return arr }
+    for i in 0..argc {
+        let res = js.arr_set(arr, js.arr_len(arr), js.arg(argv, argc, i));
+        if is_err(res) { return res }
+    }
+    tok_val(js.arr_len(arr) as f64)
+}
+
+fn pop(js: &mut Js<'_, '_>, argv: JsOff, _argc: usize) -> JsVal {
+    let arr = this(js, argv);
+    if is_err(arr) { return arr }
+    let len = js.arr_len(arr);
+    if len == 0 { return make_undef() }
+    let v = js.arr_get(arr, len - 1);
+    js.set_len(arr, len - 1);
+    if v == HOLE { make_undef() } else { v }
+}
+
+fn slice(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = this(js, argv);
+    if is_err(arr) { return arr }
+    let len = js.arr_len(arr);
+    let start = position(js.arg(argv, argc, 0), len, 0);
+    let end = position(js.arg(argv, argc, 1), len, len).max(start);
+
+    let res = js.mk_arr(end - start);
+    if is_err(res) { return res }
+    for i in start..end {
+        js.arr_set(res, i - start, js.arr_get(arr, i));
+    }
+    res
+}
+
+fn index_of(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = this(js, argv);
+    if is_err(arr) { return arr }
+    let len = js.arr_len(arr);
+    let x = js.arg(argv, argc, 0);
+    let from = position(js.arg(argv, argc, 1), len, 0);
+    let found = (from..len).find(|&i| {
+        let v = js.arr_get(arr, i);
+        v != HOLE && js.strict_eq(v, x)
+    });
+    tok_val(found.map_or(-1.0, |i| i as f64))
+}
+
+fn join(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = this(js, argv);
+    if is_err(arr) { return arr }
+    let sep = js.arg(argv, argc, 0);
+    if v_type(sep) == Type::UNDEF { return js.join(arr, None) }
+    let sep = js.stringify(sep);
+    if is_err(sep) { return sep }
+    js.join(arr, Some(sep))
+}
+
+// Check the array and the callback, the first argument
+fn iteration(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = this(js, argv);
+    if is_err(arr) { return arr }
+    if !is_func(js.arg(argv, argc, 0)) { return js.mk_err(ErrorKind::Type, "callback is not a function") }
+    arr
+}
+
+// Call the callback with element `i`, unless it is a hole. The element is
+// passed after `acc` if given, as in `reduce`. Returns HOLE for holes
+fn visit(js: &mut Js<'_, '_>, argv: JsOff, argc: usize, i: JsOff, acc: Option<JsVal>) -> JsVal {
+    let arr = js.this_arg(argv);
+    let v = js.arr_get(arr, i);
+    if v == HOLE { return HOLE }
+    let f = js.arg(argv, argc, 0);
+    let idx = tok_val(i as f64);
+    match acc {
+        Some(acc) => js.call_fn(f, make_undef(), &[acc, v, idx, arr]),
+        None => js.call_fn(f, make_undef(), &[v, idx, arr]),
+    }
+}
+
+fn for_each(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = iteration(js, argv, argc);
+    if is_err(arr) { return arr }
+    for i in 0..js.arr_len(arr) {
+        let res = visit(js, argv, argc, i, None);
+        if is_err(res) { return res }
+    }
+    make_undef()
+}
+
+fn map(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = iteration(js, argv, argc);
+    if is_err(arr) { return arr }
+    let len = js.arr_len(arr);
+    let res = js.mk_arr(len);
+    if is_err(res) { return res }
+    let res = js.push(res);
+    if is_err(res) { return res }
+    let slot = js.sp();
+
+    for i in 0..len {
+        let v = visit(js, argv, argc, i, None);
+        if is_err(v) { return v }
+        let res = js.load_val(slot);
+        // The callback may have shrunk the array, the rest stays holes
+        if v != HOLE && i < js.arr_len(res) { js.arr_set(res, i, v); }
+    }
+    js.load_val(slot)
+}
+
+fn filter(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = iteration(js, argv, argc);
+    if is_err(arr) { return arr }
+    let len = js.arr_len(arr);
+    let res = js.mk_arr(0);
+    if is_err(res) { return res }
+    let res = js.push(res);
+    if is_err(res) { return res }
+    let slot = js.sp();
+    // The element being tested, as the callback may change the array
+    let res = js.push(make_undef());
+    if is_err(res) { return res }
+
+    for i in 0..len {
+        js.save_val(slot - 8, js.arr_get(js.this_arg(argv), i));
+        let keep = visit(js, argv, argc, i, None);
+        if is_err(keep) { return keep }
+        if keep != HOLE && js.truthy(keep) {
+            let res = js.load_val(slot);
+            let res = js.arr_set(res, js.arr_len(res), js.load_val(slot - 8));
+            if is_err(res) { return res }
+        }
+    }
+    js.load_val(slot)
+}
+
+fn reduce(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let arr = iteration(js, argv, argc);
+    if is_err(arr) { return arr }
+    let len = js.arr_len(arr);
+    let mut i = 0;
+    let acc = if argc >= 2 {
+        js.arg(argv, argc, 1)
+    } else {
+        while i < len && js.arr_get(arr, i) == HOLE {
+            i += 1;
+        }
+        if i == len { return js.mk_err(ErrorKind::Type, "reduce of empty array with no initial value") }
+        i += 1;
+        js.arr_get(arr, i - 1)
+    };
+    let res = js.push(acc);
+    if is_err(res) { return res }
+    let slot = js.sp();
+
+    while i < len {
+        let acc = js.load_val(slot);
+        let res = visit(js, argv, argc, i, Some(acc));
+        if is_err(res) { return res }
+        if res != HOLE { js.save_val(slot, res) }
+        i += 1;
+    }
+    js.load_val(slot)
+}
@@ -0,0 +1,121 @@
+// Built-in functions and objects: methods of arrays, strings and numbers,
+// and globals like `Math`, `JSON` and `parseInt`.
+//
+// They live in tables of named members. A `Type::BUILTIN` value holds the
+// number of its table and the index in it, so built-ins cost no JS memory.
+// The index `OBJECT` stands for the table itself, an object like `String`
+// whose members are read from the table, and which may be callable too.
+// Names that no scope defines are looked up in `GLOBALS`, so a script can
+// shadow them with its own.
+
+use crate::core::*;
+use crate::elk::Js;
+use crate::error::ErrorKind;
+use crate::{array, json, number, string};
+
+pub(crate) type Method = for<'a, 'id> fn(&mut Js<'a, 'id>, JsOff, usize) -> JsVal;
+
+/// A member of a built-in table
+#[derive(Clone, Copy)]
+pub(crate) enum Member {
+    Fn(Method),
+    // A function of one number, like `Math.sqrt`
+    Unary(fn(f64) -> f64),
+    Num(f64),
+    Table(usize),
+}
+
+/// A built-in object: its members, and what calling it does, if anything
+pub(crate) struct Table {
+    pub(crate) call: Option<Method>,
+    pub(crate) members: &'static [(&'static str, Member)],
+}
+
+pub(crate) const ARRAY_METHODS: usize = 0;
+pub(crate) const STRING_METHODS: usize = 1;
+pub(crate) const STRING: usize = 2;
+pub(crate) const NUMBER_METHODS: usize = 3;
+pub(crate) const NUMBER: usize = 4;
+pub(crate) const MATH: usize = 5;
+pub(crate) const JSON: usize = 6;
+pub(crate) const GLOBAL: usize = 7;
+
+const TABLES: [&Table; 8] = [
+    &array::METHODS, &string::METHODS, &string::STRING, &number::METHODS, &number::NUMBER, &number::MATH, &json::JSON,
+    &GLOBALS,
+];
+
+const GLOBALS: Table = Table { call: None, members: &[
+    ("String", Member::Table(STRING)),
+    ("Number", Member::Table(NUMBER)),
+    ("Math", Member::Table(MATH)),
+    ("JSON", Member::Table(JSON)),
+    ("parseInt", Member::Fn(number::parse_int)),
+    ("parseFloat", Member::Fn(number::parse_float)),
+    ("isNaN", Member::Fn(number::is_nan)),
+    ("isFinite", Member::Fn(number::is_finite)),
+    ("NaN", Member::Num(f64::NAN)),
+    ("Infinity", Member::Num(f64::INFINITY)),
+]};
+
+const OBJECT: usize = 0xff;
+
+fn split(v: JsVal) -> (usize, usize) {
+    (v_data(v) >> 8, v_data(v) & 0xff)
+}
+
+// Index of a member of the table
+pub(crate) fn find(table: usize, name: &str) -> Option<usize> {
+    TABLES[table].members.iter().position(|&(m, _)| m == name)
+}
+
+// Value of member `i` of the table
+pub(crate) fn member(table: usize, i: usize) -> JsVal {
+    match TABLES[table].members[i].1 {
+        Member::Fn(_) | Member::Unary(_) => make_val(Type::BUILTIN, (table << 8 | i) as u64),
+        Member::Num(d) => tok_val(d),
+        Member::Table(t) => make_val(Type::BUILTIN, (t << 8 | OBJECT) as u64),
+    }
+}
+
+// Value of member `name` of the table, undefined if there is none
+pub(crate) fn lookup(table: usize, name: &str) -> JsVal {
+    find(table, name).map_or(make_undef(), |i| member(table, i))
+}
+
+// Member `name` of a built-in value, undefined unless it is an object
+pub(crate) fn get(v: JsVal, name: &str) -> JsVal {
+    match split(v) {
+        (table, OBJECT) => lookup(table, name),
+        _ => make_undef(),
+    }
+}
+
+// Value of a global name, if there is a built-in one
+pub(crate) fn global(name: &str) -> Option<JsVal> {
+    find(GLOBAL, name).map(|i| member(GLOBAL, i))
+}
+
+fn callee(v: JsVal) -> Option<Member> {
+    match split(v) {
+        (table, OBJECT) => TABLES[table].call.map(Member::Fn),
+        (table, i) => Some(TABLES[table].members[i].1),
+    }
+}
+
+// Whether the value is a function rather than an object like `Math`
+pub(crate) fn is_callable(v: JsVal) -> bool {
+    callee(v).is_some()
+}
+
+// Call a built-in value, with `this` and the arguments on the stack
+pub(crate) fn call(js: &mut Js<'_, '_>, v: JsVal, argv: JsOff, argc: usize) -> JsVal {
+    match callee(v) {
+        Some(Member::Fn(f)) => f(js, argv, argc),
+        Some(Member::Unary(f)) => {
+            let d = js.number_of(js.arg(argv, argc, 0));
+            tok_val(f(d))
+        },
+        _ => js.mk_err(ErrorKind::Type, "calling non-function"),
+    }
+}
@@ -1,37 +1,51 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
+#![allow(clippy::upper_case_acronyms)]
+
+use core::fmt::{self, Write};
+
+use crate::math::trunc;
 
 static JS_VERSION: &str = "3.0.0";
 
-const JS_EXPR_MAX: u8 = 20;
-const JS_GC_THRESHOLD: f32 = 0.75;
+pub(crate) const JS_GC_THRESHOLD: f32 = 0.75;
+pub(crate) const JS_ERR_MAX: usize = 64;  // Error message buffer size
+pub(crate) const JS_STACK_MAX: u32 = 256 * 1024;  // Default native stack limit, see Js::setmaxss
+pub(crate) const JS_PRINT_DEPTH: usize = 16;  // Nesting printed by Js::str, deeper arrays are cut
 
 pub(crate) type JsOff = u32;
 pub(crate) type JsVal = u64;
 
-/*
-const F_NOEXEC: usize = 1usize;
-const F_LOOP: usize = 2usize;
-const F_CALL: usize = 4usize;
-const F_BREAK: usize = 8usize;
-const F_RETURN: usize = 16usize;
-*/
+pub(crate) const GC_MARK: JsOff = 0x80000000;
+pub(crate) const CONST_PROP: JsOff = 1;  // Set in the key offset of a `const` property
+pub(crate) const HIDDEN_PROP: JsOff = 2;  // Set in the key offset of a property `for..in` skips
+pub(crate) const INTERNAL_PROP: JsOff = 0x80000000;  // Set in the key offset of a property only the engine sees
+pub(crate) const PROP_FLAGS: JsOff = CONST_PROP | HIDDEN_PROP | INTERNAL_PROP;
+pub(crate) const ARROW_FN: JsOff = 1;  // Set in the parent offset of an arrow function object
+pub(crate) const CLASS_FN: JsOff = 2;  // Set in the parent offset of a class, which only `new` calls
+pub(crate) const FN_FLAGS: JsOff = ARROW_FN | CLASS_FN;
 
+#[derive(Clone, Copy)]
 pub(crate) enum Flags {
-    NOEXEC,     // Parse code, but not execute
-    LOOP,       // We're inside the loop
-    CALL,       // We're inside a function call
-    BREAK,      // Exit the loop
-    RETURN      // Return has been executed
+    NOEXEC = 1,     // Parse code, but not execute
+    LOOP = 2,       // We're inside the loop
+    CALL = 4,       // We're inside a function call
+    BREAK = 8,      // Exit the loop
+    RETURN = 16,    // Return has been executed
+    CONTINUE = 32,  // Skip to the next loop iteration
+    SWITCH = 64,    // We're inside a switch, break ends it
+    LVALUE = 128,   // The member expression being parsed is assigned to
 }
 
-#[derive(Clone, PartialEq)]
-pub(crate) enum Token {
-    ERR, EOF, IDENTIFIER, NUMBER, STRING, SEMICOLON,
-    LPAREN, RPAREN, LBRACE, RBRACE, BREAK = 50, CASE, CATCH,
+/// Token kinds. `SPACE` and `COMMENT` are only produced by a
+/// `Lexer::with_trivia`, the engine skips them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    ERR, EOF, IDENTIFIER, NUMBER, STRING, TEMPLATE, SEMICOLON,
+    LPAREN, RPAREN, LBRACE, RBRACE, LBRACKET, RBRACKET, SPACE, COMMENT, BREAK = 50, CASE, CATCH,
     CLASS, CONST, CONTINUE, DEFAULT, DELETE, DO, ELSE,
-    FINALLY, FOR, FUNC, IF, IN, INSTANCEOF, LET, NEW,
-    RETURN, SWITCH, THIS, THROW, TRY, VAR, VOID, WHILE,
+    EXTENDS, FINALLY, FOR, FUNC, IF, IN, INSTANCEOF, LET, NEW,
+    RETURN, SUPER, SWITCH, THIS, THROW, TRY, VAR, VOID, WHILE,
     WITH, YIELD, UNDEF, NULL, TRUE, FALSE, DOT = 100, CALL,
     POSTINC, POSTDEC, NOT, TILDE, TYPEOF, UPLUS, UMINUS,
     EXP, MUL, DIV, REM, PLUS, MINUS, SHL, SHR, ZSHR, LT,
@@ -39,29 +53,47 @@ pub(crate) enum Token {
     Q, ASSIGN, PLUS_ASSIGN, MINUS_ASSIGN, MUL_ASSIGN,
     DIV_ASSIGN, REM_ASSIGN, SHL_ASSIGN, SHR_ASSIGN,
     ZSHR_ASSIGN, AND_ASSIGN, XOR_ASSIGN, OR_ASSIGN, COMMA,
+    ARROW, ELLIPSIS,
 }
 
-// A JS memory stores different entities: objects, properties, strings
-// All entities are packed to the beginning of a buffer.
+// The caller-provided buffer holds the Js struct itself, followed by the
+// JS memory. A JS memory stores different entities: objects, properties,
+// strings. All entities are packed to the beginning of the memory.
 // The `brk` marks the end of the used memory:
 //
-// | entity1 | entity2 | .... | entityN |  unused memory |
-// |---------|---------|------| ------- | -------------- | 
-// Js.mem                               Js.brk           Js.size
+// | Js | entity1 | entity2 | .... | entityN |  unused memory |
+// |----|---------|---------|------| ------- | -------------- |
+//      Js.mem                               Js.brk           Js.size
 //
 // LSB: Least Significant Bit
 //
 // Each entity is 4-byte aligned, therefore 2 LSB bits store entity type
 //
-// Object:    8 bytes: offset of the first property, offset of the upper obj
+// Object:    12 bytes: offset of the first property, offset of the upper obj,
+//            offset of the prototype, 0 if none
 // Property:    8 bytes + val: 4 byte next property, 4 byte key offs, N byte value
 // String:    4xN bytes: 4 byte len << 2, 4 byte-aligned 0-terminated data
+// Array:    8 bytes: offset of the element storage, 4 byte length
+//
+// Array elements live in a separate string entity, 8 bytes per JsVal, so
+// that the array can grow without moving: a bigger storage is allocated and
+// the old one becomes garbage. Only the array knows what the storage holds,
+// the GC scans its elements through the array. Missing elements are holes,
+// see `HOLE`.
 //
 // If Rust functions are imported, they use the upper part
 // of memory as stack for passing params. Each argument is pushed to the top of the memory as
 // JsVal, and Js.size is decreased by sizeof(JsVal), i.e. 8 bytes. When the function returns,
 // Js.size is restored back. So Js.size is used as a stack pointer.
 //
+// JS functions are called the same way: `this`, the function and then the
+// arguments are pushed. The callee binds the arguments to its parameters in
+// a new scope, evaluating defaults for the missing ones and collecting the
+// remaining ones into an array for a rest parameter.
+//
+// The garbage collector uses the MSB of the first entity word as a mark bit,
+// and the unused memory between Js.brk and Js.size as its scratch space.
+//
 
 
 // Pack Js values into u64, float64
@@ -76,52 +108,126 @@ pub(crate) enum Token {
 //
 // On 64-bit platforms, pointers are really 48 bit only, so they can fit,
 // provided they are sign extended
+//
+// The type nibble holds `type + 1`, so that a boxed value never has a zero
+// mantissa and can't be confused with infinity. A real NaN is always stored
+// as a boxed NUM, which is itself a NaN bit pattern.
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum Type {
     OBJ, PROP, STR, UNDEF, NULL, NUM,
-    BOOL, FUNC, CODEREF, RFUNC, ERR
+    BOOL, FUNC, CODEREF, RFUNC, ERR, ARR,
+    REF,        // Assignment target `obj[key]`, data is the stack offset of the key
+    BUILTIN,    // Built-in function or object, see `builtin.rs`
 }
 
-pub(crate) fn type_str(typ: Type) -> String {
-    format!("{:#?}", typ)
+impl Type {
+    pub(crate) fn from_tag(tag: u8) -> Type {
+        match tag {
+            0 => Type::OBJ,
+            1 => Type::PROP,
+            2 => Type::STR,
+            3 => Type::UNDEF,
+            4 => Type::NULL,
+            6 => Type::BOOL,
+            7 => Type::FUNC,
+            8 => Type::CODEREF,
+            9 => Type::RFUNC,
+            10 => Type::ERR,
+            11 => Type::ARR,
+            12 => Type::REF,
+            13 => Type::BUILTIN,
+            _ => Type::NUM,
+        }
+    }
 }
 
-pub(crate) fn make_val(typ: Type, data: u64) -> JsVal {
-    0x7ff0u64 << 48u64 | ((typ as u8) << 48) as JsVal | data & 0xffffffffffffu64
+// Type of the entity with the header word `b`. Tag 3 is free, as values
+// of types past STR are never entities, so it is given to arrays
+pub(crate) fn entity_type(b: JsOff) -> Type {
+    match b & 3 {
+        0 => Type::OBJ,
+        1 => Type::PROP,
+        2 => Type::STR,
+        _ => Type::ARR,
+    }
+}
+
+// Tag of the array entity header
+pub(crate) const ARR_TAG: JsOff = 3;
+
+// Type name, as returned by typeof
+pub(crate) fn type_str(typ: Type) -> &'static str {
+    match typ {
+        Type::STR => "string",
+        Type::NUM => "number",
+        Type::BOOL => "boolean",
+        Type::UNDEF => "undefined",
+        Type::FUNC | Type::RFUNC | Type::BUILTIN => "function",
+        _ => "object",
+    }
+}
+
+pub(crate) const fn make_val(typ: Type, data: u64) -> JsVal {
+    0x7ff0u64 << 48u64 | (typ as u64 + 1) << 48u64 | data & 0xffffffffffffu64
 }
 
 pub(crate) fn v_data(v: JsVal) -> usize {
-    (v & !(0x7fffu64 << 48u64 as JsVal)) as usize
+    (v & 0xffffffffffffu64) as usize
 }
 
+pub(crate) fn make_undef() -> JsVal {
+    make_val(Type::UNDEF, 0)
+}
+
+pub(crate) fn make_null() -> JsVal {
+    make_val(Type::NULL, 0)
+}
+
+pub(crate) fn make_bool(b: bool) -> JsVal {
+    make_val(Type::BOOL, b as u64)
+}
+
+// A missing array element. Reading it gives undefined
+pub(crate) const HOLE: JsVal = make_val(Type::UNDEF, 1);
+
 
 // Utilities
-fn is_alpha(c: char) -> bool {
-    c >= 'a' && c <= 'z' || c >= 'A' && c <= 'Z'
+fn is_alpha(c: u8) -> bool {
+    c.is_ascii_alphabetic()
+}
+
+fn is_ident_begin(c: u8) -> bool {
+    c == b'_' || c == b'$' || is_alpha(c)
 }
 
-fn is_ident_begin(c: char) -> bool {
-    c == '_' || c == '$' || is_alpha(c)
+pub(crate) fn is_digit(c: u8) -> bool {
+    c.is_ascii_digit()
 }
 
-fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+fn is_ident_continue(c: u8) -> bool {
+    c == b'_' || c == b'$' || is_alpha(c) || is_digit(c)
 }
 
-fn is_ident_continue(c: char) -> bool {
-    c == '_' || c == '$' || is_alpha(c) || is_digit(c)
+fn is_space(c: u8) -> bool {
+    c == b' ' || c == b'\r' || c == b'\n' || c == b'\t'
 }
 
-fn is_space(c: char) -> bool {
-    c == ' ' || c == '\r' || c == '\n' || c == '\t'
+// Length of the UTF-8 character starting with byte `c`
+fn char_len(c: u8) -> JsOff {
+    match c {
+        0xf0.. => 4,
+        0xe0.. => 3,
+        0xc0.. => 2,
+        _ => 1,
+    }
 }
 
 
 pub(crate) fn parse_keyword(buffer: &str) -> Token {
-    let value = buffer.chars().nth(0).unwrap();
+    let value = buffer.chars().next().unwrap();
     match value {
-        'b' if "break" == buffer.to_owned() => Token::BREAK,
+        'b' if "break" == buffer => Token::BREAK,
         'c' => {
             match buffer {
                 "class" => Token::CLASS,
@@ -129,24 +235,31 @@ pub(crate) fn parse_keyword(buffer: &str) -> Token {
                 "catch" => Token::CATCH,
                 "const" => Token::CONST,
                 "continue" => Token::CONTINUE,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'd' => {
             match buffer {
                 "do" => Token::DO,
                 "default" => Token::DEFAULT,
-                _ => Token::ERR,
+                "delete" => Token::DELETE,
+                _ => Token::IDENTIFIER,
+            }
+        },
+        'e' => {
+            match buffer {
+                "else" => Token::ELSE,
+                "extends" => Token::EXTENDS,
+                _ => Token::IDENTIFIER,
             }
         },
-        'e' if "else" == buffer => Token::ELSE,
         'f' => {
             match buffer {
                 "for" => Token::FOR,
                 "function" => Token::FUNC,
                 "finally" => Token::FINALLY,
                 "false" => Token::FALSE,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'i' => {
@@ -154,7 +267,7 @@ pub(crate) fn parse_keyword(buffer: &str) -> Token {
                 "if" => Token::IF,
                 "in" => Token::IN,
                 "instanceof" => Token::INSTANCEOF,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'l' if "let" == buffer => Token::LET,
@@ -162,11 +275,17 @@ pub(crate) fn parse_keyword(buffer: &str) -> Token {
             match buffer {
                 "new" => Token::NEW,
                 "null" => Token::NULL,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'r' if "return" == buffer => Token::RETURN,
-        's' if "switch" == buffer => Token::SWITCH,
+        's' => {
+            match buffer {
+                "super" => Token::SUPER,
+                "switch" => Token::SWITCH,
+                _ => Token::IDENTIFIER,
+            }
+        },
         't' => {
             match buffer {
                 "try" => Token::TRY,
@@ -174,7 +293,7 @@ pub(crate) fn parse_keyword(buffer: &str) -> Token {
                 "throw" => Token::THROW,
                 "true" => Token::TRUE,
                 "typeof" => Token::TYPEOF,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'u' if "undefined" == buffer => Token::UNDEF,
@@ -182,14 +301,14 @@ pub(crate) fn parse_keyword(buffer: &str) -> Token {
             match buffer {
                 "var" => Token::VAR,
                 "void" => Token::VOID,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'w' => {
             match buffer {
                 "while" => Token::WHILE,
                 "with" => Token::WITH,
-                _ => Token::ERR,
+                _ => Token::IDENTIFIER,
             }
         },
         'y' if "yield" == buffer => Token::YIELD,
@@ -197,59 +316,524 @@ pub(crate) fn parse_keyword(buffer: &str) -> Token {
     }
 }
 
-pub(crate) fn parse_ident(buffer: &str, t_len: &mut JsOff) -> Token {
-    if is_ident_begin(buffer.chars().nth(0).unwrap()) {
-        loop {
-            if *t_len < buffer.len() as u32 && is_ident_continue(buffer.chars().nth(*t_len as usize).unwrap()) {
-                *t_len += 1;
-            }
-            return parse_keyword(buffer)
+// Identifier or keyword at the beginning of `buf`
+pub(crate) fn parse_ident(buf: &[u8]) -> (Token, JsOff) {
+    if !buf.first().is_some_and(|&c| is_ident_begin(c)) {
+        return (Token::ERR, buf.first().map_or(0, |&c| char_len(c)))
+    }
+    let len = buf.iter().position(|&c| !is_ident_continue(c)).unwrap_or(buf.len());
+    // Identifiers are plain ASCII
+    let name = core::str::from_utf8(&buf[..len]).unwrap_or("");
+    (parse_keyword(name), len as JsOff)
+}
+
+// Whitespace or comment at the beginning of `buf`, with its length. An
+// unterminated block comment isn't one, `scan` makes it an error
+pub(crate) fn scan_trivia(buf: &[u8]) -> Option<(Token, JsOff)> {
+    let len = match buf {
+        [c, ..] if is_space(*c) => buf.iter().position(|&c| !is_space(c)).unwrap_or(buf.len()),
+        [b'/', b'/', rest @ ..] => 2 + rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len()),
+        [b'/', b'*', rest @ ..] => 2 + rest.windows(2).position(|w| w == b"*/")? + 2,
+        _ => return None,
+    };
+    let tok = if is_space(buf[0]) { Token::SPACE } else { Token::COMMENT };
+    Some((tok, len as JsOff))
+}
+
+// Skip whitespace and comments starting at offset `n`, return the offset
+// of the next token
+pub(crate) fn skip_to_next(code: &[u8], mut n: JsOff) -> JsOff {
+    while let Some((_, len)) = scan_trivia(&code[n as usize..]) {
+        n += len;
+    }
+    n
+}
+
+// JS whitespace and line terminators, as skipped by `trim` and by
+// conversions of strings to numbers
+pub(crate) fn is_white(c: char) -> bool {
+    c == '\u{feff}' || (c != '\u{85}' && c.is_whitespace())
+}
+
+// Length of the numeric literal at the beginning of `buf`: a decimal like
+// `1.5e-3` or `.5`, or a hex, octal or binary integer like `0xff`. One
+// that is malformed or runs into an identifier, as in `3in`, is an error
+pub(crate) fn number_len(buf: &[u8]) -> (Token, JsOff) {
+    let n = match radix_prefix(buf) {
+        Some(radix) => match parse_digits(&buf[2..], radix).1 {
+            0 => 0,
+            len => 2 + len,
+        },
+        None => decimal_len(buf),
+    };
+    let rest = buf[n..].iter().take_while(|&&c| is_ident_continue(c)).count();
+    if n == 0 || rest > 0 { (Token::ERR, (n + rest).max(1) as JsOff) } else { (Token::NUMBER, n as JsOff) }
+}
+
+// Length of the decimal number at the beginning of `buf`, 0 if there is
+// none. An exponent without digits is not part of it
+pub(crate) fn decimal_len(buf: &[u8]) -> usize {
+    let digits = |from: usize| buf.iter().skip(from).take_while(|&&c| is_digit(c)).count();
+    let mut n = digits(0);
+    if buf.get(n) == Some(&b'.') {
+        let frac = digits(n + 1);
+        if n == 0 && frac == 0 { return 0 }
+        n += 1 + frac;
+    }
+    if n > 0 && matches!(buf.get(n), Some(b'e' | b'E')) {
+        let sign = matches!(buf.get(n + 1), Some(b'+' | b'-')) as usize;
+        let exp = digits(n + 1 + sign);
+        if exp > 0 { n += 1 + sign + exp }
+    }
+    n
+}
+
+// Radix of a `0x`, `0o` or `0b` prefix
+pub(crate) fn radix_prefix(buf: &[u8]) -> Option<u32> {
+    match buf {
+        [b'0', b'x' | b'X', ..] => Some(16),
+        [b'0', b'o' | b'O', ..] => Some(8),
+        [b'0', b'b' | b'B', ..] => Some(2),
+        _ => None,
+    }
+}
+
+// Value of the digits in `radix` at the beginning of `buf`, and how many
+// there are
+pub(crate) fn parse_digits(buf: &[u8], radix: u32) -> (f64, usize) {
+    let (mut d, mut n) = (0.0, 0);
+    while let Some(v) = buf.get(n).and_then(|&c| (c as char).to_digit(radix)) {
+        d = d * radix as f64 + v as f64;
+        n += 1;
+    }
+    (d, n)
+}
+
+// Length of the string literal at the beginning of `buf`, including the
+// quotes. An unterminated string is an error that spans the rest of `buf`
+fn string_len(buf: &[u8]) -> (Token, JsOff) {
+    let mut n = 1;
+    while n < buf.len() && buf[n] != buf[0] {
+        n += if buf[n] == b'\\' { 2 } else { 1 };
+    }
+    if buf.get(n) == Some(&buf[0]) {
+        (Token::STRING, n as JsOff + 1)
+    } else {
+        (Token::ERR, buf.len() as JsOff)
+    }
+}
+
+// Length of the template literal at the beginning of `buf`, including the
+// backticks. It may span lines, and its `${}` expressions may hold strings
+// and templates of their own
+fn template_len(buf: &[u8]) -> (Token, JsOff) {
+    let mut n = 1;
+    while n < buf.len() {
+        match buf[n] {
+            b'`' => return (Token::TEMPLATE, n as JsOff + 1),
+            b'\\' => n += 2,
+            b'$' if buf.get(n + 1) == Some(&b'{') => match subst_len(&buf[n + 2..]) {
+                Some(len) => n += 2 + len,
+                None => break,
+            },
+            _ => n += 1,
         }
     }
-    Token::ERR
+    (Token::ERR, buf.len() as JsOff)
 }
 
-pub(crate) fn skip_to_next(code: &str, len: JsOff, mut n: JsOff) -> JsOff {
-    let n_u = n as usize;
-    while n < len {
-        let c = code.chars().nth(n_u).unwrap();
-        let c_n = code.chars().nth(n_u + 1).unwrap();
+// Length of the `${}` expression at the beginning of `buf`, up to and
+// including the closing brace. It is skipped token by token
+pub(crate) fn subst_len(buf: &[u8]) -> Option<usize> {
+    let (mut n, mut depth) = (0, 1);
+    loop {
+        n = skip_to_next(buf, n as JsOff) as usize;
+        let (tok, len) = scan(&buf[n..]);
+        n += len as usize;
+        match tok {
+            Token::LBRACE => depth += 1,
+            Token::RBRACE if depth == 1 => return Some(n),
+            Token::RBRACE => depth -= 1,
+            Token::EOF | Token::ERR => return None,
+            _ => (),
+        }
+    }
+}
 
-        if is_space(c) {
-            n += 1;
-        } else if (n + 1 < len) && c == '/' && c_n == '/' {
-            n += 2;
-            while n < len && c != '\n' {
-                n += 1;
-            }
-        } else if (n + 3 < len) && c == '/' && c_n == '*' {
-            n += 4;
-            while n < len && (code.chars().nth(n_u - 2).unwrap() != '*' || code.chars().nth(n_u - 1).unwrap() != '/') {
-                n += 1;
-            }
-        } else {
-            break;
+// Decode the escapes of a string literal in place, return the new length.
+// A decoded escape is never longer than the escape itself. Lone surrogates
+// become U+FFFD, to keep the string valid UTF-8
+pub(crate) fn unescape(buf: &mut [u8]) -> Result<usize, &'static str> {
+    let hex = |buf: &[u8], at: usize, n: usize| -> Result<u32, &'static str> {
+        let digits = buf.get(at..at + n).ok_or("bad escape")?;
+        digits.iter().try_fold(0u32, |acc, &c| Ok(acc * 16 + (c as char).to_digit(16).ok_or("bad escape")?))
+    };
+    let (mut r, mut w) = (0, 0);
+    while r < buf.len() {
+        if buf[r] != b'\\' {
+            buf[w] = buf[r];
+            (r, w) = (r + 1, w + 1);
+            continue
         }
+        let c = *buf.get(r + 1).ok_or("bad escape")?;
+        r += 2;
+        let cp = match c {
+            b'n' => 0x0a,
+            b't' => 0x09,
+            b'r' => 0x0d,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'v' => 0x0b,
+            b'0' => 0,
+            b'x' => {
+                r += 2;
+                hex(buf, r - 2, 2)?
+            },
+            b'u' if buf.get(r) == Some(&b'{') => {
+                let len = buf[r + 1..].iter().position(|&c| c == b'}').ok_or("bad escape")?;
+                if len == 0 || len > 6 { return Err("bad escape") }
+                let cp = hex(buf, r + 1, len)?;
+                if cp > 0x10ffff { return Err("bad escape") }
+                r += len + 2;
+                cp
+            },
+            b'u' => {
+                let mut cp = hex(buf, r, 4)?;
+                r += 4;
+                // A surrogate pair is one character
+                if (0xd800..0xdc00).contains(&cp) && buf.get(r..r + 2) == Some(b"\\u") {
+                    if let Ok(lo @ 0xdc00..=0xdfff) = hex(buf, r + 2, 4) {
+                        cp = 0x10000 + ((cp - 0xd800) << 10) + (lo - 0xdc00);
+                        r += 6;
+                    }
+                }
+                cp
+            },
+            // Line continuation
+            b'\r' if buf.get(r) == Some(&b'\n') => {
+                r += 1;
+                continue
+            },
+            b'\r' | b'\n' => continue,
+            // Any other character stands for itself
+            _ => {
+                buf[w] = c;
+                w += 1;
+                continue
+            },
+        };
+        let mut tmp = [0u8; 4];
+        let ch = char::from_u32(cp).unwrap_or(char::REPLACEMENT_CHARACTER).encode_utf8(&mut tmp).as_bytes();
+        buf[w..w + ch.len()].copy_from_slice(ch);
+        w += ch.len();
+    }
+    Ok(w)
+}
+
+// Scan the token at the beginning of `buf`, return it with its length.
+// `buf` must start at a token, see `skip_to_next`
+pub(crate) fn scan(buf: &[u8]) -> (Token, JsOff) {
+    let at = |i: usize| buf.get(i).copied().unwrap_or(0);
+    match at(0) {
+        0 if buf.is_empty() => (Token::EOF, 0),
+        b'?' => (Token::Q, 1),
+        b':' => (Token::COLON, 1),
+        b'(' => (Token::LPAREN, 1),
+        b')' => (Token::RPAREN, 1),
+        b'{' => (Token::LBRACE, 1),
+        b'}' => (Token::RBRACE, 1),
+        b'[' => (Token::LBRACKET, 1),
+        b']' => (Token::RBRACKET, 1),
+        b';' => (Token::SEMICOLON, 1),
+        b',' => (Token::COMMA, 1),
+        b'.' if at(1) == b'.' && at(2) == b'.' => (Token::ELLIPSIS, 3),
+        b'.' if is_digit(at(1)) => number_len(buf),
+        b'.' => (Token::DOT, 1),
+        b'~' => (Token::TILDE, 1),
+        b'!' if at(1) == b'=' && at(2) == b'=' => (Token::NE, 3),
+        b'!' => (Token::NOT, 1),
+        b'-' if at(1) == b'-' => (Token::POSTDEC, 2),
+        b'-' if at(1) == b'=' => (Token::MINUS_ASSIGN, 2),
+        b'-' => (Token::MINUS, 1),
+        b'+' if at(1) == b'+' => (Token::POSTINC, 2),
+        b'+' if at(1) == b'=' => (Token::PLUS_ASSIGN, 2),
+        b'+' => (Token::PLUS, 1),
+        b'*' if at(1) == b'*' => (Token::EXP, 2),
+        b'*' if at(1) == b'=' => (Token::MUL_ASSIGN, 2),
+        b'*' => (Token::MUL, 1),
+        b'/' if at(1) == b'=' => (Token::DIV_ASSIGN, 2),
+        b'/' if at(1) == b'*' => (Token::ERR, buf.len() as JsOff),  // Unterminated comment
+        b'/' => (Token::DIV, 1),
+        b'%' if at(1) == b'=' => (Token::REM_ASSIGN, 2),
+        b'%' => (Token::REM, 1),
+        b'&' if at(1) == b'&' => (Token::LAND, 2),
+        b'&' if at(1) == b'=' => (Token::AND_ASSIGN, 2),
+        b'&' => (Token::AND, 1),
+        b'|' if at(1) == b'|' => (Token::LOR, 2),
+        b'|' if at(1) == b'=' => (Token::OR_ASSIGN, 2),
+        b'|' => (Token::OR, 1),
+        b'=' if at(1) == b'=' && at(2) == b'=' => (Token::EQ, 3),
+        b'=' if at(1) == b'>' => (Token::ARROW, 2),
+        b'=' => (Token::ASSIGN, 1),
+        b'<' if at(1) == b'<' && at(2) == b'=' => (Token::SHL_ASSIGN, 3),
+        b'<' if at(1) == b'<' => (Token::SHL, 2),
+        b'<' if at(1) == b'=' => (Token::LE, 2),
+        b'<' => (Token::LT, 1),
+        b'>' if at(1) == b'>' && at(2) == b'>' && at(3) == b'=' => (Token::ZSHR_ASSIGN, 4),
+        b'>' if at(1) == b'>' && at(2) == b'>' => (Token::ZSHR, 3),
+        b'>' if at(1) == b'>' && at(2) == b'=' => (Token::SHR_ASSIGN, 3),
+        b'>' if at(1) == b'>' => (Token::SHR, 2),
+        b'>' if at(1) == b'=' => (Token::GE, 2),
+        b'>' => (Token::GT, 1),
+        b'^' if at(1) == b'=' => (Token::XOR_ASSIGN, 2),
+        b'^' => (Token::XOR, 1),
+        b'"' | b'\'' => string_len(buf),
+        b'`' => template_len(buf),
+        b'0'..=b'9' => number_len(buf),
+        _ => parse_ident(buf),
     }
-    n
 }
 
 pub(crate) fn is_nan(v: JsVal) -> bool {
-    (v >> 52u64) == 0x7ffu64
+    (v >> 52u64) == 0x7ffu64 && (v >> 48u64) & 15u64 != 0
 }
 
-pub(crate) fn v_type(v: JsVal) -> u8 {
-    if is_nan(v) { ((v >> 48u64) & 15u64) as u8 } else { Token::NUMBER as u8 }
+pub(crate) fn v_type(v: JsVal) -> Type {
+    if is_nan(v) { Type::from_tag(((v >> 48u64) & 15u64) as u8 - 1) } else { Type::NUM }
 }
 
 pub(crate) fn tok_val(d: f64) -> JsVal {
-    d as JsVal
+    if d.is_nan() { make_val(Type::NUM, 0) } else { d.to_bits() }
+}
+
+pub(crate) fn v_num(v: JsVal) -> f64 {
+    f64::from_bits(v)
+}
+
+// Whether the value points to an entity in JS memory
+pub(crate) fn is_entity(v: JsVal) -> bool {
+    matches!(v_type(v), Type::OBJ | Type::PROP | Type::STR | Type::FUNC | Type::CODEREF | Type::ARR)
+}
+
+// Type of the entity the value points to, see `is_entity`
+pub(crate) fn entity_of(v: JsVal) -> Type {
+    match v_type(v) {
+        Type::FUNC => Type::OBJ,
+        Type::CODEREF => Type::STR,
+        typ => typ,
+    }
+}
+
+pub(crate) fn is_func(v: JsVal) -> bool {
+    matches!(v_type(v), Type::FUNC | Type::RFUNC | Type::BUILTIN)
 }
 
 pub(crate) fn is_err(v: JsVal) -> bool {
-    v_type(v) == Token::ERR as u8
+    v_type(v) == Type::ERR
+}
+
+pub(crate) fn is_assign(tok: Token) -> bool {
+    (tok as u8) >= Token::ASSIGN as u8 && (tok as u8) <= Token::OR_ASSIGN as u8
 }
 
+// Keywords are valid member names, as in `a.default`
+pub(crate) fn is_keyword(tok: Token) -> bool {
+    (tok as u8) >= Token::BREAK as u8 && (tok as u8) <= Token::FALSE as u8
+}
+
+// Value of a numeric literal, or of a string converted to a number: the
+// whitespace around it is ignored and an empty string is 0. Anything else
+// that is not a literal, `Infinity` or `-Infinity` is NaN
 pub(crate) fn str_to_double(buf: &str) -> f64 {
-    buf.parse::<f64>().unwrap()
+    let s = buf.trim_matches(is_white);
+    let b = s.as_bytes();
+    if b.is_empty() { return 0.0 }
+    if let Some(radix) = radix_prefix(b) {
+        let (d, n) = parse_digits(&b[2..], radix);
+        return if n > 0 && n == b.len() - 2 { d } else { f64::NAN }
+    }
+    let sign = matches!(b[0], b'+' | b'-') as usize;
+    if &b[sign..] == b"Infinity" { return if b[0] == b'-' { f64::NEG_INFINITY } else { f64::INFINITY } }
+    match decimal_len(&b[sign..]) {
+        n if n > 0 && sign + n == b.len() => s.parse().unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+// ToInt32 conversion used by the bitwise operators
+pub(crate) fn to_i32(d: f64) -> i32 {
+    if d.is_finite() { (trunc(d) % 4294967296.0) as i64 as i32 } else { 0 }
+}
+
+// Format a number the way JS prints it
+pub(crate) fn fmt_num(d: f64, out: &mut impl fmt::Write) -> fmt::Result {
+    if d.is_nan() {
+        out.write_str("NaN")
+    } else if d.is_infinite() {
+        out.write_str(if d > 0.0 { "Infinity" } else { "-Infinity" })
+    } else if d == 0.0 {
+        out.write_str("0")
+    } else if d.abs() >= 1e21 || d.abs() < 1e-6 {
+        let mut buf = [0u8; 32];
+        let mut e = Buf::new(&mut buf);
+        write!(e, "{:e}", d)?;
+        match e.as_str().split_once('e') {
+            Some((m, x)) if !x.starts_with('-') => write!(out, "{}e+{}", m, x),
+            _ => out.write_str(e.as_str()),
+        }
+    } else {
+        write!(out, "{}", d)
+    }
+}
+
+// A fmt::Write sink over a fixed byte buffer, silently truncating the output
+pub(crate) struct Buf<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Buf<'b> {
+    pub(crate) fn new(buf: &'b mut [u8]) -> Buf<'b> {
+        Buf { buf, len: 0 }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in, see `write_str`
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for Buf<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// Size of the entity with the header word `b`, in bytes
+pub(crate) fn entity_size(b: JsOff) -> JsOff {
+    match entity_type(b) {
+        Type::OBJ => 12,
+        Type::ARR => 8,
+        Type::PROP => 16,
+        _ => ((b >> 2) + 4 + 3) & !3u32,
+    }
+}
+
+// Readers and writers for the entities packed into JS memory
+pub(crate) fn load_off(mem: &[u8], off: usize) -> JsOff {
+    JsOff::from_le_bytes(mem[off..off + 4].try_into().unwrap())
+}
+
+pub(crate) fn load_val(mem: &[u8], off: usize) -> JsVal {
+    JsVal::from_le_bytes(mem[off..off + 8].try_into().unwrap())
+}
+
+// Contents of the string entity at `off`, empty if it doesn't fit in `mem`
+pub(crate) fn mem_str(mem: &[u8], off: usize) -> &str {
+    let Some(head) = mem.get(off..off.saturating_add(4)) else { return "" };
+    let len = ((load_off(head, 0) >> 2) as usize).saturating_sub(1);
+    let data = mem.get(off + 4..off + 4 + len).unwrap_or(&[]);
+    core::str::from_utf8(data).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tokens of `code` with their source text
+    fn tokens(code: &str) -> Vec<(Token, &str)> {
+        let mut res = Vec::new();
+        let mut pos = 0;
+        loop {
+            let off = skip_to_next(code.as_bytes(), pos);
+            let (tok, len) = scan(&code.as_bytes()[off as usize..]);
+            if tok == Token::EOF { return res }
+            res.push((tok, &code[off as usize..(off + len) as usize]));
+            pos = off + len;
+        }
+    }
+
+    #[test]
+    fn spans() {
+        assert_eq!(tokens("let x=1.5e+3;"), [
+            (Token::LET, "let"), (Token::IDENTIFIER, "x"), (Token::ASSIGN, "="),
+            (Token::NUMBER, "1.5e+3"), (Token::SEMICOLON, ";"),
+        ]);
+        assert_eq!(tokens("a>>>=b>>>c>=d"), [
+            (Token::IDENTIFIER, "a"), (Token::ZSHR_ASSIGN, ">>>="), (Token::IDENTIFIER, "b"),
+            (Token::ZSHR, ">>>"), (Token::IDENTIFIER, "c"), (Token::GE, ">="), (Token::IDENTIFIER, "d"),
+        ]);
+        assert_eq!(tokens(" // c\n/* é */ 'é\\'' + \"x\\x41\""), [
+            (Token::STRING, "'é\\''"), (Token::PLUS, "+"), (Token::STRING, "\"x\\x41\""),
+        ]);
+        assert_eq!(tokens("$a_1 é"), [(Token::IDENTIFIER, "$a_1"), (Token::ERR, "é")]);
+        assert_eq!(tokens("x 'abé"), [(Token::IDENTIFIER, "x"), (Token::ERR, "'abé")]);
+        assert_eq!(tokens("'ab\\x"), [(Token::ERR, "'ab\\x")]);
+        assert_eq!(tokens("/**/ a /* b"), [(Token::IDENTIFIER, "a"), (Token::ERR, "/* b")]);
+        assert_eq!(tokens("/**/ /*"), [(Token::ERR, "/*")]);
+        assert_eq!(tokens("`a\n${ {b: '}'}.b + `${c}` }` + `"), [
+            (Token::TEMPLATE, "`a\n${ {b: '}'}.b + `${c}` }`"), (Token::PLUS, "+"), (Token::ERR, "`"),
+        ]);
+        assert_eq!(tokens("`${a`"), [(Token::ERR, "`${a`")]);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(tokens("0x1F .5 1. 0o17 0B101 2e-3 1e+"), [
+            (Token::NUMBER, "0x1F"), (Token::NUMBER, ".5"), (Token::NUMBER, "1."), (Token::NUMBER, "0o17"),
+            (Token::NUMBER, "0B101"), (Token::NUMBER, "2e-3"), (Token::ERR, "1e"), (Token::PLUS, "+"),
+        ]);
+        assert_eq!(tokens("1.2.3 a.b 1..x"), [
+            (Token::NUMBER, "1.2"), (Token::NUMBER, ".3"), (Token::IDENTIFIER, "a"), (Token::DOT, "."),
+            (Token::IDENTIFIER, "b"), (Token::NUMBER, "1."), (Token::DOT, "."), (Token::IDENTIFIER, "x"),
+        ]);
+        assert_eq!(tokens("3in 0x 0b2 09"), [
+            (Token::ERR, "3in"), (Token::ERR, "0x"), (Token::ERR, "0b2"), (Token::NUMBER, "09"),
+        ]);
+        for (s, d) in [("0x1F", 31.0), (".5", 0.5), ("1.", 1.0), ("0o17", 15.0), ("0b101", 5.0), ("2e-3", 0.002),
+                       (" 12 ", 12.0), ("", 0.0), ("\n\t", 0.0), ("-Infinity", f64::NEG_INFINITY), ("+1.5", 1.5)] {
+            assert_eq!(str_to_double(s), d, "{:?}", s);
+        }
+        for s in ["1e", "abc", "0x", "-0x10", "1 2", "inf", "NaN", "1_000", "."] {
+            assert!(str_to_double(s).is_nan(), "{:?}", s);
+        }
+    }
+
+    fn cooked(s: &str) -> Result<String, &'static str> {
+        let mut buf = s.as_bytes().to_vec();
+        let len = unescape(&mut buf)?;
+        Ok(String::from_utf8(buf[..len].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(cooked(r"a\tb\nc\\d\'\`\$"), Ok("a\tb\nc\\d'`$".to_string()));
+        assert_eq!(cooked(r"\x41é\u{1F600}\u{41}\0"), Ok("Aé😀A\0".to_string()));
+        assert_eq!(cooked(r"\uD83D\uDE00 \uD83D \uDE00x"), Ok("😀 \u{FFFD} \u{FFFD}x".to_string()));
+        assert_eq!(cooked("a\\\nb\\\r\nc"), Ok("abc".to_string()));
+        assert_eq!(cooked(r"\é\r\v\f\b"), Ok("é\r\x0b\x0c\x08".to_string()));
+        for bad in [r"\x4", r"\xg0", r"\u12", r"\u{}", r"\u{110000}", r"\u{1234567}", r"\u{12"] {
+            assert_eq!(cooked(bad), Err("bad escape"), "{}", bad);
+        }
+    }
+    #[test]
+    fn mem_strings() {
+        let mem = [(3 << 2) | 2, 0, 0, 0, b'a', b'b', 0, 0];
+        assert_eq!(mem_str(&mem, 0), "ab");
+        // Bogus headers and offsets past the end give empty strings
+        assert_eq!(mem_str(&mem, 4), "");
+        assert_eq!(mem_str(&mem, 6), "");
+        assert_eq!(mem_str(&[0; 4], 0), "");
+        assert_eq!(mem_str(&mem, usize::MAX - 2), "");
+    }
 }
@@ -1,62 +1,228 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use crate::core::*;
+use core::fmt::{self, Write};
+use core::mem::{align_of, size_of};
+use core::marker::PhantomData;
 
+use crate::{array, builtin, json, string};
+use crate::core::*;
+use crate::error::*;
+use crate::heap::*;
+use crate::math::js_pow;
+use crate::native::*;
+use crate::value::*;
 
 // JS Engine
-pub struct Js<'a> {
+pub struct Js<'a, 'id> {
     rss: JsOff,          // Max observed Rust stack size
     lwm: JsOff,         // JS RAM low watermark: min free RAM observed
     code: &'a str,      // Current parsed code snippet
-    err_msg: &'a str,   // Error message placeholder
+    err_msg: [u8; JS_ERR_MAX], // Error message placeholder
+    err_len: u8,        // Error message length
+    err_kind: ErrorKind, // Kind of the last error
+    err_off: JsOff,     // Offset of the token that caused the last error
     tok: Token,            // Last parsed token value
     consumed: bool,       // Indicator that last parsed token consumed
-    flags: Flags,       // Execution flags, see FLAGS enum above
-    c_len: JsOff,       // Code snippet length 
+    flags: u8,          // Execution flags, see FLAGS enum above
+    c_len: JsOff,       // Code snippet length
     pos: JsOff,         // Current parsing position
     t_off: JsOff,       // Offset of the last parsed token
     t_len: JsOff,       // Length of the last parsed token
     no_gc: JsOff,       // Entity offset to exclude from GC
+    natives: JsOff,     // Table of native functions, 0 if none, see `mk_fun`
     t_val: JsVal,      // Holds last parsed numeric or string literal value
     scope: JsVal,      // Current scope
-    mem: Vec<u8>,            // Available JS memory
+    this: JsVal,       // `this` of the running function
+    lbl_off: JsOff,     // Label of the pending break or continue, in the code
+    lbl_len: JsOff,     // Label length, 0 if the jump has no label
+    ret: JsVal,        // Value of the last executed return statement
+    thrown: JsVal,     // Value of the last throw, ERR if the engine raised the error
+    mem: &'a mut [u8],  // Available JS memory, the part of the buffer after Js
     size: JsOff,        // Memory size
     brk: JsOff,         // Current mem usage boundary
     gc_t: JsOff,        // GC thresold. If brk > gct, trigger GC
     max_ss: JsOff,      // Maximum allowed stack size usage
-    stk: Box<u8>,       // Stack pointer at the beginning of Js::eval()
+    stk: usize,         // Stack pointer at the beginning of Js::eval()
+    gc_runs: u32,       // Number of GC runs
+    gc_freed: usize,    // Total bytes reclaimed by GC
+    rand: u64,          // State of the `Math.random` generator
+    id: PhantomData<fn(&'id ()) -> &'id ()>, // Brand of the values, see `Value`
+}
+
+/// Error returned by `Js::new` when the buffer can't hold the engine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferTooSmall {
+    /// Minimum size of this buffer, in bytes
+    pub min: usize,
+}
+
+/// Memory usage, see `Js::stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Size of the JS memory, in bytes
+    pub total: usize,
+    /// Bytes used by entities, i.e. the current `brk`
+    pub brk: usize,
+    /// Lowest free memory observed, counting the native call stack
+    pub min_free: usize,
+    /// Deepest Rust stack observed while evaluating, in bytes
+    pub peak_stack: usize,
+    /// Number of garbage collections
+    pub gc_runs: usize,
+    /// Total bytes reclaimed by garbage collection
+    pub gc_reclaimed: usize,
+}
+
+impl fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "buffer too small, need at least {} bytes", self.min)
+    }
 }
 
 
 /// Api
-impl<'a> Js<'a> {
-    /// Create a new Js instance
-    pub fn new(buffer: &[u8]) -> Js {
-        todo!("Implement")
+impl<'a, 'id> Js<'a, 'id> {
+    /// Create a new Js instance inside the buffer and pass it to `f`. The
+    /// `Js` struct is placed at the (aligned) beginning of the buffer, the
+    /// rest is used as JS memory; nothing is allocated on the heap.
+    ///
+    /// Values are branded with the instance, so they can't be used with
+    /// another one or leave `f`:
+    ///
+    /// ```compile_fail
+    /// # use elk_rs::elk::Js;
+    /// let (mut buf1, mut buf2) = ([0u8; 1024], [0u8; 1024]);
+    /// Js::new(&mut buf1, |js1| {
+    ///     let s = js1.make_str("abc");
+    ///     Js::new(&mut buf2, |js2| js2.get_str(s).is_some()).unwrap()
+    /// }).unwrap();
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use elk_rs::elk::Js;
+    /// let mut buf = [0u8; 1024];
+    /// let s = Js::new(&mut buf, |js| js.make_str("abc")).unwrap();
+    /// ```
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<R>(buffer: &'a mut [u8], f: impl for<'b> FnOnce(&mut Js<'a, 'b>) -> R) -> Result<R, BufferTooSmall> {
+        let pad = buffer.as_ptr().align_offset(align_of::<Js>());
+        // Room for the global scope object and one stack slot
+        let min = pad.saturating_add(size_of::<Js>() + 16);
+        if buffer.len() < min { return Err(BufferTooSmall { min }) }
+
+        let (head, mem) = buffer[pad..].split_at_mut(size_of::<Js>());
+        let len = mem.len().min(JsOff::MAX as usize) & !3usize;
+        let size = len as JsOff;
+        let rand = seed(mem.as_ptr() as usize);
+        let js = Js {
+            rss: 0,
+            lwm: size,
+            code: "",
+            err_msg: [0; JS_ERR_MAX],
+            err_len: 0,
+            err_kind: ErrorKind::Thrown,
+            err_off: 0,
+            tok: Token::ERR,
+            consumed: true,
+            flags: 0,
+            c_len: 0,
+            pos: 0,
+            t_off: 0,
+            t_len: 0,
+            no_gc: 0,
+            natives: 0,
+            t_val: 0,
+            scope: 0,
+            this: make_undef(),
+            lbl_off: 0,
+            lbl_len: 0,
+            ret: 0,
+            thrown: make_val(Type::ERR, 0),
+            mem: &mut mem[..len],
+            size,
+            brk: 0,
+            gc_t: (size as f32 * JS_GC_THRESHOLD) as JsOff,
+            max_ss: JS_STACK_MAX,
+            stk: 0,
+            gc_runs: 0,
+            gc_freed: 0,
+            rand,
+            id: PhantomData,
+        };
+        // `head` is aligned and big enough, and stays borrowed for 'a
+        let js = unsafe {
+            let ptr = head.as_mut_ptr() as *mut Js<'a, '_>;
+            ptr.write(js);
+            &mut *ptr
+        };
+        js.scope = js.mk_obj(0);
+        Ok(f(js))
+    }
+
+    /// Run `f` with a new brand for values, and release the values it
+    /// created when it returns. Values from outside can't be used in `f`,
+    /// reach them from the global object instead
+    pub fn scope<R>(&mut self, f: impl for<'b> FnOnce(&mut Js<'a, 'b>) -> R) -> R {
+        let size = self.size;
+        let res = f(self);
+        self.size = size;
+        res
+    }
+
+    /// Execute Js code passed as &str, return the value of the last statement
+    pub fn eval(&mut self, code: &str) -> Result<Value<'id>, JsError> {
+        let res = self.exec(code);
+        if !is_err(res) { return Ok(self.root(res)) }
+        Err(JsError::new(self.err_kind, self.err_str(), code, self.err_off as usize))
+    }
+
+    /// Parse JSON text into Js values, like `JSON.parse`. Syntax errors
+    /// point into the text
+    pub fn parse_json(&mut self, text: &str) -> Result<Value<'id>, JsError> {
+        let (res, pos) = json::parse_text(self, text, None);
+        if !is_err(res) { return Ok(self.root(res)) }
+        Err(JsError::new(self.err_kind, self.err_str(), text, pos))
     }
 
-    /// Execute Js code passed as &str
-    pub fn eval(&self, code: &str) -> JsVal {
-        todo!("Implement")
+    /// Serialize a Js value as JSON, like `JSON.stringify`. Values JSON
+    /// can't represent, like functions, give undefined. Errors have no
+    /// position, see `JsError`
+    pub fn to_json(&mut self, val: Value<'id>) -> Result<Value<'id>, JsError> {
+        let size = self.size;
+        let res = json::to_json(self, self.rooted(val), make_undef(), make_undef());
+        self.size = size;
+        if !is_err(res) { return Ok(self.root(res)) }
+        Err(JsError::without_position(self.err_kind, self.err_str()))
     }
-    
+
     /// Return the global object
-    pub fn glob(&self) -> JsVal {
-        todo!("Implement")
+    pub fn glob(&self) -> Value<'id> {
+        Value::from_raw(make_val(Type::OBJ, 0))
     }
 
     /// Stringify Js value
-    pub fn str(&self, val: JsVal) -> &'a str {
-        todo!("Implement")
+    pub fn str(&mut self, val: Value<'id>) -> &str {
+        let v = self.rooted(val);
+        let brk = self.brk as usize;
+        let (heap, free) = self.mem.split_at_mut(brk);
+        let mut buf = Buf::new(&mut free[..self.size as usize - brk]);
+        let err = core::str::from_utf8(&self.err_msg[..self.err_len as usize]).unwrap_or("");
+        let _ = to_str(heap, err, v, &mut buf, 0);
+        let len = buf.len();
+        core::str::from_utf8(&self.mem[brk..brk + len]).unwrap_or("")
     }
 
-    /// Checks arguments validity
-    pub fn chk_args(val: &JsVal, i: i32, args: &'a str) {
-        todo!("Implement")
+    /// Stringify Js value with `Display`, without using free memory
+    pub fn show(&self, val: Value<'id>) -> Show<'_, 'a, 'id> {
+        Show { js: self, val }
     }
 
-    /// Set max stack size
+    /// Set the max native stack size, in bytes, that nested Js function
+    /// calls, statements and expressions may use. Deeper nesting fails
+    /// with `ErrorKind::StackOverflow`. The default is 256 KiB, which fits
+    /// the stack of a thread on most platforms; set it lower for smaller
+    /// stacks. 0 means no limit
     pub fn setmaxss(&mut self, max: isize) {
         self.max_ss = max as u32;
     }
@@ -66,362 +232,4606 @@ impl<'a> Js<'a> {
         self.gc_t = gct as u32;
     }
 
-    pub fn stats(&self, total: &isize, min: &isize, css: &isize) {
-        todo!("Implement")
+    /// Return memory usage and watermarks
+    pub fn stats(&self) -> Stats {
+        Stats {
+            total: self.mem.len(),
+            brk: self.brk as usize,
+            min_free: self.lwm as usize,
+            peak_stack: self.rss as usize,
+            gc_runs: self.gc_runs as usize,
+            gc_reclaimed: self.gc_freed,
+        }
+    }
+
+    /// Iterate over the entities in the JS memory, flagging corrupt ones
+    pub fn heap(&self) -> Heap<'_> {
+        Heap::new(&self.mem[..self.brk as usize])
     }
 
-    /// Print debug info.
-    pub fn dump(&self) {
-        todo!("Implement")
+    /// Decode the whole heap. The result prints as a human-readable listing
+    #[cfg(any(feature = "std", test))]
+    pub fn dump(&self) -> Dump<'_> {
+        Dump {
+            brk: self.brk as usize,
+            free: (self.size - self.brk) as usize,
+            entities: self.heap().collect(),
+        }
     }
 }
 
 /// Api
-impl<'a> Js<'a> {
-    /// All Methods with the get_ prefix get objects from `Js` and return them as rust objects.
-    /// Serializing? Essentially
+impl<'a, 'id> Js<'a, 'id> {
+    // All Methods with the get_ prefix get objects from `Js` and return them as rust objects.
+    // Serializing? Essentially
 
-    /// Return Js value type
-    pub fn get_type(val: JsVal) -> u32 {
-        todo!()
+    /// Return Js number, NaN if the value is not a number
+    pub fn get_num(val: Value<'id>) -> f64 {
+        v_num(val.raw())
     }
 
-    /// Return Js number
-    pub fn get_num(val: JsVal) -> f64 {
-        todo!()
+    /// Return Js boolean, false if the value is not a boolean
+    pub fn get_bool(val: Value<'id>) -> bool {
+        val.kind() == Kind::Boolean && v_data(val.raw()) != 0
     }
 
-    /// Return Js boolean
-    pub fn get_bool(val: JsVal) -> bool {
-        todo!()
+    /// Return Js string, None if the value is not a string
+    pub fn get_str(&self, val: Value<'id>) -> Option<&str> {
+        (val.kind() == Kind::String).then(|| self.load_str(self.rooted(val)))
     }
 
-    /// Return Js string
-    pub fn get_str(val: JsVal, len: &isize) -> &'static str {
-        todo!()
+    /// Return Js array length, None if the value is not an array
+    pub fn get_len(&self, val: Value<'id>) -> Option<usize> {
+        (val.kind() == Kind::Array).then(|| self.arr_len(self.rooted(val)) as usize)
     }
 
-    /// All Methods with the make_ prefix make `Js` objects(values) directly from Rust values.
-    /// Serializing? Essentially
+    /// Return Js array element, undefined if it doesn't exist or the
+    /// value is not an array
+    pub fn get_elem(&mut self, arr: Value<'id>, i: usize) -> Value<'id> {
+        if arr.kind() != Kind::Array || i >= JsOff::MAX as usize { return Js::make_undef() }
+        match self.arr_get(self.rooted(arr), i as JsOff) {
+            HOLE => Js::make_undef(),
+            v => self.root(v),
+        }
+    }
+
+    // All Methods with the make_ prefix make `Js` objects(values) directly from Rust values.
 
     /// Create Js undefined
-    pub fn make_undef() -> JsVal {
-        todo!()
+    pub fn make_undef() -> Value<'id> {
+        Value::from_raw(make_undef())
     }
 
     /// Create Js null
-    pub fn make_null() -> JsVal {
-        todo!()
+    pub fn make_null() -> Value<'id> {
+        Value::from_raw(make_null())
     }
 
     /// Create Js true
-    pub fn make_true() -> JsVal {
-        todo!()
+    pub fn make_true() -> Value<'id> {
+        Value::from_raw(make_bool(true))
     }
-    
+
     /// Create Js false
-    pub fn make_false() -> JsVal {
-        todo!()
+    pub fn make_false() -> Value<'id> {
+        Value::from_raw(make_bool(false))
     }
 
     /// Create Js string
-    pub fn make_str(string: &str, len: isize) -> JsVal {
-        todo!()
+    pub fn make_str(&mut self, string: &str) -> Value<'id> {
+        let res = self.mk_str(string);
+        self.root(res)
     }
 
     /// Create Js number
-    pub fn make_num(num: f64) {
-        todo!()
+    pub fn make_num(num: f64) -> Value<'id> {
+        Value::from_raw(tok_val(num))
     }
 
-    /// Create Js error
-    pub fn make_err(fmt: &str) -> JsVal {
-        make_val(Type::ERR, 0)
+    /// Create empty Js array
+    pub fn make_array(&mut self) -> Value<'id> {
+        let res = self.mk_arr(0);
+        self.root(res)
     }
 
-    /// Create Js function
-    pub fn make_fun() {
-        todo!()
+    /// Create Js error. Returned from a Rust function, it makes `eval`
+    /// fail with `ErrorKind::Thrown`
+    pub fn make_err(&mut self, fmt: &str) -> Value<'id> {
+        Value::from_raw(self.mk_err(ErrorKind::Thrown, fmt))
     }
 
-    /// Create Js object
-    pub fn make_object() {
-        todo!()
+    /// Create Js function from a Rust function or closure. Arguments and
+    /// the result are converted with `FromJs` and `IntoJs`; an argument that
+    /// can't be converted makes the call fail with a Js error. Borrowed
+    /// arguments like `&str` are only valid until the function returns,
+    /// keeping them longer doesn't compile:
+    ///
+    /// ```compile_fail
+    /// # use core::cell::Cell;
+    /// # let mut buf = [0u8; 1024];
+    /// let kept = Cell::new("");
+    /// elk_rs::elk::Js::new(&mut buf, |js| {
+    ///     js.make_fun(|s: &str| kept.set(s));
+    /// }).unwrap();
+    /// ```
+    pub fn make_fun<Args, F: NativeFn<'a, Args>>(&mut self, f: F) -> Value<'id> {
+        Value::from_raw(self.mk_fun(f))
     }
 
-    /// Set Js object attribute
-    pub fn set_object(num: JsVal, val: &str, ano: JsVal) {
-        todo!()
+    /// Register a Rust function as a method of the object, see `make_fun`
+    pub fn set_fn<Args, F: NativeFn<'a, Args>>(&mut self, obj: Value<'id>, name: &str, f: F) -> Value<'id> {
+        let fun = self.mk_fun(f);
+        if is_err(fun) { return Value::from_raw(fun) }
+        Value::from_raw(self.set(self.rooted(obj), name, fun))
+    }
+
+    /// Set Js array element, extending the array with holes if needed.
+    /// Returns the value, or an error if `arr` is not an array or the
+    /// memory is exhausted
+    pub fn set_elem(&mut self, arr: Value<'id>, i: usize, val: Value<'id>) -> Value<'id> {
+        if arr.kind() != Kind::Array { return Value::from_raw(self.mk_err(ErrorKind::Type, "not an array")) }
+        if i >= JsOff::MAX as usize { return Value::from_raw(self.mk_err(ErrorKind::Range, "invalid array index")) }
+        let res = self.arr_set(self.rooted(arr), i as JsOff, self.rooted(val));
+        if is_err(res) { Value::from_raw(res) } else { val }
     }
-}
 
-impl<'a> Js<'a> {
-    pub fn stmt(&self) -> JsVal {
-        let res: JsVal = 0;
+    /// Create empty Js object
+    pub fn make_object(&mut self) -> Value<'id> {
+        let res = self.mk_obj(0);
+        self.root(res)
+    }
 
-        if self.brk > self.gc_t { Js::<'a>::gc(); }
+    /// Set Js object property, creating it if it doesn't exist. Returns the
+    /// value, or an error if `obj` is not an object or the memory is
+    /// exhausted
+    pub fn set_object(&mut self, obj: Value<'id>, key: &str, val: Value<'id>) -> Value<'id> {
+        if v_type(obj.raw()) != Type::OBJ { return Value::from_raw(self.mk_err(ErrorKind::Type, "not an object")) }
+        let res = self.set(self.rooted(obj), key, self.rooted(val));
+        if is_err(res) { Value::from_raw(res) } else { val }
+    }
 
-        match self.next() {
-            Token::CASE | Token::CATCH | Token::CLASS | Token::CONST | Token::DEFAULT | Token::DELETE | Token::DO | Token::FINALLY | Token::IN | Token::INSTANCEOF | Token::NEW | Token::SWITCH | Token::THIS | Token::THROW | Token::TRY | Token::VAR | Token::VOID | Token::WITH | Token::WHILE | Token::YIELD => {
-                res = Js::<'a>::make_err(format!("{} not implemented", 2).as_str());
-            },
-            Token::CONTINUE => { res = Js::<'a>::continue_(); },
-            _ => ()
-        }
-        res
+    /// Iterate over the keys and values of a Js object, in the order the
+    /// properties were created. Yields nothing if `obj` is not an object
+    pub fn props(&mut self, obj: Value<'id>) -> Props<'_, 'a, 'id> {
+        let first = if v_type(obj.raw()) == Type::OBJ { self.first_prop(self.rooted(obj)) } else { 0 };
+        Props::new(self, first as usize)
     }
+}
 
-    pub fn gc() {
+// Values given to Rust are kept on the stack, where GC finds them, and
+// point to their slot. The global object never moves and is used as is
+impl<'a, 'id> Js<'a, 'id> {
+    pub(crate) fn root(&mut self, v: JsVal) -> Value<'id> {
+        if !is_entity(v) || v_data(v) == 0 { return Value::from_raw(v) }
+        let res = self.push(v);
+        if is_err(res) { return Value::from_raw(res) }
+        Value::from_raw(make_val(v_type(v), self.size as u64))
+    }
 
-    } 
+    pub(crate) fn rooted(&self, val: Value<'id>) -> JsVal {
+        let v = val.raw();
+        if !is_entity(v) || v_data(v) == 0 { return v }
+        self.load_val(v_data(v))
+    }
 }
 
-// Internals
-impl<'a> Js<'a> {
-    fn next(&mut self) -> Token {
-        if self.consumed { return self.tok.clone() }
+impl<'a, 'id> Js<'a, 'id> {
+    fn exec(&mut self, code: &str) -> JsVal {
+        // The snippet is only referenced while this call runs: every value
+        // that outlives it is copied into `mem` first.
+        let code: &'a str = unsafe { &*(code as *const str) };
+        let mut res: JsVal = make_undef();
+
         self.consumed = true;
         self.tok = Token::ERR;
-        self.t_off = skip_to_next(self.code, self.c_len, self.pos);
-        self.pos = self.t_off;
-        self.t_len = 0;
-
-        let b = format!("{}{}", self.code, self.t_off);
-        let buf: &str = b.as_str();
-
-        if self.t_off >= self.c_len {
-            self.tok = Token::EOF;
-            return self.tok.clone()
-        }
-
-        match buf.chars().nth(0).unwrap() {
-            '?' => self.token(Token::Q, 1),
-            ':' => self.token(Token::COLON, 1),
-            '(' => self.token(Token::LPAREN, 1),
-            ')' => self.token(Token::RPAREN, 1),
-            '{' => self.token(Token::LBRACE, 1),
-            '}' => self.token(Token::RBRACE, 1),
-            ';' => self.token(Token::SEMICOLON, 1),
-            ',' => self.token(Token::COMMA, 1),
-            '!' => {
-                if self.look(buf, 1, '=') &&
-                    self.look(buf, 2, '=') {
-                    self.token(Token::NE, 3)
-                } else {
-                    self.token(Token::NOT, 1)
-                }
-            },
-            '.' => self.token(Token::DOT, 1),
-            '~' => self.token(Token::TILDE, 1),
-            '-' => {
-                if self.look(buf, 1, '-') {
-                    self.token(Token::POSTDEC, 2)
-                } else if self.look(buf, 1, '='){
-                    self.token(Token::MINUS_ASSIGN, 2)
-                } else {
-                    self.token(Token::MINUS, 1)
-                }
-            },
-            '+' => {
-                if self.look(buf, 1, '+') {
-                    self.token(Token::POSTINC, 2)
-                } else if self.look(buf, 1, '=') {
-                    self.token(Token::PLUS_ASSIGN, 2)
-                } else {
-                    self.token(Token::PLUS, 1)
-                }
-            },
-            '*' => {
-                if self.look(buf, 1, '*') {
-                    self.token(Token::EXP, 2)
-                } else if self.look(buf, 1, '=') {
-                    self.token(Token::MUL_ASSIGN, 1)
-                } else {
-                    self.token(Token::MUL, 1)
-                }
+        self.code = code;
+        self.c_len = code.len() as JsOff;
+        self.pos = 0;
+        self.stk = &res as *const JsVal as usize;
+
+        // Keep the outer scope on the stack, so that GC can move it
+        let scope = self.push(self.scope);
+        if is_err(scope) {
+            self.code = "";
+            self.stk = 0;
+            return scope
+        }
+        let size = self.size;
+        while self.next() != Token::EOF && !is_err(res) {
+            res = self.stmt();
+        }
+        if !is_err(res) && self.is_jump() { res = self.label_err() }
+        self.flags = 0;
+        // A failed statement may leave values behind
+        self.size = size;
+        self.scope = self.pop();
+        self.code = "";
+        self.stk = 0;
+        res
+    }
+
+    fn stmt(&mut self) -> JsVal {
+        self.setlwm();
+        let res = self.check_stack();
+        if is_err(res) { return res }
+        if self.brk > self.gc_t { self.gc(); }
+
+        let res = match self.next() {
+            Token::CASE | Token::CATCH | Token::DEFAULT | Token::FINALLY | Token::VAR | Token::WITH | Token::YIELD => {
+                let word = &self.code[self.t_off as usize..(self.t_off + self.t_len) as usize];
+                self.mk_err(ErrorKind::Syntax, format_args!("'{}' not implemented", word))
             },
-            '/' => {
-                if self.look(buf, 1, '=') {
-                    self.token(Token::DIV_ASSIGN, 2)
-                } else {
-                    self.token(Token::DIV, 1)
-                }
+            Token::SEMICOLON => {
+                self.consumed = true;
+                return make_undef()
             },
-            '%' => {
-                if self.look(buf, 1, '=') {
-                    self.token(Token::REM_ASSIGN, 2)
-                } else {
-                    self.token(Token::REM, 1)
-                }
+            Token::LBRACE => return self.create_block(!self.is(Flags::NOEXEC)),
+            Token::IF => return self.if_(),
+            Token::WHILE => return self.while_((0, 0)),
+            Token::DO => return self.do_((0, 0)),
+            Token::FOR => return self.for_((0, 0)),
+            Token::IDENTIFIER if self.look_ahead() == Token::COLON => return self.label(),
+            Token::BREAK | Token::CONTINUE => self.break_(),
+            Token::TRY => return self.try_(),
+            Token::SWITCH => return self.switch_(),
+            Token::THROW => self.throw_(),
+            Token::FUNC if self.look_ahead() == Token::IDENTIFIER => return self.func_decl(),
+            Token::CLASS => return self.class_decl(),
+            Token::LET | Token::CONST => self.let_(),
+            Token::RETURN => self.return_(),
+            _ => {
+                let res = self.expr();
+                self.resolve(res)
             },
-            '&' => {
-                if self.look(buf, 1, '&') {
-                    self.token(Token::LAND, 2)
-                } else if self.look(buf, 2, '=') {
-                    self.token(Token::AND_ASSIGN, 2)
-                } else {
-                    self.token(Token::AND, 1)
-                }
+        };
+
+        if is_err(res) { return res }
+        match self.next() {
+            Token::SEMICOLON => self.consumed = true,
+            Token::RBRACE | Token::EOF => (),
+            _ => return self.mk_err(ErrorKind::Syntax, "; expected"),
+        }
+        res
+    }
+
+    /// Collect garbage: mark every entity reachable from the roots, then
+    /// slide the live ones down to the beginning of memory
+    pub fn gc(&mut self) {
+        let brk = self.brk;
+        let in_mem = self.code_in_mem();
+        self.gc_mark_all();
+        let table = self.gc_table();
+        self.gc_fixup(table);
+        self.gc_compact();
+        // The running function code moved along with its entity
+        if in_mem { self.set_code(self.no_gc as usize) }
+        self.gc_runs += 1;
+        self.gc_freed += (brk - self.brk) as usize;
+    }
+}
+
+// Garbage collector
+impl<'a, 'id> Js<'a, 'id> {
+    // Size of the entity, in bytes
+    fn entity_size(&self, off: usize) -> JsOff {
+        entity_size(self.load_off(off) & !GC_MARK)
+    }
+
+    // Set the mark bit and queue the entity on the mark stack, which lives
+    // in the unused memory. When it is full, `ovf` tells to rescan the heap
+    fn gc_mark(&mut self, off: JsOff, sp: &mut usize, ovf: &mut bool) {
+        if off >= self.brk { return }
+        let b = self.load_off(off as usize);
+        if b & GC_MARK != 0 { return }
+        self.save_off(off as usize, b | GC_MARK);
+        if *sp + 4 <= self.size as usize {
+            self.save_off(*sp, off);
+            *sp += 4;
+        } else {
+            *ovf = true;
+        }
+    }
+
+    fn gc_mark_val(&mut self, v: JsVal, sp: &mut usize, ovf: &mut bool) {
+        if is_entity(v) { self.gc_mark(v_data(v) as JsOff, sp, ovf) }
+    }
+
+    // Mark everything the entity refers to
+    fn gc_scan(&mut self, off: usize, sp: &mut usize, ovf: &mut bool) {
+        let b = self.load_off(off) & !GC_MARK;
+        match entity_type(b) {
+            Type::OBJ => {
+                if b & !3u32 != 0 { self.gc_mark(b & !3u32, sp, ovf) }
+                self.gc_mark(self.load_off(off + 4) & !FN_FLAGS, sp, ovf);
+                self.gc_mark(self.load_off(off + 8), sp, ovf);
             },
-            '|' => {
-                if self.look(buf, 1, '|') {
-                    self.token(Token::LOR, 2)
-                } else if self.look(buf, 1, '=') {
-                    self.token(Token::OR_ASSIGN, 1)
-                } else {
-                    self.token(Token::OR, 1)
-                }
+            Type::PROP => {
+                if b & !3u32 != 0 { self.gc_mark(b & !3u32, sp, ovf) }
+                self.gc_mark(self.prop_key(off), sp, ovf);
+                self.gc_mark_val(self.load_val(off + 8), sp, ovf);
             },
-            '=' => {
-                if self.look(buf, 1, '=') &&
-                    self.look(buf, 2, '=') {
-                    self.token(Token::EQ, 3)
-                } else {
-                    self.token(Token::ASSIGN, 1)
+            Type::ARR if b & !3u32 != 0 => {
+                let data = b & !3u32;
+                self.gc_mark(data, sp, ovf);
+                for i in 0..self.load_off(off + 4) as usize {
+                    self.gc_mark_val(self.load_val(data as usize + 4 + i * 8), sp, ovf);
                 }
             },
-            '<' => {
-                if self.look(buf, 1, '<') && self.look(buf, 2, '=') {
-                    self.token(Token::SHL_ASSIGN, 3)
-                } else if self.look(buf, 1, '<') {
-                    self.token(Token::SHL, 2)
-                } else if self.look(buf, 1, '=') {
-                    self.token(Token::LE, 2)
-                } else {
-                    self.token(Token::LT, 1)
+            _ => (),
+        }
+    }
+
+    fn gc_mark_all(&mut self) {
+        let base = self.brk as usize;
+        let (mut sp, mut ovf) = (base, false);
+
+        self.gc_mark(0, &mut sp, &mut ovf);
+        self.gc_mark_val(self.scope, &mut sp, &mut ovf);
+        self.gc_mark_val(self.this, &mut sp, &mut ovf);
+        self.gc_mark_val(self.ret, &mut sp, &mut ovf);
+        self.gc_mark_val(self.thrown, &mut sp, &mut ovf);
+        self.gc_mark(self.no_gc, &mut sp, &mut ovf);
+        // Native functions live as long as the instance
+        if self.natives != 0 { self.gc_mark(self.natives, &mut sp, &mut ovf) }
+        for i in 0..self.native_count() {
+            self.gc_mark(self.load_off(self.natives as usize + 4 + i * 4), &mut sp, &mut ovf);
+        }
+        for off in (self.size as usize..self.stack_top()).step_by(8) {
+            self.gc_mark_val(self.load_val(off), &mut sp, &mut ovf);
+        }
+
+        loop {
+            while sp > base {
+                sp -= 4;
+                let off = self.load_off(sp) as usize;
+                self.gc_scan(off, &mut sp, &mut ovf);
+            }
+            if !ovf { break }
+
+            // The mark stack overflowed: some marked entities were never
+            // scanned. Walk the heap and scan all marked ones again
+            ovf = false;
+            let mut off = 0;
+            while off < self.brk as usize {
+                if self.load_off(off) & GC_MARK != 0 {
+                    self.gc_scan(off, &mut sp, &mut ovf);
+                    while sp > base {
+                        sp -= 4;
+                        let next = self.load_off(sp) as usize;
+                        self.gc_scan(next, &mut sp, &mut ovf);
+                    }
                 }
-            },
-            '>' => {
-                if self.look(buf, 1, '>') && self.look(buf, 2, '=') {
-                    self.token(Token::SHR_ASSIGN, 3)
-                } else if self.look(buf, 1, '>') {
-                    self.token(Token::SHR, 2)
-                } else if self.look(buf, 1, '=') {
-                    self.token(Token::GE, 2);
-                } else {
-                    self.token(Token::GT, 1)
+                off += self.entity_size(off) as usize;
+            }
+        }
+    }
+
+    // Build the relocation table in the unused memory: for every run of
+    // live entities, its start offset and the number of garbage bytes before
+    // it. Returns the number of entries, or None if the table doesn't fit
+    fn gc_table(&mut self) -> Option<usize> {
+        let (mut off, mut dead, mut n) = (0usize, 0 as JsOff, 0usize);
+        let mut live = true;
+        let base = self.brk as usize;
+
+        while off < self.brk as usize {
+            let size = self.entity_size(off);
+            let marked = self.load_off(off) & GC_MARK != 0;
+            if marked && !live && dead > 0 {
+                if base + (n + 1) * 8 > self.size as usize { return None }
+                self.save_off(base + n * 8, off as JsOff);
+                self.save_off(base + n * 8 + 4, dead);
+                n += 1;
+            }
+            if !marked { dead += size }
+            live = marked;
+            off += size as usize;
+        }
+        Some(n)
+    }
+
+    // New offset of the live entity once the garbage is squeezed out
+    fn gc_fwd(&self, off: JsOff, table: Option<usize>) -> JsOff {
+        let base = self.brk as usize;
+        match table {
+            Some(n) => {
+                let (mut lo, mut hi) = (0, n);
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    if self.load_off(base + mid * 8) <= off { lo = mid + 1 } else { hi = mid }
                 }
+                if lo == 0 { off } else { off - self.load_off(base + (lo - 1) * 8 + 4) }
             },
-            '^' => {
-                if self.look(buf, 1, '=') {
-                    self.token(Token::XOR_ASSIGN, 2)
-                } else {
-                    self.token(Token::XOR, 1)
+            None => {
+                let (mut pos, mut dead) = (0, 0);
+                while pos < off as usize {
+                    let size = self.entity_size(pos);
+                    if self.load_off(pos) & GC_MARK == 0 { dead += size }
+                    pos += size as usize;
                 }
+                off - dead
             },
-            '"' | '\'' => {
-                let c_n = buf.chars().nth(0).unwrap();
-
-                self.t_len += 1;
+        }
+    }
 
-                while (self.t_off + self.t_len) < self.c_len && buf.chars().nth(self.t_len as usize).unwrap() != c_n {
-                    let mut inc: u8 = 1;
+    fn gc_fwd_val(&self, v: JsVal, table: Option<usize>) -> JsVal {
+        if !is_entity(v) { return v }
+        make_val(v_type(v), self.gc_fwd(v_data(v) as JsOff, table) as u64)
+    }
 
-                    if buf.chars().nth(self.t_len as usize).unwrap() == '\\' {
-                        if self.t_off + self.t_len + 2 > self.c_len {
-                            break;
+    // Point all references to the live entities to their new offsets
+    fn gc_fixup(&mut self, table: Option<usize>) {
+        let mut off = 0usize;
+        while off < self.brk as usize {
+            let b = self.load_off(off);
+            let size = self.entity_size(off);
+            if b & GC_MARK != 0 {
+                let next = b & !3u32 & !GC_MARK;
+                match entity_type(b) {
+                    Type::OBJ | Type::PROP => {
+                        if next != 0 {
+                            let fwd = self.gc_fwd(next, table);
+                            self.save_off(off, fwd | (b & (GC_MARK | 3)));
                         }
-                        inc = 2;
-                        if buf.chars().nth(self.t_len as usize + 1).unwrap() == 'x' {
-                            if self.t_off + self.t_len + 4 > self.c_len {
-                                break;
-                            }
-                            inc = 4;
+                        // Keeps the flags of a property key or function
+                        let link = self.load_off(off + 4);
+                        let fwd = self.gc_fwd(link & !PROP_FLAGS, table);
+                        self.save_off(off + 4, fwd | (link & PROP_FLAGS));
+                        if b & 3 == Type::PROP as JsOff {
+                            let fwd = self.gc_fwd_val(self.load_val(off + 8), table);
+                            self.save_val(off + 8, fwd);
+                        } else {
+                            let fwd = self.gc_fwd(self.load_off(off + 8), table);
+                            self.save_off(off + 8, fwd);
                         }
-                    }
-                    self.t_len += inc as u32;
-                }
-                if c_n == buf.chars().nth(self.t_len as usize).unwrap() {
-                    self.tok = Token::STRING;
-                    self.t_len += 1;
+                    },
+                    // The storage hasn't moved yet, fix the elements in place
+                    Type::ARR if next != 0 => {
+                        for i in 0..self.load_off(off + 4) as usize {
+                            let elem = next as usize + 4 + i * 8;
+                            let fwd = self.gc_fwd_val(self.load_val(elem), table);
+                            self.save_val(elem, fwd);
+                        }
+                        let fwd = self.gc_fwd(next, table);
+                        self.save_off(off, fwd | (b & (GC_MARK | 3)));
+                    },
+                    _ => (),
                 }
-            },
-            '0'..='9' => {
-                self.t_val = tok_val(str_to_double(buf));
-                // TODO(lsm): protect against OOB access
-                self.token(Token::NUMBER, buf.len() as JsOff)
-            },
-            _ => {
-                self.tok = parse_ident(buf, &mut self.t_len);
-            },
-        };
+            }
+            off += size as usize;
+        }
 
-        self.pos = self.t_off + self.t_len;
-        self.tok.clone()
+        self.scope = self.gc_fwd_val(self.scope, table);
+        self.this = self.gc_fwd_val(self.this, table);
+        self.ret = self.gc_fwd_val(self.ret, table);
+        self.thrown = self.gc_fwd_val(self.thrown, table);
+        if self.no_gc < self.brk { self.no_gc = self.gc_fwd(self.no_gc, table) }
+        // The table hasn't moved yet either
+        for i in 0..self.native_count() {
+            let entry = self.natives as usize + 4 + i * 4;
+            self.save_off(entry, self.gc_fwd(self.load_off(entry), table));
+        }
+        if self.natives != 0 { self.natives = self.gc_fwd(self.natives, table) }
+        for off in (self.size as usize..self.stack_top()).step_by(8) {
+            let fwd = self.gc_fwd_val(self.load_val(off), table);
+            self.save_val(off, fwd);
+        }
     }
 
-    fn look(&self, buf: &str, offset: u8, ch: char) -> bool {
-        (self.t_off + offset as u32) < self.c_len && buf.chars().nth(offset as usize).unwrap() == ch
+    // Slide live entities down and clear their marks
+    fn gc_compact(&mut self) {
+        let (mut off, mut brk) = (0usize, 0usize);
+        while off < self.brk as usize {
+            let b = self.load_off(off);
+            let size = self.entity_size(off) as usize;
+            if b & GC_MARK != 0 {
+                self.save_off(off, b & !GC_MARK);
+                self.mem.copy_within(off..off + size, brk);
+                brk += size;
+            }
+            off += size;
+        }
+        self.brk = brk as JsOff;
     }
+}
 
-    fn token(&mut self, tok: Token, len: JsOff) {
-        self.tok = tok;
-        self.t_len = len;
+// Internals
+impl<'a, 'id> Js<'a, 'id> {
+    fn next(&mut self) -> Token {
+        if !self.consumed { return self.tok }
+        self.consumed = false;
+        let code = self.code.as_bytes();
+        self.t_off = skip_to_next(code, self.pos);
+        (self.tok, self.t_len) = scan(&code[self.t_off as usize..]);
+        if self.tok == Token::NUMBER {
+            self.t_val = tok_val(str_to_double(self.tok_str()));
+        }
+        self.pos = self.t_off + self.t_len;
+        self.tok
     }
 
     fn look_ahead(&mut self) -> Token {
-        let old: Token = self.tok.clone();
-        let tok: Token;
-
-        let pos: JsOff = self.pos;
+        let old: Token = self.tok;
+        let (consumed, pos) = (self.consumed, self.pos);
+        let (t_off, t_len) = (self.t_off, self.t_len);
 
         self.consumed = true;
-        tok = self.next();
+        let tok = self.next();
 
+        self.consumed = consumed;
         self.pos = pos;
         self.tok = old;
+        self.t_off = t_off;
+        self.t_len = t_len;
         tok
     }
 
-    fn make_scope(&mut self) {
-        match self.flags {
-            Flags::NOEXEC => panic!("[ELK]: No Exec Has Been Set"),
-            _ => ()
-        }
+    fn is(&self, flag: Flags) -> bool {
+        self.flags & flag as u8 != 0
+    }
+
+    // Source text of the last parsed token
+    fn tok_str(&self) -> &'a str {
+        &self.code[self.t_off as usize..(self.t_off + self.t_len) as usize]
+    }
+
+    fn expect(&mut self, tok: Token, msg: &str) -> JsVal {
+        if self.next() != tok { return self.mk_err(ErrorKind::Syntax, msg) }
+        self.consumed = true;
+        make_undef()
+    }
+
+    fn make_scope(&mut self) -> JsVal {
+        assert!(!self.is(Flags::NOEXEC), "[ELK]: No Exec Has Been Set");
 
         let prev: JsOff = v_data(self.scope) as u32;
-        // self.scope = Js::<'a>::make_object(prev);
+        let scope = self.mk_obj(prev);
+        if !is_err(scope) { self.scope = scope; }
+        scope
     }
 
     fn load_off(&self, off: usize) -> JsOff {
-        let mut v: JsOff = 0;
         assert!(self.brk <= self.size);
-        std::mem::replace(&mut v, self.mem[off] as u32)
+        load_off(self.mem, off)
+    }
+
+    fn save_off(&mut self, off: usize, val: JsOff) {
+        self.mem[off..off + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    pub(crate) fn load_val(&self, off: usize) -> JsVal {
+        load_val(self.mem, off)
+    }
+
+    pub(crate) fn save_val(&mut self, off: usize, val: JsVal) {
+        self.mem[off..off + 8].copy_from_slice(&val.to_le_bytes());
     }
 
     fn upper(&self, scope: JsVal) -> JsVal {
-        make_val(Type::OBJ, self.load_off(v_data(scope) + std::mem::size_of::<JsOff>()) as u64)
+        make_val(Type::OBJ, self.load_off(v_data(scope) + size_of::<JsOff>()) as u64)
     }
 
     fn delete_scope(&mut self) {
         self.scope = self.upper(self.scope);
     }
 
-    fn create_block(&self, create_scope: bool) -> JsVal {
-        let res: JsVal = Js::<'a>::make_undef();
+    // Replace the scope with a copy of it under the same parent, so that
+    // closures made in one iteration of a `for` loop keep its variables
+    fn copy_scope(&mut self) -> JsVal {
+        let scope = self.mk_obj(v_data(self.upper(self.scope)) as JsOff);
+        if is_err(scope) { return scope }
+        let mut off = self.load_off(v_data(self.scope)) & !3u32;
+        while off != 0 {
+            let key = self.load_off(off as usize + 4);
+            let prop = self.set_prop(scope, make_val(Type::STR, (key & !PROP_FLAGS) as u64), self.load_val(off as usize + 8));
+            if is_err(prop) { return prop }
+            self.save_off(v_data(prop) + 4, key);
+            off = self.load_off(off as usize) & !3u32;
+        }
+        self.scope = scope;
+        scope
+    }
+
+    fn create_block(&mut self, create_scope: bool) -> JsVal {
+        let mut res: JsVal = make_undef();
 
-        if create_scope { self.make_scope() };
+        if create_scope {
+            let scope = self.make_scope();
+            if is_err(scope) { return scope }
+        }
         self.consumed = true;
 
         while self.next() != Token::EOF && self.next() != Token::RBRACE && !is_err(res) {
-            let t = self.tok;
+            res = self.stmt();
+        }
+        if !is_err(res) {
+            res = match self.expect(Token::RBRACE, "} expected") {
+                err if is_err(err) => err,
+                _ => res,
+            };
+        }
+        if create_scope { self.delete_scope() }
+        res
+    }
 
-            let res = Js::<'a>::stmt();
+    fn block_or_stmt(&mut self) -> JsVal {
+        if self.next() == Token::LBRACE { return self.create_block(!self.is(Flags::NOEXEC)) }
+        self.stmt()
+    }
+
+    // `let` and `const` declarations, in the current block scope
+    fn let_(&mut self) -> JsVal {
+        let constant = self.tok == Token::CONST;
+        self.consumed = true;
+        loop {
+            if self.next() != Token::IDENTIFIER { return self.mk_err(ErrorKind::Syntax, "identifier expected") }
+            // The name is sliced again after the initializer, which may move
+            // the code if it is a function body, see `gc`
+            let (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+            let mut v = make_undef();
+            self.consumed = true;
 
-            if !is_err(res) && t != Token::LBRACE && t != Token::IF && t != Token::WHILE && self.tok != Token::SEMICOLON {
-                res = Js::<'a>::make_err("; expected");
-                break;
+            if self.next() == Token::ASSIGN {
+                self.consumed = true;
+                v = self.expr();
+                if is_err(v) { return v }
+            } else if constant {
+                return self.mk_err(ErrorKind::Syntax, format_args!("'{}' must be initialized", &self.code[n_off..n_off + n_len]))
+            }
+            if !self.is(Flags::NOEXEC) {
+                let name = &self.code[n_off..n_off + n_len];
+                if self.lkp(self.scope, name) != 0 {
+                    return self.mk_err(ErrorKind::Syntax, format_args!("'{}' already declared", name))
+                }
+                let v = self.resolve(v);
+                let k = self.mk_str(name);
+                if is_err(k) { return k }
+                let prop = self.set_prop(self.scope, k, v);
+                if is_err(prop) { return prop }
+                if constant {
+                    let key = self.load_off(v_data(prop) + 4);
+                    self.save_off(v_data(prop) + 4, key | CONST_PROP);
+                }
             }
+            if self.next() != Token::COMMA { break }
+            self.consumed = true;
+        }
+        make_undef()
+    }
+
+    fn if_(&mut self) -> JsVal {
+        self.consumed = true;
+        let res = self.expect(Token::LPAREN, "( expected");
+        if is_err(res) { return res }
+
+        let cond = self.expr();
+        if is_err(cond) { return cond }
+        let cond = self.resolve(cond);
+        let res = self.expect(Token::RPAREN, ") expected");
+        if is_err(res) { return res }
+
+        let flags = self.flags;
+        let exe = !self.is(Flags::NOEXEC);
+        let cond_true = exe && self.truthy(cond);
+
+        if !cond_true { self.flags |= Flags::NOEXEC as u8 }
+        let blk = self.block_or_stmt();
+        self.restore_flags(flags);
+        if is_err(blk) { return blk }
+        let mut res = if cond_true { blk } else { make_undef() };
+
+        if self.next() == Token::ELSE {
+            self.consumed = true;
+            if cond_true { self.flags |= Flags::NOEXEC as u8 }
+            let blk = self.block_or_stmt();
+            self.restore_flags(flags);
+            if is_err(blk) { return blk }
+            if exe && !cond_true { res = blk }
         }
-        if create_scope { self.delete_scope() }
         res
     }
 
-    fn lkp(&self, obj: JsVal, buf: &str, len: usize) {
-        let off: JsOff = self.load_off(v_data(obj)) & !3u32;
+    // Restore the flags saved before a nested statement. A return, break
+    // or continue executed in it skips the rest of the enclosing code
+    fn restore_flags(&mut self, flags: u8) {
+        let jumps = Flags::RETURN as u8 | Flags::BREAK as u8 | Flags::CONTINUE as u8;
+        self.flags = flags | (self.flags & jumps);
+        if self.flags & jumps != 0 { self.flags |= Flags::NOEXEC as u8 }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    // Whether a break or continue is pending
+    fn is_jump(&self) -> bool {
+        self.is(Flags::BREAK) || self.is(Flags::CONTINUE)
+    }
+
+    // Error for a break or continue whose label doesn't enclose it
+    fn label_err(&mut self) -> JsVal {
+        let label = &self.code[self.lbl_off as usize..(self.lbl_off + self.lbl_len) as usize];
+        self.mk_err(ErrorKind::Syntax, format_args!("label '{}' not found", label))
+    }
+
+    // Continue parsing at `pos`, used to run loops again
+    fn jump(&mut self, pos: JsOff) {
+        self.pos = pos;
+        self.consumed = true;
+    }
+
+    // Check if the pending jump targets the loop or statement with this label
+    fn is_label(&self, label: (JsOff, JsOff)) -> bool {
+        let (off, len) = (label.0 as usize, label.1 as usize);
+        let (l_off, l_len) = (self.lbl_off as usize, self.lbl_len as usize);
+        l_len == 0 || self.code[off..off + len] == self.code[l_off..l_off + l_len]
+    }
+
+    // Called after each loop body: consume a break or continue aimed at
+    // this loop and restore the flags. Returns true if the loop must stop
+    fn loop_end(&mut self, flags: u8, label: (JsOff, JsOff)) -> bool {
+        let jump = self.flags & (Flags::BREAK as u8 | Flags::CONTINUE as u8);
+        if self.is(Flags::RETURN) || (jump != 0 && !self.is_label(label)) {
+            self.restore_flags(flags);
+            return true
+        }
+        self.flags = flags;
+        self.lbl_len = 0;
+        jump == Flags::BREAK as u8
+    }
+
+    // `label: stmt`. A break to the label ends the statement
+    fn label(&mut self) -> JsVal {
+        let label = (self.t_off, self.t_len);
+        self.consumed = true;
+        self.next();
+        self.consumed = true;
+
+        let flags = self.flags;
+        let res = match self.next() {
+            Token::WHILE => self.while_(label),
+            Token::DO => self.do_(label),
+            Token::FOR => self.for_(label),
+            _ => self.stmt(),
+        };
+        if is_err(res) { return res }
+        if self.is(Flags::BREAK) && self.lbl_len != 0 && self.is_label(label) {
+            self.flags = flags;
+            self.lbl_len = 0;
+        }
+        res
+    }
+
+    // `break` and `continue`, with an optional label
+    fn break_(&mut self) -> JsVal {
+        let flag = if self.tok == Token::BREAK { Flags::BREAK } else { Flags::CONTINUE };
+        let word = self.tok_str();
+        self.consumed = true;
+        let (mut off, mut len) = (0, 0);
+        if self.next() == Token::IDENTIFIER {
+            (off, len) = (self.t_off, self.t_len);
+            self.consumed = true;
+        }
+        let in_switch = self.is(Flags::SWITCH) && matches!(flag, Flags::BREAK);
+        if !self.is(Flags::LOOP) && !in_switch && (len == 0 || matches!(flag, Flags::CONTINUE)) {
+            return self.mk_err(ErrorKind::Syntax, format_args!("'{}' outside loop", word))
+        }
+        if !self.is(Flags::NOEXEC) {
+            self.flags |= flag as u8 | Flags::NOEXEC as u8;
+            (self.lbl_off, self.lbl_len) = (off, len);
+        }
+        make_undef()
+    }
+
+    // `throw value`. The error message is the value itself if it is a
+    // string, its `message` property if it has one, or its printed form
+    fn throw_(&mut self) -> JsVal {
+        let off = self.t_off;
+        self.consumed = true;
+        let v = self.expr();
+        if is_err(v) || self.is(Flags::NOEXEC) { return v }
+        let v = self.resolve(v);
+
+        let mut buf = [0u8; JS_ERR_MAX];
+        let mut out = Buf::new(&mut buf);
+        let msg = if v_type(v) == Type::OBJ { self.lkp(v, "message") } else { 0 };
+        let _ = match v_type(v) {
+            Type::STR => out.write_str(self.load_str(v)),
+            _ if msg != 0 => self.fmt_val(self.load_val(msg as usize + 8), &mut out),
+            _ => self.fmt_val(v, &mut out),
+        };
+        // Strings are printed with quotes, drop them
+        let text = out.as_str();
+        let text = if msg != 0 && text.len() >= 2 && text.starts_with('"') { &text[1..text.len() - 1] } else { text };
+        let res = self.mk_err(ErrorKind::Thrown, text);
+        self.thrown = v;
+        self.err_off = off;
+        res
+    }
+
+    // What a catch clause binds: the thrown value, or an error object with
+    // `name` and `message` for errors raised by the engine or by Rust
+    fn error_value(&mut self) -> JsVal {
+        if !is_err(self.thrown) { return self.thrown }
+        let mut buf = [0u8; JS_ERR_MAX];
+        let len = self.err_len as usize;
+        buf[..len].copy_from_slice(&self.err_msg[..len]);
+        let msg = core::str::from_utf8(&buf[..len]).unwrap_or("");
+
+        let obj = self.mk_obj(0);
+        if is_err(obj) { return obj }
+        let name = self.mk_str(self.err_kind.name());
+        if is_err(name) { return name }
+        let res = self.set(obj, "name", name);
+        if is_err(res) { return res }
+        let msg = self.mk_str(msg);
+        if is_err(msg) { return msg }
+        let res = self.set(obj, "message", msg);
+        if is_err(res) { return res }
+        obj
+    }
+
+    // Errors a catch clause can handle. Running out of memory or stack
+    // leaves nothing to recover with, and syntax errors are bugs, unless
+    // they are about data, see `throw_err`
+    fn is_catchable(&self) -> bool {
+        matches!(self.err_kind, ErrorKind::Type | ErrorKind::Reference | ErrorKind::Range | ErrorKind::Thrown)
+            || !is_err(self.thrown)
+    }
+
+    // Make a syntax error just raised about data catchable, by throwing
+    // the error object a catch clause would bind
+    pub(crate) fn throw_err(&mut self, err: JsVal) -> JsVal {
+        if self.err_kind != ErrorKind::Syntax { return err }
+        let v = self.error_value();
+        if is_err(v) { return v }
+        self.thrown = v;
+        err
+    }
+
+    // Skip the block at `pos` after an error stopped it halfway
+    fn skip_block(&mut self, pos: JsOff, flags: u8) -> JsVal {
+        self.flags = flags | Flags::NOEXEC as u8;
+        self.jump(pos);
+        self.next();
+        let res = self.create_block(false);
+        self.flags = flags;
+        res
+    }
+
+    // `try {} catch (e) {} finally {}`, with either clause optional. The
+    // finally block runs after an error, a return, break or continue too,
+    // which then resume unless the finally block raises or jumps itself
+    fn try_(&mut self) -> JsVal {
+        let size = self.size;
+        let res = self.try_catch();
+        self.size = size;
+        res
+    }
+
+    fn try_catch(&mut self) -> JsVal {
+        self.consumed = true;
+        let (flags, exe) = (self.flags, !self.is(Flags::NOEXEC));
+        if self.next() != Token::LBRACE { return self.mk_err(ErrorKind::Syntax, "{ expected") }
+        let try_pos = self.t_off;
+        // The scope to return to if the block fails halfway
+        let res = self.push(self.scope);
+        if is_err(res) { return res }
+        let slot = self.size as usize;
+
+        let mut res = self.create_block(exe);
+        if is_err(res) {
+            if !self.is_catchable() { return res }
+            self.scope = self.load_val(slot);
+            let skip = self.skip_block(try_pos, flags);
+            if is_err(skip) { return skip }
+        }
+
+        if self.next() == Token::CATCH {
+            self.consumed = true;
+            let (mut n_off, mut n_len) = (0, 0);
+            if self.next() == Token::LPAREN {
+                self.consumed = true;
+                if self.next() != Token::IDENTIFIER { return self.mk_err(ErrorKind::Syntax, "identifier expected") }
+                (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+                self.consumed = true;
+                let res = self.expect(Token::RPAREN, ") expected");
+                if is_err(res) { return res }
+            }
+            if self.next() != Token::LBRACE { return self.mk_err(ErrorKind::Syntax, "{ expected") }
+
+            let (before, catch_pos) = (self.flags, self.t_off);
+            if is_err(res) {
+                let v = self.error_value();
+                if is_err(v) { return v }
+                let scope = self.make_scope();
+                if is_err(scope) { return scope }
+                if n_len > 0 {
+                    let k = self.mk_str(&self.code[n_off..n_off + n_len]);
+                    if is_err(k) { return k }
+                    let prop = self.set_prop(self.scope, k, v);
+                    if is_err(prop) { return prop }
+                }
+                res = self.create_block(false);
+                self.scope = self.load_val(slot);
+                if is_err(res) {
+                    if !self.is_catchable() { return res }
+                    let skip = self.skip_block(catch_pos, before);
+                    if is_err(skip) { return skip }
+                }
+                self.restore_flags(before);
+            } else {
+                self.flags |= Flags::NOEXEC as u8;
+                let blk = self.create_block(false);
+                self.flags = before;
+                if is_err(blk) { return blk }
+            }
+        } else if self.next() != Token::FINALLY {
+            return self.mk_err(ErrorKind::Syntax, "catch or finally expected")
+        }
+
+        if self.next() == Token::FINALLY {
+            self.consumed = true;
+            if self.next() != Token::LBRACE { return self.mk_err(ErrorKind::Syntax, "{ expected") }
+            // Whatever ends the try statement, an error, a return or a
+            // jump, is put aside while the finally block runs
+            let jumps = Flags::RETURN as u8 | Flags::BREAK as u8 | Flags::CONTINUE as u8;
+            let pending = self.flags & jumps;
+            let (lbl_off, lbl_len) = (self.lbl_off, self.lbl_len);
+            let (kind, msg, len, off) = (self.err_kind, self.err_msg, self.err_len, self.err_off);
+            let saved = self.push(self.ret);
+            if is_err(saved) { return saved }
+            // The ERR marker would read as a failed push, and isn't an entity
+            let thrown = self.thrown;
+            let saved = self.push(if is_err(thrown) { make_undef() } else { thrown });
+            if is_err(saved) { return saved }
+
+            if pending != 0 { self.flags = flags }
+            let fin = self.create_block(exe);
+            if is_err(fin) { return fin }
+            if self.flags & jumps == 0 && pending != 0 {
+                self.flags |= pending | Flags::NOEXEC as u8;
+                (self.lbl_off, self.lbl_len) = (lbl_off, lbl_len);
+                self.ret = self.load_val(self.size as usize + 8);
+            }
+            if is_err(res) {
+                if self.flags & jumps != 0 { return make_undef() }
+                (self.err_kind, self.err_msg, self.err_len, self.err_off) = (kind, msg, len, off);
+                self.thrown = if is_err(thrown) { thrown } else { self.load_val(self.size as usize) };
+            }
+        }
+        if is_err(res) { res } else { make_undef() }
+    }
+
+    // `switch (value) { case a: ... default: ... }`. Cases are compared with
+    // strict equality, in order; the statements run from the first match,
+    // or from `default` if nothing matches, until a break
+    fn switch_(&mut self) -> JsVal {
+        let size = self.size;
+        let res = self.switch_stmt();
+        self.size = size;
+        res
+    }
+
+    fn switch_stmt(&mut self) -> JsVal {
+        self.consumed = true;
+        let res = self.expect(Token::LPAREN, "( expected");
+        if is_err(res) { return res }
+        let v = self.expr();
+        if is_err(v) { return v }
+        let v = self.resolve(v);
+        let res = self.expect(Token::RPAREN, ") expected");
+        if is_err(res) { return res }
+        if self.next() != Token::LBRACE { return self.mk_err(ErrorKind::Syntax, "{ expected") }
+        self.consumed = true;
+
+        let (flags, exe) = (self.flags, !self.is(Flags::NOEXEC));
+        let res = self.push(v);
+        if is_err(res) { return res }
+        let slot = self.size as usize;
+        if exe {
+            let scope = self.make_scope();
+            if is_err(scope) { return scope }
+        }
+
+        let base = flags | Flags::SWITCH as u8;
+        let (mut res, matched, default) = self.switch_cases(slot, base, false);
+        if !is_err(res) && exe && !matched {
+            if let Some(pos) = default {
+                self.jump(pos);
+                res = self.switch_cases(slot, base, true).0;
+            }
+        }
+        if exe { self.delete_scope() }
+        if is_err(res) {
+            self.flags = flags;
+            return res
+        }
+        let res = self.expect(Token::RBRACE, "} expected");
+        if is_err(res) { return res }
+
+        if self.is(Flags::BREAK) && self.lbl_len == 0 { self.flags = flags } else { self.restore_flags(flags) }
+        make_undef()
+    }
+
+    // Parse the switch body up to the closing brace. Case values are only
+    // computed until one matches; the statements before that are skipped.
+    // Returns whether a case matched and where `default` starts
+    fn switch_cases(&mut self, slot: usize, base: u8, mut matched: bool) -> (JsVal, bool, Option<JsOff>) {
+        let exe = base & Flags::NOEXEC as u8 == 0;
+        let mut default = None;
+        self.flags = if matched { base } else { base | Flags::NOEXEC as u8 };
+        loop {
+            match self.next() {
+                Token::RBRACE => break,
+                Token::EOF => return (self.mk_err(ErrorKind::Syntax, "} expected"), matched, default),
+                Token::CASE => {
+                    self.consumed = true;
+                    let (before, test) = (self.flags, exe && !matched);
+                    self.flags = if test { base } else { before | Flags::NOEXEC as u8 };
+                    let v = self.expr();
+                    self.flags = before;
+                    if is_err(v) { return (v, matched, default) }
+                    let res = self.expect(Token::COLON, ": expected");
+                    if is_err(res) { return (res, matched, default) }
+                    if test && self.strict_eq(self.load_val(slot), self.resolve(v)) {
+                        matched = true;
+                        self.flags = base;
+                    }
+                },
+                Token::DEFAULT => {
+                    self.consumed = true;
+                    let res = self.expect(Token::COLON, ": expected");
+                    if is_err(res) { return (res, matched, default) }
+                    default = Some(self.pos);
+                },
+                _ => {
+                    let res = self.stmt();
+                    if is_err(res) { return (res, matched, default) }
+                },
+            }
+        }
+        (make_undef(), matched, default)
+    }
+
+    fn while_(&mut self, label: (JsOff, JsOff)) -> JsVal {
+        self.consumed = true;
+        let res = self.expect(Token::LPAREN, "( expected");
+        if is_err(res) { return res }
+
+        let (flags, cond_pos) = (self.flags, self.pos);
+        loop {
+            self.jump(cond_pos);
+            let cond = self.expr();
+            if is_err(cond) { return cond }
+            let run = !self.is(Flags::NOEXEC) && self.truthy(self.resolve(cond));
+            let res = self.expect(Token::RPAREN, ") expected");
+            if is_err(res) { return res }
+
+            if !run { self.flags |= Flags::NOEXEC as u8 }
+            self.flags |= Flags::LOOP as u8;
+            let blk = self.block_or_stmt();
+            if is_err(blk) {
+                self.flags = flags;
+                return blk
+            }
+            if self.loop_end(flags, label) || !run { break }
+        }
+        make_undef()
+    }
+
+    fn do_(&mut self, label: (JsOff, JsOff)) -> JsVal {
+        self.consumed = true;
+        let (flags, body_pos) = (self.flags, self.pos);
+        loop {
+            self.jump(body_pos);
+            self.flags |= Flags::LOOP as u8;
+            let blk = self.block_or_stmt();
+            if is_err(blk) {
+                self.flags = flags;
+                return blk
+            }
+            // After a break, the condition is parsed but not run
+            let done = self.loop_end(flags, label);
+            let after = self.flags;
+            if done { self.flags |= Flags::NOEXEC as u8 }
+
+            let res = self.expect(Token::WHILE, "while expected");
+            if is_err(res) { return res }
+            let res = self.expect(Token::LPAREN, "( expected");
+            if is_err(res) { return res }
+            let cond = self.expr();
+            if is_err(cond) { return cond }
+            let run = !self.is(Flags::NOEXEC) && self.truthy(self.resolve(cond));
+            self.flags = after;
+            let res = self.expect(Token::RPAREN, ") expected");
+            if is_err(res) { return res }
+            if !run { break }
+        }
+        if self.next() == Token::SEMICOLON { self.consumed = true }
+        make_undef()
+    }
+
+    // `for (init; cond; step) body` and `for (name in obj) body`. Variables
+    // declared in the header live in a scope around the loop, which `let`
+    // loops copy for every iteration
+    fn for_(&mut self, label: (JsOff, JsOff)) -> JsVal {
+        self.consumed = true;
+        let res = self.expect(Token::LPAREN, "( expected");
+        if is_err(res) { return res }
+
+        let (flags, size, exe) = (self.flags, self.size, !self.is(Flags::NOEXEC));
+        if exe {
+            let scope = self.make_scope();
+            if is_err(scope) { return scope }
+        }
+        let res = if self.for_in_ahead() { self.for_in(label) } else { self.for_loop(label) };
+        if exe { self.delete_scope() }
+        self.size = size;
+        if is_err(res) { self.flags = flags }
+        res
+    }
+
+    // Check for `[let|const] name in` after the opening paren
+    fn for_in_ahead(&mut self) -> bool {
+        let pos = self.pos;
+        if matches!(self.next(), Token::LET | Token::CONST) { self.consumed = true }
+        let res = self.next() == Token::IDENTIFIER && self.look_ahead() == Token::IN;
+        self.jump(pos);
+        res
+    }
+
+    fn for_loop(&mut self, label: (JsOff, JsOff)) -> JsVal {
+        let flags = self.flags;
+        let copy = self.next() == Token::LET && !self.is(Flags::NOEXEC);
+        let init = match self.next() {
+            Token::SEMICOLON => make_undef(),
+            Token::LET | Token::CONST => self.let_(),
+            _ => self.expr(),
+        };
+        if is_err(init) { return init }
+        if copy {
+            let scope = self.copy_scope();
+            if is_err(scope) { return scope }
+        }
+        let res = self.expect(Token::SEMICOLON, "; expected");
+        if is_err(res) { return res }
+
+        // Find where the step and the body start, without running anything
+        let cond_pos = self.pos;
+        self.flags |= Flags::NOEXEC as u8;
+        if self.next() != Token::SEMICOLON {
+            let cond = self.expr();
+            if is_err(cond) { return cond }
+        }
+        let res = self.expect(Token::SEMICOLON, "; expected");
+        if is_err(res) { return res }
+        let step_pos = self.pos;
+        if self.next() != Token::RPAREN {
+            let step = self.expr();
+            if is_err(step) { return step }
+        }
+        let res = self.expect(Token::RPAREN, ") expected");
+        if is_err(res) { return res }
+        let body_pos = self.pos;
+        self.flags = flags;
+
+        loop {
+            self.jump(cond_pos);
+            let mut run = !self.is(Flags::NOEXEC);
+            if self.next() != Token::SEMICOLON {
+                let cond = self.expr();
+                if is_err(cond) { return cond }
+                run = run && self.truthy(self.resolve(cond));
+            }
+
+            self.jump(body_pos);
+            if !run { self.flags |= Flags::NOEXEC as u8 }
+            self.flags |= Flags::LOOP as u8;
+            let blk = self.block_or_stmt();
+            if is_err(blk) { return blk }
+            if self.loop_end(flags, label) || !run { break }
+
+            // The step updates the variables of the next iteration
+            if copy {
+                let scope = self.copy_scope();
+                if is_err(scope) { return scope }
+            }
+            self.jump(step_pos);
+            if self.next() != Token::RPAREN {
+                let step = self.expr();
+                if is_err(step) { return step }
+            }
+        }
+        make_undef()
+    }
+
+    // Iterate over the property names of an object, or the indices of an
+    // array. The next property or index to visit is kept on the stack,
+    // where GC can move it, see `for_in_key`
+    fn for_in(&mut self, label: (JsOff, JsOff)) -> JsVal {
+        let (flags, exe) = (self.flags, !self.is(Flags::NOEXEC));
+        let decl = matches!(self.next(), Token::LET | Token::CONST);
+        let constant = self.tok == Token::CONST;
+        if decl { self.consumed = true }
+        self.next();
+        let (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+        self.consumed = true;
+        self.next();
+        self.consumed = true;
+
+        let obj = self.expr();
+        if is_err(obj) { return obj }
+        let obj = self.resolve(obj);
+        let res = self.expect(Token::RPAREN, ") expected");
+        if is_err(res) { return res }
+        let body_pos = self.pos;
+
+        if exe && decl {
+            let k = self.mk_str(&self.code[n_off..n_off + n_len]);
+            if is_err(k) { return k }
+            let prop = self.set_prop(self.scope, k, make_undef());
+            if is_err(prop) { return prop }
+            if constant { self.save_off(v_data(prop) + 4, v_data(k) as JsOff | CONST_PROP) }
+        }
+        let cursor = match v_type(obj) {
+            Type::ARR if exe => {
+                let res = self.push(obj);
+                if is_err(res) { return res }
+                tok_val(0.0)
+            },
+            Type::OBJ if exe => make_val(Type::PROP, self.visible_prop(self.first_prop(obj)) as u64),
+            _ => make_val(Type::PROP, 0),
+        };
+        let res = self.push(cursor);
+        if is_err(res) { return res }
+        let slot = self.size as usize;
+
+        loop {
+            self.jump(body_pos);
+            let key = if exe { self.for_in_key(slot) } else { make_undef() };
+            if is_err(key) { return key }
+            let run = v_type(key) == Type::STR;
+            if run {
+                let name = &self.code[n_off..n_off + n_len];
+                if decl {
+                    // A new binding for each property
+                    let scope = self.copy_scope();
+                    if is_err(scope) { return scope }
+                    let var = self.lkp(self.scope, name) as usize;
+                    self.save_val(var + 8, key);
+                } else {
+                    let var = self.lookup(name);
+                    if is_err(var) { return var }
+                    let res = self.assign(var, key);
+                    if is_err(res) { return res }
+                }
+            }
+
+            if !run { self.flags |= Flags::NOEXEC as u8 }
+            self.flags |= Flags::LOOP as u8;
+            let blk = self.block_or_stmt();
+            if is_err(blk) { return blk }
+            if self.loop_end(flags, label) || !run { break }
+        }
+        make_undef()
+    }
+
+    // Next key of a `for..in` loop, undefined once done. The cursor at
+    // `slot` is the next property, or for arrays the next index, with the
+    // array right above it. Holes are skipped
+    fn for_in_key(&mut self, slot: usize) -> JsVal {
+        let cursor = self.load_val(slot);
+        if v_type(cursor) == Type::NUM {
+            let arr = self.load_val(slot + 8);
+            let mut i = v_num(cursor) as JsOff;
+            while i < self.arr_len(arr) && self.arr_get(arr, i) == HOLE { i += 1 }
+            if i >= self.arr_len(arr) { return make_undef() }
+            self.save_val(slot, tok_val((i + 1) as f64));
+            return self.stringify(tok_val(i as f64))
+        }
+        let prop = v_data(cursor) as JsOff;
+        if prop == 0 { return make_undef() }
+        let next = self.visible_prop(self.next_prop(prop));
+        self.save_val(slot, make_val(Type::PROP, next as u64));
+        make_val(Type::STR, self.prop_key(prop as usize) as u64)
+    }
+
+    fn return_(&mut self) -> JsVal {
+        if !self.is(Flags::CALL) { return self.mk_err(ErrorKind::Syntax, "'return' outside function") }
+        self.consumed = true;
+        let mut res = make_undef();
+        if !matches!(self.next(), Token::SEMICOLON | Token::RBRACE | Token::EOF) {
+            res = self.expr();
+            if is_err(res) { return res }
+        }
+        if !self.is(Flags::NOEXEC) {
+            self.ret = self.resolve(res);
+            self.flags |= Flags::RETURN as u8 | Flags::NOEXEC as u8;
+        }
+        make_undef()
+    }
+
+    // Function literal: `function [name](a, b) { ... }`. The code from the
+    // parameter list to the closing brace is copied into a string and kept,
+    // as a CODEREF, in a function object whose parent is the current scope
+    fn func_literal(&mut self) -> JsVal {
+        if self.next() == Token::IDENTIFIER { self.consumed = true }
+        if self.next() != Token::LPAREN { return self.mk_err(ErrorKind::Syntax, "( expected") }
+        let start = self.t_off as usize;
+
+        // Only check the syntax, the code runs on each call
+        let flags = self.flags;
+        self.flags = (flags & !(Flags::LOOP as u8 | Flags::SWITCH as u8)) | Flags::NOEXEC as u8 | Flags::CALL as u8;
+        let res = self.params(0, 0);
+        let res = match res {
+            _ if is_err(res) => res,
+            _ if self.next() != Token::LBRACE => self.mk_err(ErrorKind::Syntax, "{ expected"),
+            _ => self.create_block(false),
+        };
+        self.flags = flags;
+        if is_err(res) || self.is(Flags::NOEXEC) { return res }
+
+        let code = self.mk_str(&self.code[start..self.pos as usize]);
+        if is_err(code) { return code }
+        self.mk_func(v_data(self.scope) as JsOff, make_val(Type::CODEREF, v_data(code) as u64))
+    }
+
+    // Function object running `code`, a CODEREF, in a scope under `parent`.
+    // The code is its first property, an internal one
+    fn mk_func(&mut self, parent: JsOff, code: JsVal) -> JsVal {
+        let obj = self.mk_obj(parent);
+        if is_err(obj) { return obj }
+        let res = self.set_internal(obj, "code", code);
+        if is_err(res) { return res }
+        make_val(Type::FUNC, v_data(obj) as u64)
+    }
+
+    // Function declaration, binds the function to its name in the current scope
+    fn func_decl(&mut self) -> JsVal {
+        self.consumed = true;
+        self.next();
+        let (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+        let f = self.func_literal();
+        if is_err(f) || self.is(Flags::NOEXEC) { return f }
+
+        self.declare(&self.code[n_off..n_off + n_len], f)
+    }
+
+    // Bind the declared function or class to its name in the current scope
+    fn declare(&mut self, name: &str, v: JsVal) -> JsVal {
+        let off = self.lkp(self.scope, name);
+        if off != 0 {
+            let res = self.assign(make_val(Type::PROP, off as u64), v);
+            if is_err(res) { return res }
+            return make_undef()
+        }
+        let k = self.mk_str(name);
+        if is_err(k) { return k }
+        let prop = self.set_prop(self.scope, k, v);
+        if is_err(prop) { return prop }
+        make_undef()
+    }
+
+    // Class declaration, binds the class to its name in the current scope
+    fn class_decl(&mut self) -> JsVal {
+        self.consumed = true;
+        if self.next() != Token::IDENTIFIER { return self.mk_err(ErrorKind::Syntax, "class name expected") }
+        let (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+        let c = self.class_literal();
+        if is_err(c) || self.is(Flags::NOEXEC) { return c }
+        self.declare(&self.code[n_off..n_off + n_len], c)
+    }
+
+    // `class Name extends Base { ... }`, lowered to a function and its
+    // prototype: the constructor becomes the class function, methods go to
+    // the prototype and static members to the class itself. The class and
+    // the base constructor share the code when there is no constructor.
+    //
+    // With a base class, the methods are created in a scope holding the
+    // base as its internal `super` property and the prototype as `home`,
+    // the object the method lives on, which `super` looks up. Static
+    // methods get a scope of their own, whose `home` is the class
+    fn class_literal(&mut self) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        if self.next() == Token::IDENTIFIER { self.consumed = true }
+        let mut base = make_undef();
+        if self.next() == Token::EXTENDS {
+            self.consumed = true;
+            base = self.call_dot();
+            if is_err(base) { return base }
+            base = self.resolve(base);
+            if exe && v_type(base) != Type::FUNC { return self.mk_err(ErrorKind::Type, "class extends value is not a constructor") }
+        }
+        if self.next() != Token::LBRACE { return self.mk_err(ErrorKind::Syntax, "{ expected") }
+        self.consumed = true;
+
+        // The outer scope, the class, its prototype and the scope of the
+        // static methods stay on the stack
+        let size = self.size;
+        let res = self.push(self.scope);
+        if is_err(res) { return res }
+        let slot = self.size as usize;
+        let res = if exe { self.class_init(base) } else { make_undef() };
+        let res = if is_err(res) { res } else { self.class_body(slot) };
+        self.scope = self.load_val(slot);
+        self.size = size;
+        res
+    }
+
+    // Make the class with the default constructor and its prototype
+    fn class_init(&mut self, base: JsVal) -> JsVal {
+        let (c, proto) = if v_type(base) == Type::FUNC {
+            let proto = self.fn_prototype(base);
+            if is_err(proto) { return proto }
+            let obj = self.mk_obj(0);
+            if is_err(obj) { return obj }
+            if v_type(proto) == Type::OBJ { self.save_off(v_data(obj) + 8, v_data(proto) as JsOff) }
+            let c = self.mk_func((self.load_off(v_data(base) + 4) & !FN_FLAGS) | CLASS_FN, self.get_internal(base, "code"));
+            if is_err(c) { return c }
+            self.save_off(v_data(c) + 8, v_data(base) as JsOff);
+
+            let scope = self.mk_obj(v_data(self.scope) as JsOff);
+            if is_err(scope) { return scope }
+            let res = self.set_internal(scope, "super", base);
+            if is_err(res) { return res }
+            let res = self.set_internal(scope, "home", obj);
+            if is_err(res) { return res }
+            self.scope = scope;
+            (c, obj)
+        } else {
+            let proto = self.mk_obj(0);
+            if is_err(proto) { return proto }
+            let code = self.mk_str("() {}");
+            if is_err(code) { return code }
+            let c = self.mk_func(v_data(self.scope) as JsOff | CLASS_FN, make_val(Type::CODEREF, v_data(code) as u64));
+            if is_err(c) { return c }
+            (c, proto)
+        };
+        let res = self.set(c, "prototype", proto);
+        if is_err(res) { return res }
+        let res = self.set(proto, "constructor", c);
+        if is_err(res) { return res }
+        self.hide_prop(proto, "constructor");
+        for v in [c, proto, make_undef()] {
+            let res = self.push(v);
+            if is_err(res) { return res }
+        }
+        c
+    }
+
+    // Parse the members. The class, its prototype and the static scope are
+    // in the stack slots below the outer scope at `slot`
+    fn class_body(&mut self, slot: usize) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        while self.next() != Token::RBRACE {
+            if self.tok == Token::SEMICOLON {
+                self.consumed = true;
+                continue
+            }
+            let is_static = self.tok == Token::IDENTIFIER && self.tok_str() == "static"
+                && !matches!(self.look_ahead(), Token::LPAREN | Token::ASSIGN | Token::SEMICOLON | Token::RBRACE);
+            if is_static { self.consumed = true }
+            let tok = self.next();
+            if tok != Token::IDENTIFIER && !is_keyword(tok) { return self.mk_err(ErrorKind::Syntax, "bad property name") }
+            self.consumed = true;
+            let (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+            let is_ctor = !is_static && self.tok_str() == "constructor";
+
+            let is_method = self.next() == Token::LPAREN;
+            let v = match self.next() {
+                Token::LPAREN if is_static && exe => {
+                    let scope = self.static_scope(slot);
+                    if is_err(scope) { return scope }
+                    let f = self.func_literal();
+                    if v_type(scope) != Type::UNDEF { self.scope = self.upper(self.load_val(slot - 32)) }
+                    f
+                },
+                Token::LPAREN => self.func_literal(),
+                Token::ASSIGN if is_static => {
+                    self.consumed = true;
+                    let v = self.assignment();
+                    if is_err(v) { v } else { self.resolve(v) }
+                },
+                _ if is_static => make_undef(),
+                Token::ASSIGN => return self.mk_err(ErrorKind::Syntax, "class fields must be static"),
+                _ => return self.mk_err(ErrorKind::Syntax, "( expected"),
+            };
+            if is_err(v) { return v }
+            if !exe { continue }
+
+            let (c, proto) = (self.load_val(slot - 8), self.load_val(slot - 16));
+            let res = if is_ctor {
+                // The class keeps its identity, it takes the code and the
+                // scope of the constructor
+                let res = self.set_internal(c, "code", self.get_internal(v, "code"));
+                if is_err(res) { return res }
+                self.save_off(v_data(c) + 4, self.load_off(v_data(v) + 4) | CLASS_FN);
+                v
+            } else {
+                let obj = if is_static { c } else { proto };
+                let name = &self.code[n_off..n_off + n_len];
+                let res = self.set(obj, name, v);
+                // Methods aren't enumerable, static fields are
+                if !is_err(res) && is_method { self.hide_prop(obj, name) }
+                res
+            };
+            if is_err(res) { return res }
+        }
+        self.consumed = true;
+        if exe { self.load_val(slot - 8) } else { make_undef() }
+    }
+
+    // Enter the scope of the static methods, made on first use. Without a
+    // base class, static methods don't need one
+    fn static_scope(&mut self, slot: usize) -> JsVal {
+        if self.proto_of(v_data(self.load_val(slot - 8))) == 0 { return make_undef() }
+        let mut scope = self.load_val(slot - 32);
+        if v_type(scope) == Type::UNDEF {
+            scope = self.mk_obj(v_data(self.scope) as JsOff);
+            if is_err(scope) { return scope }
+            self.save_val(slot - 32, scope);
+            let res = self.set_internal(scope, "home", self.load_val(slot - 8));
+            if is_err(res) { return res }
+        }
+        self.scope = scope;
+        scope
+    }
+
+    fn expr(&mut self) -> JsVal {
+        self.assignment()
+    }
+
+    // Fail once nested calls, statements or expressions use more native
+    // stack than `setmaxss` allows
+    fn check_stack(&mut self) -> JsVal {
+        let marker = 0u8;
+        let depth = self.stk.saturating_sub(&marker as *const u8 as usize);
+        if self.max_ss > 0 && depth > self.max_ss as usize {
+            return self.mk_err(ErrorKind::StackOverflow, "stack overflow")
+        }
+        make_undef()
+    }
+
+    fn assignment(&mut self) -> JsVal {
+        let res = self.check_stack();
+        if is_err(res) { return res }
+        if self.is_arrow() { return self.arrow() }
+        let size = self.size;
+        let res = self.ternary();
+        if is_err(res) || !is_assign(self.next()) { return res }
+
+        let op = self.tok;
+        self.consumed = true;
+        // The target stays on the stack while the value is computed: it may
+        // call a function, which may run GC
+        let lhs = self.push(res);
+        if is_err(lhs) {
+            self.size = size;
+            return lhs
+        }
+        let slot = self.size;
+        let rhs = self.assignment();
+        let lhs = self.load_val(slot as usize);
+        let res = if is_err(rhs) { rhs } else { self.do_op(op, lhs, rhs) };
+        // Also drops what a REF target left on the stack
+        self.size = size;
+        res
+    }
+
+    fn ternary(&mut self) -> JsVal {
+        let cond = self.logical_or();
+        if is_err(cond) || self.next() != Token::Q { return cond }
+        self.consumed = true;
+
+        let flags = self.flags;
+        let cond = self.resolve(cond);
+        let cond_true = !self.is(Flags::NOEXEC) && self.truthy(cond);
+
+        if !cond_true { self.flags |= Flags::NOEXEC as u8 }
+        let yes = self.assignment();
+        self.flags = flags;
+        if is_err(yes) { return yes }
+
+        let res = self.expect(Token::COLON, ": expected");
+        if is_err(res) { return res }
+
+        if cond_true { self.flags |= Flags::NOEXEC as u8 }
+        let no = self.assignment();
+        self.flags = flags;
+        if is_err(no) { return no }
+
+        self.resolve(if cond_true { yes } else { no })
+    }
+
+    // Short-circuiting && and ||: the right hand side is parsed, but not
+    // executed, once the left hand side decides the result
+    fn logical(&mut self, op: Token, f: fn(&mut Self) -> JsVal) -> JsVal {
+        let mut res = f(self);
+
+        while !is_err(res) && self.next() == op {
+            self.consumed = true;
+            let flags = self.flags;
+            res = self.resolve(res);
+            let done = self.is(Flags::NOEXEC) || self.truthy(res) == (op == Token::LOR);
+
+            if done { self.flags |= Flags::NOEXEC as u8 }
+            let rhs = f(self);
+            self.flags = flags;
+            if is_err(rhs) { return rhs }
+            if !done { res = self.resolve(rhs) }
+        }
+        res
+    }
+
+    // Left-to-right binary operators
+    fn binary(&mut self, ops: &[Token], f: fn(&mut Self) -> JsVal) -> JsVal {
+        let mut res = f(self);
+
+        while !is_err(res) && ops.contains(&self.next()) {
+            let op = self.tok;
+            self.consumed = true;
+            // Keep the left operand on the stack, see `assignment`
+            let lhs = self.push(res);
+            if is_err(lhs) { return lhs }
+            let slot = self.size;
+            let rhs = f(self);
+            res = self.load_val(slot as usize);
+            self.size = slot + 8;
+            if is_err(rhs) { return rhs }
+            res = self.do_op(op, res, rhs);
+        }
+        res
+    }
+
+    fn logical_or(&mut self) -> JsVal {
+        self.logical(Token::LOR, Js::logical_and)
+    }
+
+    fn logical_and(&mut self) -> JsVal {
+        self.logical(Token::LAND, Js::bitwise_or)
+    }
+
+    fn bitwise_or(&mut self) -> JsVal {
+        self.binary(&[Token::OR], Js::bitwise_xor)
+    }
+
+    fn bitwise_xor(&mut self) -> JsVal {
+        self.binary(&[Token::XOR], Js::bitwise_and)
+    }
+
+    fn bitwise_and(&mut self) -> JsVal {
+        self.binary(&[Token::AND], Js::equality)
+    }
+
+    fn equality(&mut self) -> JsVal {
+        self.binary(&[Token::EQ, Token::NE], Js::comparison)
+    }
+
+    fn comparison(&mut self) -> JsVal {
+        self.binary(&[Token::LT, Token::LE, Token::GT, Token::GE, Token::IN, Token::INSTANCEOF], Js::shifts)
+    }
+
+    fn shifts(&mut self) -> JsVal {
+        self.binary(&[Token::SHL, Token::SHR, Token::ZSHR], Js::plus_minus)
+    }
+
+    fn plus_minus(&mut self) -> JsVal {
+        self.binary(&[Token::PLUS, Token::MINUS], Js::mul_div_rem)
+    }
+
+    fn mul_div_rem(&mut self) -> JsVal {
+        self.binary(&[Token::MUL, Token::DIV, Token::REM], Js::exponent)
+    }
+
+    // `**` is the only right-associative binary operator
+    fn exponent(&mut self) -> JsVal {
+        let base = self.unary();
+        if is_err(base) || self.next() != Token::EXP { return base }
+        self.consumed = true;
+        let rhs = self.exponent();
+        if is_err(rhs) { return rhs }
+        self.do_op(Token::EXP, base, rhs)
+    }
+
+    fn unary(&mut self) -> JsVal {
+        self.setlwm();
+        let res = self.check_stack();
+        if is_err(res) { return res }
+        let op = match self.next() {
+            Token::NOT | Token::TILDE | Token::TYPEOF | Token::VOID | Token::DELETE => self.tok,
+            Token::MINUS => Token::UMINUS,
+            Token::PLUS => Token::UPLUS,
+            Token::POSTINC => Token::PLUS_ASSIGN,
+            Token::POSTDEC => Token::MINUS_ASSIGN,
+            _ => return self.postfix(),
+        };
+        self.consumed = true;
+
+        let size = self.size;
+        if is_assign(op) || op == Token::DELETE { self.flags |= Flags::LVALUE as u8 }
+        let res = self.unary();
+        if is_err(res) { return res }
+        if op == Token::DELETE {
+            let res = self.delete(res);
+            self.size = size;
+            return res
+        }
+        if is_assign(op) {
+            // Prefix ++ and --
+            if !self.is(Flags::NOEXEC) && v_type(self.resolve(res)) != Type::NUM {
+                self.size = size;
+                return self.mk_err(ErrorKind::Type, "type mismatch")
+            }
+            let res = self.do_op(op, res, tok_val(1.0));
+            self.size = size;
+            return res
+        }
+        self.do_op(op, make_undef(), res)
+    }
+
+    // `delete obj.key` removes the property, `delete arr[i]` leaves a hole.
+    // Variables can't be deleted
+    fn delete(&mut self, v: JsVal) -> JsVal {
+        if self.is(Flags::NOEXEC) { return make_undef() }
+        if v_type(v) != Type::REF { return make_bool(v_type(v) != Type::PROP) }
+        let (obj, key) = (self.load_val(v_data(v) + 8), self.load_val(v_data(v)));
+        match v_type(obj) {
+            Type::ARR => match self.arr_key(key) {
+                array::Key::Index(i) => {
+                    if i < self.arr_len(obj) { self.arr_set(obj, i, HOLE); }
+                    make_bool(true)
+                },
+                array::Key::Length => make_bool(false),
+                _ => make_bool(true),
+            },
+            Type::OBJ | Type::FUNC => make_bool(self.del_prop(obj, key)),
+            _ => make_bool(true),
+        }
+    }
+
+    fn postfix(&mut self) -> JsVal {
+        let size = self.size;
+        let res = self.call_dot();
+        if is_err(res) { return res }
+        match self.next() {
+            Token::POSTINC | Token::POSTDEC => {
+                let op = self.tok;
+                self.consumed = true;
+                let res = self.do_op(op, res, make_undef());
+                self.size = size;
+                res
+            },
+            _ => res,
+        }
+    }
+
+    fn call_dot(&mut self) -> JsVal {
+        let lval = self.is(Flags::LVALUE);
+        self.flags &= !(Flags::LVALUE as u8);
+        let mut res = if self.next() == Token::NEW { self.new_() } else { self.group() };
+        while !is_err(res) {
+            res = match self.next() {
+                Token::LPAREN => self.call(res, make_undef()),
+                Token::DOT | Token::LBRACKET => self.member(res, lval, true),
+                _ => break,
+            };
+        }
+        res
+    }
+
+    // `new F(args)`: F is called with a new object as `this`, whose
+    // prototype is `F.prototype`. The object is the result, unless F
+    // returns an object of its own
+    fn new_(&mut self) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        self.consumed = true;
+        let mut ctor = if self.next() == Token::NEW { self.new_() } else { self.group() };
+        while !is_err(ctor) && matches!(self.next(), Token::DOT | Token::LBRACKET) {
+            ctor = self.member(ctor, false, false);
+        }
+        if is_err(ctor) { return ctor }
+        if !exe {
+            if self.next() != Token::LPAREN { return ctor }
+            return self.call(ctor, make_undef())
+        }
+
+        let ctor = self.resolve(ctor);
+        if v_type(ctor) != Type::FUNC || self.load_off(v_data(ctor) + 4) & ARROW_FN != 0 { return self.mk_err(ErrorKind::Type, "not a constructor") }
+        let proto = self.fn_prototype(ctor);
+        if is_err(proto) { return proto }
+        let obj = self.mk_obj(0);
+        if is_err(obj) { return obj }
+        if v_type(proto) == Type::OBJ { self.save_off(v_data(obj) + 8, v_data(proto) as JsOff) }
+
+        // The object stays on the stack during the call
+        let size = self.size;
+        let res = self.push(obj);
+        if is_err(res) { return res }
+        let res = match self.next() {
+            Token::LPAREN => self.call_args(ctor, obj, true),
+            _ => {
+                let res = self.push(ctor);
+                if is_err(res) { res } else { self.do_call(self.size, 0, true) }
+            },
+        };
+        let obj = self.load_val(size as usize - 8);
+        self.size = size;
+        match v_type(res) {
+            Type::OBJ | Type::ARR | Type::FUNC | Type::ERR => res,
+            _ => obj,
+        }
+    }
+
+    // Member access `obj.name` or `obj[key]`. Followed by arguments, it is
+    // a method call, unless `call` is false as in `new a.B()`. As an
+    // assignment target, the object and the key are left on the stack and
+    // a REF to them is returned, the assignment pops them
+    fn member(&mut self, obj: JsVal, lval: bool, call: bool) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        let obj = self.resolve(obj);
+        let dot = self.tok == Token::DOT;
+        self.consumed = true;
+
+        let mut name = "";
+        let (obj, key) = if dot {
+            let tok = self.next();
+            if tok != Token::IDENTIFIER && !is_keyword(tok) { return self.mk_err(ErrorKind::Syntax, "name expected") }
+            self.consumed = true;
+            name = self.tok_str();
+            (obj, make_undef())
+        } else {
+            // The object stays on the stack while the key is computed
+            let size = self.size;
+            let res = self.push(obj);
+            if is_err(res) { return res }
+            let key = self.expr();
+            let obj = self.load_val(size as usize - 8);
+            self.size = size;
+            if is_err(key) { return key }
+            let res = self.expect(Token::RBRACKET, "] expected");
+            if is_err(res) { return res }
+            (obj, self.resolve(key))
+        };
+        if !exe { return make_undef() }
+
+        let next = self.next();
+        let is_lval = (lval && !matches!(next, Token::DOT | Token::LBRACKET | Token::LPAREN))
+            || is_assign(next) || next == Token::POSTINC || next == Token::POSTDEC;
+        if matches!(v_type(obj), Type::UNDEF | Type::NULL) {
+            let verb = if is_lval { "set" } else { "read" };
+            let what = if v_type(obj) == Type::NULL { "null" } else { "undefined" };
+            return self.mk_err(ErrorKind::Type, format_args!("can't {} members of {}", verb, what))
+        }
+
+        if is_lval {
+            // Objects are keyed by strings, arrays by numbers too
+            let key = match dot {
+                true => self.mk_str(name),
+                false if v_type(obj) != Type::ARR && v_type(key) != Type::STR => self.stringify(key),
+                false => key,
+            };
+            if is_err(key) { return key }
+            let res = self.push(obj);
+            if is_err(res) { return res }
+            let res = self.push(key);
+            if is_err(res) {
+                self.pop();
+                return res
+            }
+            return make_val(Type::REF, self.size as u64)
+        }
+
+        let res = match v_type(obj) {
+            Type::ARR if dot => self.arr_member(obj, array::key(name, builtin::ARRAY_METHODS)),
+            Type::ARR => self.arr_member(obj, self.arr_key(key)),
+            Type::STR if dot => string::member(self, obj, array::key(name, builtin::STRING_METHODS)),
+            Type::STR => {
+                let key = self.member_key(key, builtin::STRING_METHODS);
+                string::member(self, obj, key)
+            },
+            Type::NUM if dot => builtin::lookup(builtin::NUMBER_METHODS, name),
+            Type::BUILTIN if dot => builtin::get(obj, name),
+            Type::NUM | Type::BUILTIN => {
+                let key = if v_type(key) == Type::STR { key } else { self.stringify(key) };
+                if is_err(key) { return key }
+                match v_type(obj) {
+                    Type::NUM => builtin::lookup(builtin::NUMBER_METHODS, self.load_str(key)),
+                    _ => builtin::get(obj, self.load_str(key)),
+                }
+            },
+            Type::FUNC if dot && name == "prototype" => self.fn_prototype(obj),
+            Type::OBJ | Type::FUNC if dot => self.get_prop(obj, name),
+            Type::OBJ | Type::FUNC => {
+                let key = if v_type(key) == Type::STR { key } else { self.stringify(key) };
+                if is_err(key) { return key }
+                if v_type(obj) == Type::FUNC && self.load_str(key) == "prototype" { return self.fn_prototype(obj) }
+                self.get_prop(obj, self.load_str(key))
+            },
+            _ => make_undef(),
+        };
+        if call && next == Token::LPAREN { self.call(res, obj) } else { res }
+    }
+
+    // Parse the arguments, pushing them to the stack after `this` and the
+    // function itself, and make the call
+    fn call(&mut self, func: JsVal, this: JsVal) -> JsVal {
+        self.call_args(func, this, false)
+    }
+
+    // Same, `new` is set for calls that construct an object: `new` itself
+    // and `super()`
+    fn call_args(&mut self, func: JsVal, this: JsVal, new: bool) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        let size = self.size;
+        self.consumed = true;
+
+        if exe {
+            let func = self.resolve(func);
+            let res = self.push(this);
+            if is_err(res) { return res }
+            let res = self.push(func);
+            if is_err(res) {
+                self.size = size;
+                return res
+            }
+        }
+        let mut argc = 0;
+        while self.next() != Token::RPAREN {
+            let arg = self.expr();
+            if is_err(arg) {
+                self.size = size;
+                return arg
+            }
+            if exe {
+                let arg = self.resolve(arg);
+                let res = self.push(arg);
+                if is_err(res) {
+                    self.size = size;
+                    return res
+                }
+                argc += 1;
+            }
+            if self.next() != Token::COMMA { break }
+            self.consumed = true;
+        }
+        let res = self.expect(Token::RPAREN, ") expected");
+        if is_err(res) || !exe {
+            self.size = size;
+            return res
+        }
+
+        let res = self.do_call(size - 16, argc, new);
+        self.size = size;
+        res
+    }
+
+    // Call the function from Rust, e.g. a callback of a built-in method.
+    // GC may run meanwhile, so the caller's own values must be on the stack
+    pub(crate) fn call_fn(&mut self, func: JsVal, this: JsVal, args: &[JsVal]) -> JsVal {
+        let size = self.size;
+        for &v in [this, func].iter().chain(args) {
+            let res = self.push(v);
+            if is_err(res) {
+                self.size = size;
+                return res
+            }
+        }
+        let res = self.do_call(size - 16, args.len(), false);
+        self.size = size;
+        res
+    }
+
+    // Call the function stored on the stack at `slot`, with `this` right
+    // above it and `argc` arguments right below it. Classes need `new`
+    fn do_call(&mut self, slot: JsOff, argc: usize, new: bool) -> JsVal {
+        let func = self.load_val(slot as usize);
+        match v_type(func) {
+            Type::RFUNC => {
+                let Some(off) = self.native(v_data(func)) else { return self.mk_err(ErrorKind::Type, "calling non-function") };
+                let mut ptr = [0u8; TRAMPOLINE_SIZE];
+                ptr.copy_from_slice(&self.mem[off..off + TRAMPOLINE_SIZE]);
+                // Written by `mk_fun` from a trampoline of the same type
+                let tramp = unsafe { core::mem::transmute::<usize, Trampoline<'a>>(usize::from_ne_bytes(ptr)) };
+                tramp(self, (off + TRAMPOLINE_SIZE) as JsOff, slot - 8, argc)
+            },
+            Type::FUNC if !new && self.load_off(v_data(func) + 4) & CLASS_FN != 0 => {
+                self.mk_err(ErrorKind::Type, "Class constructor cannot be invoked without 'new'")
+            },
+            Type::FUNC => self.call_js(slot, argc),
+            Type::BUILTIN => builtin::call(self, func, slot - 8, argc),
+            _ => self.mk_err(ErrorKind::Type, "calling non-function"),
+        }
+    }
+
+    // Run a Js function in a new scope, whose parent is the scope the
+    // function was created in. The caller's parser state is saved here, its
+    // scope and code entity on the stack, where GC can update them
+    fn call_js(&mut self, slot: JsOff, argc: usize) -> JsVal {
+        let res = self.check_stack();
+        if is_err(res) { return res }
+
+        let (code, in_mem, c_len, pos) = (self.code, self.code_in_mem(), self.c_len, self.pos);
+        let (tok, consumed, t_off, t_len, flags) = (self.tok, self.consumed, self.t_off, self.t_len, self.flags);
+        let res = self.push(self.scope);
+        if is_err(res) { return res }
+        let res = self.push(make_val(Type::STR, self.no_gc as u64));
+        if is_err(res) { return res }
+        let res = self.push(self.this);
+        if is_err(res) { return res }
+        let size = self.size;
+
+        let obj = v_data(self.load_val(slot as usize));
+        let scope = self.mk_obj(self.load_off(obj + 4) & !FN_FLAGS);
+        let res = if is_err(scope) { scope } else {
+            // The code is the first property of the function object
+            let code = self.load_val((self.load_off(obj) & !3u32) as usize + 8);
+            self.scope = scope;
+            self.no_gc = v_data(code) as JsOff;
+            self.set_code(v_data(code));
+            self.pos = 0;
+            self.consumed = true;
+            self.flags = Flags::CALL as u8;
+            // Arrow functions keep the `this` they were created with
+            self.this = match self.load_off(obj + 4) & ARROW_FN {
+                0 => self.load_val(slot as usize + 8),
+                _ => self.get_internal(make_val(Type::OBJ, obj as u64), "this"),
+            };
+            self.call_body(slot - 8, argc)
+        };
+
+        self.size = size;
+        self.this = self.pop();
+        self.no_gc = v_data(self.pop()) as JsOff;
+        self.scope = self.pop();
+        if in_mem { self.set_code(self.no_gc as usize) } else { (self.code, self.c_len) = (code, c_len) }
+        (self.pos, self.tok, self.consumed, self.t_off, self.t_len, self.flags) = (pos, tok, consumed, t_off, t_len, flags);
+        // Errors in the function are reported at the call
+        if is_err(res) { self.err_off = self.t_off }
+        res
+    }
+
+    // Bind the arguments to the parameters and run the function body. An
+    // arrow function body may be a single expression
+    fn call_body(&mut self, argv: JsOff, argc: usize) -> JsVal {
+        let res = self.params(argv, argc);
+        if is_err(res) { return res }
+        if self.next() == Token::ARROW {
+            self.consumed = true;
+            if self.next() != Token::LBRACE {
+                let res = self.assignment();
+                return if is_err(res) { res } else { self.resolve(res) }
+            }
+        }
+        if self.next() != Token::LBRACE { return self.mk_err(ErrorKind::Syntax, "{ expected") }
+
+        let res = self.create_block(false);
+        if is_err(res) { return res }
+        if self.is_jump() { return self.label_err() }
+        if !self.is(Flags::RETURN) { return make_undef() }
+        let res = self.ret;
+        self.ret = make_undef();
+        res
+    }
+
+    // Parse the parameters: `(a, b = 1, ...rest)`, or a lone name before an
+    // arrow. When executing, bind them to the arguments in the current
+    // scope. A default is evaluated when its argument is undefined, after
+    // the parameters before it are bound
+    fn params(&mut self, argv: JsOff, argc: usize) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        let parens = self.next() == Token::LPAREN;
+        if parens { self.consumed = true }
+        let mut i = 0;
+        while !parens || self.next() != Token::RPAREN {
+            let rest = parens && self.next() == Token::ELLIPSIS;
+            if rest { self.consumed = true }
+            if self.next() != Token::IDENTIFIER { return self.mk_err(ErrorKind::Syntax, "identifier expected") }
+            self.consumed = true;
+            let (n_off, n_len) = (self.t_off as usize, self.t_len as usize);
+
+            let mut v = make_undef();
+            if parens && !rest && self.next() == Token::ASSIGN {
+                self.consumed = true;
+                let flags = self.flags;
+                if v_type(self.arg(argv, argc, i)) != Type::UNDEF { self.flags |= Flags::NOEXEC as u8 }
+                let res = self.assignment();
+                self.flags = flags;
+                if is_err(res) { return res }
+                v = self.resolve(res);
+            }
+            if exe {
+                if rest {
+                    v = self.mk_arr(argc.saturating_sub(i) as JsOff);
+                    for j in i..argc {
+                        if !is_err(v) { self.arr_set(v, (j - i) as JsOff, self.arg(argv, argc, j)); }
+                    }
+                } else if v_type(self.arg(argv, argc, i)) != Type::UNDEF {
+                    v = self.arg(argv, argc, i);
+                }
+                let res = if is_err(v) { v } else { self.set(self.scope, &self.code[n_off..n_off + n_len], v) };
+                if is_err(res) { return res }
+            }
+            i += 1;
+            if !parens { return make_undef() }
+            if rest && self.next() != Token::RPAREN { return self.mk_err(ErrorKind::Syntax, "rest parameter must be last") }
+            if self.next() != Token::COMMA { break }
+            self.consumed = true;
+        }
+        self.expect(Token::RPAREN, ") expected")
+    }
+
+    // Whether an arrow function starts here: a name or a parenthesized list
+    // followed by `=>`. Scans ahead to the closing paren without parsing
+    fn is_arrow(&mut self) -> bool {
+        match self.next() {
+            Token::IDENTIFIER => return self.look_ahead() == Token::ARROW,
+            Token::LPAREN => (),
+            _ => return false,
+        }
+        let code = self.code.as_bytes();
+        let (mut pos, mut depth) = (self.pos, 1);
+        while depth > 0 {
+            let off = skip_to_next(code, pos);
+            let (tok, len) = scan(&code[off as usize..]);
+            match tok {
+                Token::LPAREN => depth += 1,
+                Token::RPAREN => depth -= 1,
+                Token::EOF | Token::ERR => return false,
+                _ => (),
+            }
+            pos = off + len;
+        }
+        let off = skip_to_next(code, pos);
+        scan(&code[off as usize..]).0 == Token::ARROW
+    }
+
+    // `x => expr` or `(a, b) => { ... }`. The code is kept like that of a
+    // function; `this` is the one of the enclosing code, kept in the
+    // internal `this` property of the function
+    fn arrow(&mut self) -> JsVal {
+        let start = self.t_off as usize;
+        let flags = self.flags;
+        self.flags = (flags & !(Flags::LOOP as u8 | Flags::SWITCH as u8)) | Flags::NOEXEC as u8 | Flags::CALL as u8;
+        let res = self.params(0, 0);
+        let res = if is_err(res) { res } else { self.expect(Token::ARROW, "=> expected") };
+        let res = match res {
+            _ if is_err(res) => res,
+            _ if self.next() == Token::LBRACE => self.create_block(false),
+            _ => self.assignment(),
+        };
+        self.flags = flags;
+        if is_err(res) || self.is(Flags::NOEXEC) { return res }
+
+        // The expression body ends where the next token starts
+        let end = if self.consumed { self.pos } else { self.t_off } as usize;
+        let code = self.mk_str(&self.code[start..end]);
+        if is_err(code) { return code }
+        let f = self.mk_func(v_data(self.scope) as JsOff, make_val(Type::CODEREF, v_data(code) as u64));
+        if is_err(f) { return f }
+        let res = self.set_internal(f, "this", self.this);
+        if is_err(res) { return res }
+        let parent = v_data(f) + 4;
+        self.save_off(parent, self.load_off(parent) | ARROW_FN);
+        f
+    }
+
+    // Whether the code being parsed is a function body in JS memory
+    fn code_in_mem(&self) -> bool {
+        let start = self.mem.as_ptr() as usize;
+        (start..start + self.mem.len()).contains(&(self.code.as_ptr() as usize))
+    }
+
+    // Parse the code stored in the string entity at `off`
+    fn set_code(&mut self, off: usize) {
+        let len = (self.load_off(off) >> 2) - 1;
+        let code = core::str::from_utf8(&self.mem[off + 4..off + 4 + len as usize]).unwrap_or("");
+        // Valid while the entity stays where it is, `gc` sets it again
+        // after moving it
+        self.code = unsafe { &*(code as *const str) };
+        self.c_len = len;
+    }
+
+    // Argument `i` of the native call, undefined if it wasn't passed
+    pub(crate) fn arg(&self, argv: JsOff, argc: usize, i: usize) -> JsVal {
+        if i >= argc { return make_undef() }
+        self.load_val(argv as usize - i * 8)
+    }
+
+    // Argument `i` of the native call as a value rooted in its slot
+    pub(crate) fn arg_val(&self, argv: JsOff, argc: usize, i: usize) -> Value<'id> {
+        if i >= argc { return Js::make_undef() }
+        let slot = argv as usize - i * 8;
+        let v = self.load_val(slot);
+        if !is_entity(v) || v_data(v) == 0 { return Value::from_raw(v) }
+        Value::from_raw(make_val(v_type(v), slot as u64))
+    }
+
+    // `this` of the native call
+    pub(crate) fn this_arg(&self, argv: JsOff) -> JsVal {
+        self.load_val(argv as usize + 16)
+    }
+
+    pub(crate) fn mem_ptr(&self, off: JsOff) -> *const u8 {
+        self.mem[off as usize..].as_ptr()
+    }
+
+    fn group(&mut self) -> JsVal {
+        if self.next() != Token::LPAREN { return self.literal() }
+        self.consumed = true;
+        let res = self.expr();
+        if is_err(res) { return res }
+        match self.expect(Token::RPAREN, ") expected") {
+            err if is_err(err) => err,
+            _ => res,
+        }
+    }
+
+    fn literal(&mut self) -> JsVal {
+        let tok = self.next();
+        self.consumed = true;
+        match tok {
+            Token::NUMBER => self.t_val,
+            Token::STRING if self.is(Flags::NOEXEC) => make_undef(),
+            Token::STRING => self.str_literal(),
+            Token::TEMPLATE => self.template(),
+            Token::TRUE => make_bool(true),
+            Token::FALSE => make_bool(false),
+            Token::NULL => make_null(),
+            Token::UNDEF => make_undef(),
+            Token::IDENTIFIER => self.lookup(self.tok_str()),
+            Token::THIS => self.this,
+            Token::FUNC => self.func_literal(),
+            Token::CLASS => self.class_literal(),
+            Token::SUPER => self.super_(),
+            Token::LBRACKET => self.array_literal(),
+            Token::LBRACE => self.object_literal(),
+            _ => self.mk_err(ErrorKind::Syntax, "bad expr"),
+        }
+    }
+
+    // `super(args)` runs the base class constructor on `this`. `super.name`
+    // reads the member of the base through the prototype of the object the
+    // method lives on, and calls it with the current `this`
+    fn super_(&mut self) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        if self.next() == Token::LPAREN {
+            let base = match self.lookup_internal("super") {
+                0 if exe => return self.mk_err(ErrorKind::Syntax, "'super' outside of a derived class"),
+                0 => make_undef(),
+                off => self.load_val(off as usize + 8),
+            };
+            let res = self.call_args(base, self.this, true);
+            return if is_err(res) { res } else { make_undef() }
+        }
+
+        let dot = self.next() == Token::DOT;
+        if !dot && self.tok != Token::LBRACKET { return self.mk_err(ErrorKind::Syntax, "'super' needs a call or a member") }
+        self.consumed = true;
+        let key = if dot {
+            let tok = self.next();
+            if tok != Token::IDENTIFIER && !is_keyword(tok) { return self.mk_err(ErrorKind::Syntax, "name expected") }
+            self.consumed = true;
+            if exe { self.mk_str(self.tok_str()) } else { make_undef() }
+        } else {
+            let key = self.expr();
+            if is_err(key) { return key }
+            let res = self.expect(Token::RBRACKET, "] expected");
+            if is_err(res) { return res }
+            let key = self.resolve(key);
+            if !exe || v_type(key) == Type::STR { key } else { self.stringify(key) }
+        };
+        if is_err(key) || !exe { return key }
+        if is_assign(self.next()) { return self.mk_err(ErrorKind::Syntax, "can't assign to super members") }
+
+        let home = match self.lookup_internal("home") {
+            0 => return self.mk_err(ErrorKind::Syntax, "'super' outside of a derived class"),
+            off => self.load_val(off as usize + 8),
+        };
+        let res = match self.proto_of(v_data(home)) {
+            0 => make_undef(),
+            proto => self.get_prop(make_val(Type::OBJ, proto as u64), self.load_str(key)),
+        };
+        if self.next() == Token::LPAREN { self.call(res, self.this) } else { res }
+    }
+
+    // String from the STRING token just parsed, with its escapes decoded
+    fn str_literal(&mut self) -> JsVal {
+        let (start, end) = ((self.t_off + 1) as usize, (self.t_off + self.t_len - 1) as usize);
+        let s = &self.code[start..end];
+        if !s.contains('\\') { return self.mk_str(s) }
+        let mut pos = self.brk as usize + 4;
+        let res = self.put_cooked(&mut pos, start, end);
+        if is_err(res) { return res }
+        self.take_str(pos)
+    }
+
+    // Template literal. The values of the `${}` expressions are pushed to
+    // the stack as strings, then the text and the values are written out
+    // into the new string
+    fn template(&mut self) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        let (start, end) = ((self.t_off + 1) as usize, (self.t_off + self.t_len - 1) as usize);
+        let size = self.size;
+        let mut pos = start;
+        while let Some(at) = self.subst_at(pos, end) {
+            self.pos = (at + 2) as JsOff;
+            self.consumed = true;
+            let v = self.expr();
+            let res = if is_err(v) { v } else { self.expect(Token::RBRACE, "} expected") };
+            let v = if is_err(res) || !exe { res } else {
+                let v = self.resolve(v);
+                let v = self.stringify(v);
+                if is_err(v) { v } else { self.push(v) }
+            };
+            if is_err(v) {
+                self.size = size;
+                return v
+            }
+            pos = self.pos as usize;
+        }
+        self.pos = end as JsOff + 1;
+        self.consumed = true;
+        if !exe { return make_undef() }
+
+        let (mut out, mut slot) = (self.brk as usize + 4, size as usize);
+        let mut pos = start;
+        let res = loop {
+            let at = self.subst_at(pos, end);
+            let res = self.put_cooked(&mut out, pos, at.unwrap_or(end));
+            if is_err(res) { break res }
+            let Some(at) = at else { break self.take_str(out) };
+            slot -= 8;
+            if !self.put_mem(&mut out, self.v_str(self.load_val(slot))) { break self.mk_err(ErrorKind::Oom, "oom") }
+            pos = at + 2 + subst_len(&self.code.as_bytes()[at + 2..]).unwrap_or(0);
+        };
+        self.size = size;
+        res
+    }
+
+    // Offset of the next `${` in the template text between `pos` and `end`
+    fn subst_at(&self, mut pos: usize, end: usize) -> Option<usize> {
+        let code = self.code.as_bytes();
+        while pos < end {
+            match code[pos] {
+                b'\\' => pos += 2,
+                b'$' if code[pos + 1] == b'{' => return Some(pos),
+                _ => pos += 1,
+            }
+        }
+        None
+    }
+
+    // `{a: 1, 'b': 2, 3: c}`: the object and the current key stay on the
+    // stack while the value is computed
+    fn object_literal(&mut self) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        let size = self.size;
+        let obj = if exe { self.mk_obj(0) } else { make_undef() };
+        let res = if is_err(obj) { obj } else { self.push(obj) };
+        if is_err(res) { return res }
+        let slot = self.size as usize;
+
+        while self.next() != Token::RBRACE {
+            let tok = self.tok;
+            self.consumed = true;
+            let key = match tok {
+                _ if !exe => make_undef(),
+                Token::STRING => self.str_literal(),
+                Token::NUMBER => self.stringify(self.t_val),
+                Token::IDENTIFIER => self.mk_str(self.tok_str()),
+                _ if is_keyword(tok) => self.mk_str(self.tok_str()),
+                _ => self.mk_err(ErrorKind::Syntax, "bad property name"),
+            };
+            let res = if is_err(key) { key } else { self.push(key) };
+            let res = if is_err(res) { res } else { self.expect(Token::COLON, ": expected") };
+            let v = if is_err(res) { res } else { self.assignment() };
+            let res = if is_err(v) || !exe { v } else {
+                let (v, obj, key) = (self.resolve(v), self.load_val(slot), self.load_val(slot - 8));
+                match self.lkp(obj, self.load_str(key)) {
+                    0 => self.set_prop(obj, key, v),
+                    off => {
+                        self.save_val(off as usize + 8, v);
+                        v
+                    },
+                }
+            };
+            self.size = slot as JsOff;
+            if is_err(res) {
+                self.size = size;
+                return res
+            }
+            if self.next() != Token::COMMA { break }
+            self.consumed = true;
+        }
+        let res = self.expect(Token::RBRACE, "} expected");
+        let obj = self.load_val(slot);
+        self.size = size;
+        if is_err(res) { res } else { obj }
+    }
+
+    // `[a, , b]`: the elements are pushed to the stack as they are parsed,
+    // then copied into the new array. Elisions make holes
+    fn array_literal(&mut self) -> JsVal {
+        let exe = !self.is(Flags::NOEXEC);
+        let size = self.size;
+        let mut n = 0;
+        while self.next() != Token::RBRACKET {
+            let v = if self.tok == Token::COMMA { HOLE } else { self.assignment() };
+            let res = if !is_err(v) && exe { self.push(self.resolve(v)) } else { v };
+            if is_err(res) {
+                self.size = size;
+                return res
+            }
+            n += 1;
+            if self.next() != Token::COMMA { break }
+            self.consumed = true;
+        }
+        let res = self.expect(Token::RBRACKET, "] expected");
+        if is_err(res) || !exe {
+            self.size = size;
+            return res
+        }
+
+        let arr = self.mk_arr(n);
+        if !is_err(arr) {
+            // The stack grows down, the first element is at the top
+            let data = (self.load_off(v_data(arr)) & !3u32) as usize;
+            for i in 0..n as usize {
+                let v = self.load_val(size as usize - 8 * (i + 1));
+                self.save_val(data + 4 + 8 * i, v);
+            }
+        }
+        self.size = size;
+        arr
+    }
+
+    fn do_op(&mut self, op: Token, lhs: JsVal, rhs: JsVal) -> JsVal {
+        if self.is(Flags::NOEXEC) { return make_undef() }
+
+        let l = self.resolve(lhs);
+        let r = self.resolve(rhs);
+        let (lt, rt) = (v_type(l), v_type(r));
+
+        if (is_assign(op) || op == Token::POSTINC || op == Token::POSTDEC) && !matches!(v_type(lhs), Type::PROP | Type::REF) {
+            return self.mk_err(ErrorKind::Syntax, "bad lhs")
+        }
+
+        match op {
+            Token::TYPEOF => self.mk_str(type_name(r)),
+            Token::VOID => make_undef(),
+            Token::NOT => make_bool(!self.truthy(r)),
+            Token::EQ => make_bool(self.strict_eq(l, r)),
+            Token::NE => make_bool(!self.strict_eq(l, r)),
+            Token::ASSIGN => self.assign(lhs, r),
+            Token::POSTINC | Token::POSTDEC => {
+                if lt != Type::NUM { return self.mk_err(ErrorKind::Type, "type mismatch") }
+                let d = if op == Token::POSTINC { 1.0 } else { -1.0 };
+                let res = self.assign(lhs, tok_val(v_num(l) + d));
+                if is_err(res) { return res }
+                l
+            },
+            _ if is_assign(op) => {
+                let base = match op {
+                    Token::PLUS_ASSIGN => Token::PLUS,
+                    Token::MINUS_ASSIGN => Token::MINUS,
+                    Token::MUL_ASSIGN => Token::MUL,
+                    Token::DIV_ASSIGN => Token::DIV,
+                    Token::REM_ASSIGN => Token::REM,
+                    Token::SHL_ASSIGN => Token::SHL,
+                    Token::SHR_ASSIGN => Token::SHR,
+                    Token::ZSHR_ASSIGN => Token::ZSHR,
+                    Token::AND_ASSIGN => Token::AND,
+                    Token::XOR_ASSIGN => Token::XOR,
+                    _ => Token::OR,
+                };
+                let res = self.do_op(base, l, r);
+                if is_err(res) { return res }
+                self.assign(lhs, res)
+            },
+            Token::PLUS if lt == Type::STR || rt == Type::STR => self.concat(l, r),
+            Token::IN => match rt {
+                Type::ARR => make_bool(match self.arr_key(l) {
+                    array::Key::Index(i) => self.arr_get(r, i) != HOLE,
+                    array::Key::None => false,
+                    _ => true,
+                }),
+                Type::OBJ | Type::FUNC => {
+                    let key = if lt == Type::STR { l } else { self.stringify(l) };
+                    if is_err(key) { return key }
+                    make_bool(self.find_prop(r, self.load_str(key)) != 0)
+                },
+                _ => self.mk_err(ErrorKind::Type, format_args!("'in' needs an object, got {}", type_name(r))),
+            },
+            Token::INSTANCEOF => {
+                if rt != Type::FUNC { return self.mk_err(ErrorKind::Type, format_args!("'instanceof' needs a constructor, got {}", type_name(r))) }
+                let proto = self.get_prop(r, "prototype");
+                if v_type(proto) != Type::OBJ || !matches!(lt, Type::OBJ | Type::FUNC) { return make_bool(false) }
+                let mut off = self.proto_of(v_data(l));
+                while off != 0 && off as usize != v_data(proto) {
+                    off = self.proto_of(off as usize);
+                }
+                make_bool(off != 0)
+            },
+            Token::LT | Token::LE | Token::GT | Token::GE if lt == Type::STR && rt == Type::STR => {
+                let ord = self.load_str(l).cmp(self.load_str(r));
+                let res = match op {
+                    Token::LT => ord.is_lt(),
+                    Token::LE => ord.is_le(),
+                    Token::GT => ord.is_gt(),
+                    _ => ord.is_ge(),
+                };
+                make_bool(res)
+            },
+            Token::UMINUS | Token::UPLUS | Token::TILDE => {
+                if rt != Type::NUM { return self.mk_err(ErrorKind::Type, "type mismatch") }
+                do_num_op(op, 0.0, v_num(r))
+            },
+            _ => {
+                if lt != Type::NUM || rt != Type::NUM { return self.mk_err(ErrorKind::Type, "type mismatch") }
+                do_num_op(op, v_num(l), v_num(r))
+            },
+        }
+    }
+
+    fn assign(&mut self, lhs: JsVal, val: JsVal) -> JsVal {
+        let lhs = if v_type(lhs) != Type::REF { lhs } else {
+            let (obj, key) = (self.load_val(v_data(lhs) + 8), self.load_val(v_data(lhs)));
+            match v_type(obj) {
+                Type::ARR => return self.arr_assign(obj, key, val),
+                Type::OBJ | Type::FUNC => {
+                    let off = self.lkp(obj, self.load_str(key));
+                    if off == 0 {
+                        // The key string is already in memory, reuse it
+                        let prop = self.set_prop(obj, key, val);
+                        return if is_err(prop) { prop } else { val }
+                    }
+                    make_val(Type::PROP, off as u64)
+                },
+                _ => return self.mk_err(ErrorKind::Type, format_args!("can't set members of {}", type_name(obj))),
+            }
+        };
+        if self.load_off(v_data(lhs) + 4) & CONST_PROP != 0 {
+            let name = self.load_str(make_val(Type::STR, self.prop_key(v_data(lhs)) as u64));
+            // The name lives in `mem`, copy it out before recording the error
+            let mut buf = [0u8; JS_ERR_MAX];
+            let mut out = Buf::new(&mut buf);
+            let _ = write!(out, "assignment to constant '{}'", name);
+            return self.mk_err(ErrorKind::Type, out.as_str())
+        }
+        self.save_val(v_data(lhs) + 8, val);
+        val
+    }
+
+    // String concatenation, the non-string operand gets stringified first
+    fn concat(&mut self, l: JsVal, r: JsVal) -> JsVal {
+        let l = self.stringify(l);
+        if is_err(l) { return l }
+        let r = self.stringify(r);
+        if is_err(r) { return r }
+
+        let ((l_off, l_len), (r_off, r_len)) = (self.v_str(l), self.v_str(r));
+        let n = l_len + r_len + 1;
+        let res = self.make_entity((n << 2) | Type::STR as JsOff, &[]);
+        if is_err(res) { return res }
+
+        let off = v_data(res) + 4;
+        self.mem.copy_within(l_off as usize..(l_off + l_len) as usize, off);
+        self.mem.copy_within(r_off as usize..(r_off + r_len) as usize, off + l_len as usize);
+        self.mem[off + (l_len + r_len) as usize] = 0;
+        res
+    }
+
+    // Convert a value to a Js string
+    pub(crate) fn stringify(&mut self, v: JsVal) -> JsVal {
+        match v_type(v) {
+            Type::STR => v,
+            Type::NUM => {
+                let mut buf = [0u8; 32];
+                let mut out = Buf::new(&mut buf);
+                let _ = fmt_num(v_num(v), &mut out);
+                let len = out.len();
+                self.mk_str(core::str::from_utf8(&buf[..len]).unwrap_or(""))
+            },
+            Type::BOOL => self.mk_str(if v_data(v) != 0 { "true" } else { "false" }),
+            Type::NULL => self.mk_str("null"),
+            Type::UNDEF => self.mk_str("undefined"),
+            Type::ARR => self.join(v, None),
+            _ => self.mk_str("[object Object]"),
+        }
+    }
+
+    // Next number of `Math.random`, in [0, 1), from a xorshift64* generator
+    pub(crate) fn random(&mut self) -> f64 {
+        self.rand ^= self.rand >> 12;
+        self.rand ^= self.rand << 25;
+        self.rand ^= self.rand >> 27;
+        (self.rand.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Convert a value to a number, arrays through their string
+    pub(crate) fn number_of(&mut self, v: JsVal) -> f64 {
+        match v_type(v) {
+            Type::NUM => v_num(v),
+            Type::BOOL => v_data(v) as f64,
+            Type::NULL => 0.0,
+            Type::STR => str_to_double(self.load_str(v)),
+            Type::ARR => match self.join(v, None) {
+                s if is_err(s) => f64::NAN,
+                s => str_to_double(self.load_str(s)),
+            },
+            _ => f64::NAN,
+        }
+    }
+
+    pub(crate) fn strict_eq(&self, l: JsVal, r: JsVal) -> bool {
+        match (v_type(l), v_type(r)) {
+            (Type::NUM, Type::NUM) => v_num(l) == v_num(r),
+            (Type::STR, Type::STR) => self.load_str(l) == self.load_str(r),
+            _ => l == r,
+        }
+    }
+
+    pub(crate) fn truthy(&self, v: JsVal) -> bool {
+        match v_type(v) {
+            Type::BOOL => v_data(v) != 0,
+            Type::NUM => v_num(v) != 0.0 && !v_num(v).is_nan(),
+            Type::STR => self.v_str(v).1 > 0,
+            Type::OBJ | Type::FUNC | Type::RFUNC | Type::ARR | Type::BUILTIN => true,
+            _ => false,
+        }
+    }
+
+    // Load the value of a property or member reference
+    fn resolve(&self, v: JsVal) -> JsVal {
+        match v_type(v) {
+            Type::PROP => self.load_val(v_data(v) + 8),
+            Type::REF => {
+                let (obj, key) = (self.load_val(v_data(v) + 8), self.load_val(v_data(v)));
+                match v_type(obj) {
+                    Type::ARR => self.arr_member(obj, self.arr_key(key)),
+                    Type::OBJ | Type::FUNC => self.get_prop(obj, self.load_str(key)),
+                    _ => make_undef(),
+                }
+            },
+            _ => v,
+        }
+    }
+
+    // Offset and length of the string data
+    pub(crate) fn v_str(&self, v: JsVal) -> (JsOff, JsOff) {
+        let off = v_data(v);
+        (off as JsOff + 4, (self.load_off(off) >> 2) - 1)
+    }
+
+    // Contents of a string value
+    pub(crate) fn load_str(&self, v: JsVal) -> &str {
+        mem_str(self.mem, v_data(v))
+    }
+
+    // Stringify a value into any writer, see `Js::str`
+    pub(crate) fn fmt_val(&self, v: JsVal, out: &mut impl Write) -> core::fmt::Result {
+        to_str(&self.mem[..self.brk as usize], self.err_str(), v, out, 0)
+    }
+
+    fn alloc(&mut self, size: JsOff) -> JsOff {
+        let size = (size + 3) & !3u32;
+        if self.brk + size > self.size { return !0u32 }
+        let off = self.brk;
+        self.brk += size;
+        self.setlwm();
+        off
+    }
+
+    // Push the value to the stack at the top of memory, see `core.rs`
+    pub(crate) fn push(&mut self, v: JsVal) -> JsVal {
+        if self.brk + 8 > self.size { return self.mk_err(ErrorKind::Oom, "oom") }
+        self.size -= 8;
+        self.save_val(self.size as usize, v);
+        self.setlwm();
+        v
+    }
+
+    // Update the free memory and the native stack watermarks
+    fn setlwm(&mut self) {
+        self.lwm = self.lwm.min(self.size - self.brk);
+        let marker = 0u8;
+        let sp = &marker as *const u8 as usize;
+        self.rss = self.rss.max(self.stk.saturating_sub(sp) as JsOff);
+    }
+
+    // Offset of the last pushed value
+    pub(crate) fn sp(&self) -> usize {
+        self.size as usize
+    }
+
+    // The stack grows down from here
+    fn stack_top(&self) -> usize {
+        self.mem.len() & !3usize
+    }
+
+    pub(crate) fn pop(&mut self) -> JsVal {
+        let v = self.load_val(self.size as usize);
+        self.size += 8;
+        v
+    }
+
+    fn make_entity(&mut self, b: JsOff, buf: &[u8]) -> JsVal {
+        let len = if b & 3 == Type::STR as JsOff { b >> 2 } else { buf.len() as JsOff };
+        let off = self.alloc(len + 4);
+        if off == !0u32 { return self.mk_err(ErrorKind::Oom, "oom") }
+
+        self.save_off(off as usize, b);
+        let start = off as usize + 4;
+        let src = (buf.as_ptr() as usize).wrapping_sub(self.mem.as_ptr() as usize);
+        if src < self.mem.len() {
+            // Copying from JS memory itself, e.g. a native function result
+            self.mem.copy_within(src..src + buf.len(), start);
+        } else {
+            self.mem[start..start + buf.len()].copy_from_slice(buf);
+        }
+        make_val(entity_type(b), off as u64)
+    }
+
+    pub(crate) fn mk_obj(&mut self, parent: JsOff) -> JsVal {
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&parent.to_le_bytes());
+        self.make_entity(Type::OBJ as JsOff, &buf)
+    }
+
+    pub(crate) fn mk_str(&mut self, string: &str) -> JsVal {
+        let n = string.len() as JsOff + 1;
+        let res = self.make_entity((n << 2) | Type::STR as JsOff, string.as_bytes());
+        if !is_err(res) {
+            self.mem[v_data(res) + 4 + string.len()] = 0;
+        }
+        res
+    }
+
+    // Record the error at the current token. Long messages are truncated
+    // to fit the error buffer
+    pub(crate) fn mk_err(&mut self, kind: ErrorKind, msg: impl fmt::Display) -> JsVal {
+        let mut buf = Buf::new(&mut self.err_msg);
+        let _ = write!(buf, "{}", msg);
+        self.err_len = buf.len() as u8;
+        self.err_kind = kind;
+        self.err_off = self.t_off;
+        self.thrown = make_val(Type::ERR, 0);
+        make_val(Type::ERR, 0)
+    }
+
+    fn err_str(&self) -> &str {
+        core::str::from_utf8(&self.err_msg[..self.err_len as usize]).unwrap_or("")
+    }
+
+    // Store the trampoline and the closure in a string entity, and its
+    // offset in the native table, a string entity too. The RFUNC value
+    // holds the index in the table, which scripts can't make up or change
+    fn mk_fun<Args, F: NativeFn<'a, Args>>(&mut self, f: F) -> JsVal {
+        let len = TRAMPOLINE_SIZE + core::mem::size_of::<F>();
+        let blob = self.make_entity(((len as JsOff + 1) << 2) | Type::STR as JsOff, &[]);
+        if is_err(blob) { return blob }
+        let n = self.native_count();
+        let table = self.make_entity(((((n + 1) * 4) as JsOff + 1) << 2) | Type::STR as JsOff, &[]);
+        if is_err(table) { return table }
+
+        let off = v_data(blob) + 4;
+        let tramp: Trampoline<'a> = trampoline::<Args, F>;
+        self.mem[off..off + TRAMPOLINE_SIZE].copy_from_slice(&(tramp as usize).to_ne_bytes());
+        let data = &mut self.mem[off + TRAMPOLINE_SIZE..off + len];
+        unsafe { core::ptr::write_unaligned(data.as_mut_ptr() as *mut F, f) };
+        self.mem[off + len] = 0;
+
+        let start = v_data(table) + 4;
+        if n > 0 {
+            let old = self.natives as usize + 4;
+            self.mem.copy_within(old..old + n * 4, start);
+        }
+        self.save_off(start + n * 4, v_data(blob) as JsOff);
+        self.mem[start + (n + 1) * 4] = 0;
+        self.natives = v_data(table) as JsOff;
+        make_val(Type::RFUNC, n as u64)
+    }
+
+    // Number of native functions
+    fn native_count(&self) -> usize {
+        if self.natives == 0 { return 0 }
+        // GC may have marked the table
+        ((((self.load_off(self.natives as usize) & !GC_MARK) >> 2) - 1) / 4) as usize
+    }
+
+    // Offset of the data of native function `i`, None if there is none
+    fn native(&self, i: usize) -> Option<usize> {
+        if i >= self.native_count() { return None }
+        Some(self.load_off(self.natives as usize + 4 + i * 4) as usize + 4)
+    }
+
+    // Append a new property to the object's property list
+    fn set_prop(&mut self, obj: JsVal, k: JsVal, v: JsVal) -> JsVal {
+        let mut buf = [0u8; 12];
+        buf[..4].copy_from_slice(&(v_data(k) as JsOff).to_le_bytes());
+        buf[4..].copy_from_slice(&v.to_le_bytes());
+
+        let prop = self.make_entity(Type::PROP as JsOff, &buf);
+        if is_err(prop) { return prop }
+
+        let mut tail = v_data(obj);
+        while self.load_off(tail) & !3u32 != 0 {
+            tail = (self.load_off(tail) & !3u32) as usize;
+        }
+        let head = self.load_off(tail) & 3u32;
+        self.save_off(tail, v_data(prop) as JsOff | head);
+        prop
+    }
+
+    // Update the property, or create it if it doesn't exist
+    fn set(&mut self, obj: JsVal, name: &str, v: JsVal) -> JsVal {
+        let off = self.lkp(obj, name);
+        if off != 0 {
+            self.save_val(off as usize + 8, v);
+            return v
+        }
+        let k = self.mk_str(name);
+        if is_err(k) { return k }
+        let prop = self.set_prop(obj, k, v);
+        if is_err(prop) { return prop }
+        v
+    }
+
+    // Same, with the name as a string value
+    pub(crate) fn set_key(&mut self, obj: JsVal, k: JsVal, v: JsVal) -> JsVal {
+        let off = self.lkp(obj, self.load_str(k));
+        if off != 0 {
+            self.save_val(off as usize + 8, v);
+            return v
+        }
+        let prop = self.set_prop(obj, k, v);
+        if is_err(prop) { return prop }
+        v
+    }
+
+    // Offset of the property's key string
+    fn prop_key(&self, off: usize) -> JsOff {
+        self.load_off(off + 4) & !PROP_FLAGS
+    }
+
+    // Next property, key string and value of the property at `off`
+    pub(crate) fn prop_at(&self, off: usize) -> (usize, JsOff, JsVal) {
+        (self.next_prop(off as JsOff) as usize, self.prop_key(off), self.load_val(off + 8))
+    }
+
+    // First property from `off` on that `for..in` visits, 0 if none
+    fn visible_prop(&self, mut off: JsOff) -> JsOff {
+        while off != 0 && self.load_off(off as usize + 4) & HIDDEN_PROP != 0 {
+            off = self.next_prop(off);
+        }
+        off
+    }
+
+    // Make the property named `name` of the object non-enumerable
+    fn hide_prop(&mut self, obj: JsVal, name: &str) {
+        let off = self.lkp(obj, name) as usize;
+        if off != 0 { self.save_off(off + 4, self.load_off(off + 4) | HIDDEN_PROP) }
+    }
+
+    // Offset of the object's first property, 0 if it has none. Internal
+    // properties are skipped here and by `next_prop`
+    pub(crate) fn first_prop(&self, obj: JsVal) -> JsOff {
+        self.public_prop(self.load_off(v_data(obj)) & !3u32)
+    }
+
+    // Offset of the property after this one, 0 if it is the last
+    pub(crate) fn next_prop(&self, prop: JsOff) -> JsOff {
+        self.public_prop(self.load_off(prop as usize) & !3u32)
+    }
+
+    // First property from `off` on that isn't internal, 0 if none
+    fn public_prop(&self, mut off: JsOff) -> JsOff {
+        while off != 0 && self.load_off(off as usize + 4) & INTERNAL_PROP != 0 {
+            off = self.load_off(off as usize) & !3u32;
+        }
+        off
+    }
+
+    // Name of the property, a string
+    pub(crate) fn prop_name(&self, prop: JsOff) -> JsVal {
+        make_val(Type::STR, self.prop_key(prop as usize) as u64)
+    }
+
+    // Find the property of the object by name, 0 if it doesn't exist
+    fn lkp(&self, obj: JsVal, buf: &str) -> JsOff {
+        self.lkp_flags(obj, buf, 0)
+    }
+
+    // Same for the internal properties the engine keeps its own state in.
+    // Scripts can't reach them whatever their name
+    fn lkp_internal(&self, obj: JsVal, buf: &str) -> JsOff {
+        self.lkp_flags(obj, buf, INTERNAL_PROP)
+    }
+
+    fn lkp_flags(&self, obj: JsVal, buf: &str, internal: JsOff) -> JsOff {
+        let mut off: JsOff = self.load_off(v_data(obj)) & !3u32;
+        while off != 0 {
+            let key = self.load_off(off as usize + 4);
+            if key & INTERNAL_PROP == internal && self.load_str(make_val(Type::STR, (key & !PROP_FLAGS) as u64)) == buf {
+                return off
+            }
+            off = self.load_off(off as usize) & !3u32;
+        }
+        0
+    }
+
+    // Value of the internal property, undefined if the object has none
+    fn get_internal(&self, obj: JsVal, name: &str) -> JsVal {
+        match self.lkp_internal(obj, name) {
+            0 => make_undef(),
+            off => self.load_val(off as usize + 8),
+        }
+    }
+
+    // Update the internal property, or create it if it doesn't exist
+    fn set_internal(&mut self, obj: JsVal, name: &str, v: JsVal) -> JsVal {
+        let off = self.lkp_internal(obj, name);
+        if off != 0 {
+            self.save_val(off as usize + 8, v);
+            return v
+        }
+        let k = self.mk_str(name);
+        if is_err(k) { return k }
+        let prop = self.set_prop(obj, k, v);
+        if is_err(prop) { return prop }
+        let key = v_data(prop) + 4;
+        self.save_off(key, self.load_off(key) | INTERNAL_PROP | HIDDEN_PROP);
+        v
+    }
+
+    // Offset of the object's prototype, 0 if it has none
+    fn proto_of(&self, obj: usize) -> JsOff {
+        self.load_off(obj + 8)
+    }
+
+    // Find the property of the object or of its prototype chain
+    fn find_prop(&self, obj: JsVal, name: &str) -> JsOff {
+        let mut obj = v_data(obj);
+        loop {
+            let off = self.lkp(make_val(Type::OBJ, obj as u64), name);
+            if off != 0 { return off }
+            obj = self.proto_of(obj) as usize;
+            if obj == 0 { return 0 }
+        }
+    }
+
+    // Value of the property, undefined if neither the object nor its
+    // prototypes have it
+    pub(crate) fn get_prop(&self, obj: JsVal, name: &str) -> JsVal {
+        match self.find_prop(obj, name) {
+            0 => make_undef(),
+            off => self.load_val(off as usize + 8),
+        }
+    }
+
+    // `F.prototype`, made on first use, with a `constructor` property
+    // pointing back to F
+    fn fn_prototype(&mut self, func: JsVal) -> JsVal {
+        let off = self.lkp(func, "prototype");
+        if off != 0 { return self.load_val(off as usize + 8) }
+        let proto = self.mk_obj(0);
+        if is_err(proto) { return proto }
+        let res = self.set(proto, "constructor", func);
+        if is_err(res) { return res }
+        let res = self.set(func, "prototype", proto);
+        if is_err(res) { return res }
+        proto
+    }
+
+    // Unlink the property named by the string `key` from the object's
+    // property list. Returns false for constants, which can't be deleted
+    fn del_prop(&mut self, obj: JsVal, key: JsVal) -> bool {
+        let mut prev = v_data(obj);
+        loop {
+            let off = (self.load_off(prev) & !3u32) as usize;
+            if off == 0 { return true }
+            let internal = self.load_off(off + 4) & INTERNAL_PROP != 0;
+            if !internal && self.load_str(make_val(Type::STR, self.prop_key(off) as u64)) == self.load_str(key) {
+                if self.load_off(off + 4) & CONST_PROP != 0 { return false }
+                let next = self.load_off(off) & !3u32;
+                self.save_off(prev, next | (self.load_off(prev) & 3u32));
+                return true
+            }
+            prev = off;
+        }
+    }
+
+    // Resolve the variable through the scope chain
+    fn lookup(&mut self, buf: &str) -> JsVal {
+        if self.is(Flags::NOEXEC) { return make_undef() }
+
+        let mut scope = self.scope;
+        loop {
+            let off = self.lkp(scope, buf);
+            if off != 0 { return make_val(Type::PROP, off as u64) }
+            if v_data(scope) == 0 { break }
+            scope = self.upper(scope);
+        }
+        if let Some(v) = builtin::global(buf) { return v }
+        self.mk_err(ErrorKind::Reference, format_args!("'{}' not found", buf))
+    }
+
+    // Find the internal property through the scope chain, 0 if no scope
+    // has it
+    fn lookup_internal(&self, name: &str) -> JsOff {
+        let mut scope = self.scope;
+        loop {
+            let off = self.lkp_internal(scope, name);
+            if off != 0 || v_data(scope) == 0 { return off }
+            scope = self.upper(scope);
+        }
+    }
+}
+
+// Arrays
+impl<'a, 'id> Js<'a, 'id> {
+    // New array of `len` holes
+    pub(crate) fn mk_arr(&mut self, len: JsOff) -> JsVal {
+        let arr = self.make_entity(ARR_TAG, &0u32.to_le_bytes());
+        if is_err(arr) { return arr }
+        let res = self.set_len(arr, len);
+        if is_err(res) { return res }
+        arr
+    }
+
+    // Offset of the element storage, 0 if there is none
+    fn arr_data(&self, arr: JsVal) -> usize {
+        (self.load_off(v_data(arr)) & !3u32) as usize
+    }
+
+    pub(crate) fn arr_len(&self, arr: JsVal) -> JsOff {
+        self.load_off(v_data(arr) + 4)
+    }
+
+    // Number of elements the storage can hold
+    fn arr_cap(&self, arr: JsVal) -> JsOff {
+        let data = self.arr_data(arr);
+        if data == 0 { 0 } else { ((self.load_off(data) >> 2) - 1) / 8 }
+    }
+
+    // Element `i`, HOLE if it is missing
+    pub(crate) fn arr_get(&self, arr: JsVal, i: JsOff) -> JsVal {
+        if i >= self.arr_len(arr) { return HOLE }
+        self.load_val(self.arr_data(arr) + 4 + i as usize * 8)
+    }
+
+    // Make room for `cap` elements, moving them to a bigger storage
+    fn arr_reserve(&mut self, arr: JsVal, cap: JsOff) -> JsVal {
+        let old = self.arr_cap(arr);
+        if cap <= old { return make_undef() }
+        let cap = cap.max(old.saturating_mul(2)).max(4) as usize;
+        // The length of a string entity is limited by the GC mark bit
+        if cap * 8 + 1 >= (GC_MARK >> 2) as usize { return self.mk_err(ErrorKind::Oom, "oom") }
+
+        let data = self.make_entity((((cap * 8 + 1) as JsOff) << 2) | Type::STR as JsOff, &[]);
+        if is_err(data) { return data }
+        let (old, len) = (self.arr_data(arr), self.arr_len(arr) as usize);
+        let new = v_data(data);
+        if old != 0 { self.mem.copy_within(old + 4..old + 4 + len * 8, new + 4) }
+        self.mem[new + 4 + cap * 8] = 0;
+        self.save_off(v_data(arr), new as JsOff | ARR_TAG);
+        make_undef()
+    }
+
+    // Truncate the array, or extend it with holes
+    pub(crate) fn set_len(&mut self, arr: JsVal, len: JsOff) -> JsVal {
+        let res = self.arr_reserve(arr, len);
+        if is_err(res) { return res }
+        let data = self.arr_data(arr);
+        for i in self.arr_len(arr)..len {
+            self.save_val(data + 4 + i as usize * 8, HOLE);
+        }
+        self.save_off(v_data(arr) + 4, len);
+        make_undef()
+    }
+
+    // Set element `i`, extending the array if needed
+    pub(crate) fn arr_set(&mut self, arr: JsVal, i: JsOff, v: JsVal) -> JsVal {
+        if i >= self.arr_len(arr) {
+            let res = self.set_len(arr, i + 1);
+            if is_err(res) { return res }
+        }
+        self.save_val(self.arr_data(arr) + 4 + i as usize * 8, v);
+        v
+    }
+
+    // `arr[key] = val`
+    fn arr_assign(&mut self, arr: JsVal, key: JsVal, val: JsVal) -> JsVal {
+        match self.arr_key(key) {
+            array::Key::Index(i) => self.arr_set(arr, i, val),
+            array::Key::Length => match array::index(v_num(val)) {
+                array::Key::Index(len) if v_type(val) == Type::NUM => {
+                    let res = self.set_len(arr, len);
+                    if is_err(res) { res } else { val }
+                },
+                _ => self.mk_err(ErrorKind::Range, "invalid array length"),
+            },
+            _ => self.mk_err(ErrorKind::Type, "bad array index"),
+        }
+    }
+
+    // What `arr[key]` refers to
+    fn arr_key(&self, key: JsVal) -> array::Key {
+        self.member_key(key, builtin::ARRAY_METHODS)
+    }
+
+    // Key of an array or string member, with methods from the table
+    fn member_key(&self, key: JsVal, methods: usize) -> array::Key {
+        match v_type(key) {
+            Type::NUM => array::index(v_num(key)),
+            Type::STR => array::key(self.load_str(key), methods),
+            _ => array::Key::None,
+        }
+    }
+
+    fn arr_member(&self, arr: JsVal, key: array::Key) -> JsVal {
+        match key {
+            array::Key::Index(i) => match self.arr_get(arr, i) {
+                HOLE => make_undef(),
+                v => v,
+            },
+            array::Key::Length => tok_val(self.arr_len(arr) as f64),
+            array::Key::Method(m) => builtin::member(builtin::ARRAY_METHODS, m),
+            array::Key::None => make_undef(),
+        }
+    }
+
+    // Join the elements into a new string, separated by `sep` or by commas.
+    // The result is written straight into the free memory, then made an
+    // entity
+    pub(crate) fn join(&mut self, arr: JsVal, sep: Option<JsVal>) -> JsVal {
+        let mut pos = self.brk as usize + 4;
+        if pos >= self.size as usize || !self.join_into(arr, sep, &mut pos, 0) {
+            return self.mk_err(ErrorKind::Oom, "oom")
+        }
+        self.take_str(pos)
+    }
+
+    // The free memory where `take_str` expects a new string, short of the
+    // byte for the NUL
+    pub(crate) fn str_room(&mut self) -> &mut [u8] {
+        let (start, end) = (self.str_start(), self.size as usize);
+        &mut self.mem[start.min(end)..end.saturating_sub(1).max(start.min(end))]
+    }
+
+    // Where to write a new string for `take_str`
+    pub(crate) fn str_start(&self) -> usize {
+        self.brk as usize + 4
+    }
+
+    // A new string of the bytes from `start` to `end` of string `s`
+    pub(crate) fn mk_substr(&mut self, s: JsVal, start: usize, end: usize) -> JsVal {
+        let n = (end - start) as JsOff + 1;
+        let res = self.make_entity((n << 2) | Type::STR as JsOff, &[]);
+        if is_err(res) { return res }
+        let (from, off) = (self.v_str(s).0 as usize + start, v_data(res) + 4);
+        self.mem.copy_within(from..from + end - start, off);
+        self.mem[off + end - start] = 0;
+        res
+    }
+
+    // Make a string of the bytes written right above `brk`, up to `end`.
+    // The writer left room for the NUL
+    pub(crate) fn take_str(&mut self, end: usize) -> JsVal {
+        let n = (end - self.brk as usize - 4) as JsOff + 1;
+        if end >= self.size as usize { return self.mk_err(ErrorKind::Oom, "oom") }
+        let off = self.alloc(n + 4);
+        if off == !0u32 { return self.mk_err(ErrorKind::Oom, "oom") }
+        self.mem[end] = 0;
+        self.save_off(off as usize, (n << 2) | Type::STR as JsOff);
+        make_val(Type::STR, off as u64)
+    }
+
+    // Write the joined elements at `pos`, false if they don't fit. Nested
+    // arrays are joined with commas, too deep ones are left out, which
+    // also stops cycles
+    fn join_into(&mut self, arr: JsVal, sep: Option<JsVal>, pos: &mut usize, depth: usize) -> bool {
+        for i in 0..self.arr_len(arr) {
+            if i > 0 {
+                let ok = match sep {
+                    Some(sep) => self.put_mem(pos, self.v_str(sep)),
+                    None => self.put(pos, b","),
+                };
+                if !ok { return false }
+            }
+            let v = self.arr_get(arr, i);
+            let ok = match v_type(v) {
+                Type::UNDEF | Type::NULL => true,
+                Type::STR => self.put_mem(pos, self.v_str(v)),
+                Type::ARR if depth < JS_PRINT_DEPTH => self.join_into(v, None, pos, depth + 1),
+                Type::ARR => true,
+                Type::NUM => {
+                    let mut buf = [0u8; 32];
+                    let mut out = Buf::new(&mut buf);
+                    let _ = fmt_num(v_num(v), &mut out);
+                    let len = out.len();
+                    self.put(pos, &buf[..len])
+                },
+                Type::BOOL => self.put(pos, if v_data(v) != 0 { b"true" } else { b"false" }),
+                _ => self.put(pos, b"[object Object]"),
+            };
+            if !ok { return false }
+        }
+        true
+    }
+
+    // Append bytes to a string being built in the free memory, keeping
+    // room for the NUL
+    pub(crate) fn put(&mut self, pos: &mut usize, bytes: &[u8]) -> bool {
+        if *pos + bytes.len() + 1 > self.size as usize { return false }
+        self.mem[*pos..*pos + bytes.len()].copy_from_slice(bytes);
+        *pos += bytes.len();
+        true
+    }
+
+    // Same, with bytes from an entity
+    pub(crate) fn put_mem(&mut self, pos: &mut usize, (off, len): (JsOff, JsOff)) -> bool {
+        if *pos + len as usize + 1 > self.size as usize { return false }
+        self.mem.copy_within(off as usize..(off + len) as usize, *pos);
+        *pos += len as usize;
+        true
+    }
+
+    // Same, with the code between `start` and `end` with its escapes
+    // decoded. The code may be a function body in JS memory
+    fn put_cooked(&mut self, pos: &mut usize, start: usize, end: usize) -> JsVal {
+        let from = *pos;
+        let src = (self.code.as_ptr() as usize).wrapping_sub(self.mem.as_ptr() as usize);
+        let ok = if src < self.mem.len() {
+            self.put_mem(pos, ((src + start) as JsOff, (end - start) as JsOff))
+        } else {
+            let code = self.code;
+            self.put(pos, &code.as_bytes()[start..end])
+        };
+        if !ok { return self.mk_err(ErrorKind::Oom, "oom") }
+        match unescape(&mut self.mem[from..*pos]) {
+            Ok(len) => {
+                *pos = from + len;
+                make_undef()
+            },
+            Err(msg) => self.mk_err(ErrorKind::Syntax, msg),
+        }
+    }
+}
+
+// What `typeof` says of the value. Built-in objects like `Math` are
+// objects, other built-ins functions
+fn type_name(v: JsVal) -> &'static str {
+    if v_type(v) == Type::BUILTIN && !builtin::is_callable(v) { "object" } else { type_str(v_type(v)) }
+}
+
+// Seed for `Math.random`, from the buffer address and, with std, the time
+fn seed(addr: usize) -> u64 {
+    #[allow(unused_mut)]
+    let mut s = addr as u64 ^ 0x9e37_79b9_7f4a_7c15;
+    #[cfg(feature = "std")]
+    if let Ok(t) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        s ^= t.as_nanos() as u64;
+    }
+    s | 1
+}
+
+fn do_num_op(op: Token, a: f64, b: f64) -> JsVal {
+    let shift = (to_i32(b) & 31) as u32;
+    let res = match op {
+        Token::EXP => js_pow(a, b),
+        Token::MUL => a * b,
+        Token::DIV => a / b,
+        Token::REM => a % b,
+        Token::PLUS => a + b,
+        Token::MINUS => a - b,
+        Token::SHL => to_i32(a).wrapping_shl(shift) as f64,
+        Token::SHR => to_i32(a).wrapping_shr(shift) as f64,
+        Token::ZSHR => (to_i32(a) as u32).wrapping_shr(shift) as f64,
+        Token::AND => (to_i32(a) & to_i32(b)) as f64,
+        Token::XOR => (to_i32(a) ^ to_i32(b)) as f64,
+        Token::OR => (to_i32(a) | to_i32(b)) as f64,
+        Token::UMINUS => -b,
+        Token::UPLUS => b,
+        Token::TILDE => !to_i32(b) as f64,
+        Token::LT => return make_bool(a < b),
+        Token::LE => return make_bool(a <= b),
+        Token::GT => return make_bool(a > b),
+        _ => return make_bool(a >= b),
+    };
+    tok_val(res)
+}
+
+// Write the printable form of the value, reading entities from `mem`
+fn to_str(mem: &[u8], err_msg: &str, v: JsVal, out: &mut impl Write, depth: usize) -> core::fmt::Result {
+    match v_type(v) {
+        Type::UNDEF => out.write_str("undefined"),
+        Type::NULL => out.write_str("null"),
+        Type::BOOL => out.write_str(if v_data(v) != 0 { "true" } else { "false" }),
+        Type::NUM => fmt_num(v_num(v), out),
+        Type::ERR => write!(out, "ERROR: {}", err_msg),
+        Type::STR => write!(out, "\"{}\"", mem_str(mem, v_data(v))),
+        Type::OBJ if depth >= JS_PRINT_DEPTH => out.write_str("{...}"),
+        Type::OBJ => {
+            out.write_str("{")?;
+            let (mut next, mut sep) = (load_off(mem, v_data(v)) & !3u32, "");
+            while next != 0 {
+                let key = load_off(mem, next as usize + 4);
+                // Internal properties aren't printed
+                if key & INTERNAL_PROP == 0 {
+                    out.write_str(sep)?;
+                    to_str(mem, err_msg, make_val(Type::STR, (key & !PROP_FLAGS) as u64), out, depth)?;
+                    out.write_str(":")?;
+                    to_str(mem, err_msg, load_val(mem, next as usize + 8), out, depth + 1)?;
+                    sep = ",";
+                }
+                next = load_off(mem, next as usize) & !3u32;
+            }
+            out.write_str("}")
+        },
+        Type::PROP => to_str(mem, err_msg, load_val(mem, v_data(v) + 8), out, depth),
+        Type::ARR if depth >= JS_PRINT_DEPTH => out.write_str("[...]"),
+        Type::ARR => {
+            out.write_str("[")?;
+            let data = (load_off(mem, v_data(v)) & !3u32) as usize;
+            for i in 0..load_off(mem, v_data(v) + 4) as usize {
+                if i > 0 { out.write_str(",")? }
+                match load_val(mem, data + 4 + i * 8) {
+                    HOLE => (),
+                    elem => to_str(mem, err_msg, elem, out, depth + 1)?,
+                }
+            }
+            out.write_str("]")
+        },
+        Type::RFUNC | Type::BUILTIN => out.write_str("function"),
+        Type::FUNC => {
+            let prop = (load_off(mem, v_data(v)) & !3u32) as usize;
+            write!(out, "function{}", mem_str(mem, v_data(load_val(mem, prop + 8))))
+        },
+        typ => write!(out, "{:?}", typ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(js: &mut Js, code: &str) -> String {
+        js.scope(|js| match js.eval(code) {
+            Ok(res) => js.str(res).to_string(),
+            Err(e) => format!("ERROR: {}", e.message()),
+        })
+    }
+
+    #[test]
+    fn literals() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "42"), "42");
+            assert_eq!(ev(js, "1.5"), "1.5");
+            assert_eq!(ev(js, "2e3"), "2000");
+            assert_eq!(ev(js, "'hi'"), "\"hi\"");
+            assert_eq!(ev(js, "\"hi\""), "\"hi\"");
+            assert_eq!(ev(js, "true"), "true");
+            assert_eq!(ev(js, "false"), "false");
+            assert_eq!(ev(js, "null"), "null");
+            assert_eq!(ev(js, "undefined"), "undefined");
+            assert_eq!(ev(js, ""), "undefined");
+            assert_eq!(ev(js, "1; 2; 3"), "3");
+        }).unwrap();
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "1 + 2 * 3"), "7");
+            assert_eq!(ev(js, "(1 + 2) * 3"), "9");
+            assert_eq!(ev(js, "10 - 4 - 3"), "3");
+            assert_eq!(ev(js, "7 / 2"), "3.5");
+            assert_eq!(ev(js, "7 % 3"), "1");
+            assert_eq!(ev(js, "-7 % 3"), "-1");
+            assert_eq!(ev(js, "2 ** 3 ** 2"), "512");
+            assert_eq!(ev(js, "2 * 3 ** 2"), "18");
+            assert_eq!(ev(js, "-3 + +2"), "-1");
+            assert_eq!(ev(js, "- - 3"), "3");
+            assert_eq!(ev(js, "1 / 0"), "Infinity");
+            assert_eq!(ev(js, "-1 / 0"), "-Infinity");
+            assert_eq!(ev(js, "0 / 0"), "NaN");
+            assert_eq!(ev(js, "0.1 + 0.2"), "0.30000000000000004");
+            assert_eq!(ev(js, "1e21 * 10"), "1e+22");
+        }).unwrap();
+    }
+
+    #[test]
+    fn bitwise() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "6 & 3"), "2");
+            assert_eq!(ev(js, "6 | 3"), "7");
+            assert_eq!(ev(js, "6 ^ 3"), "5");
+            assert_eq!(ev(js, "~5"), "-6");
+            assert_eq!(ev(js, "1 << 4"), "16");
+            assert_eq!(ev(js, "-16 >> 2"), "-4");
+            assert_eq!(ev(js, "-1 >>> 28"), "15");
+            assert_eq!(ev(js, "1 << 32"), "1");
+            assert_eq!(ev(js, "1 | 2 ^ 3 & 4"), "3");
+            assert_eq!(ev(js, "1 + 2 << 1"), "6");
+        }).unwrap();
+    }
+
+    #[test]
+    fn comparison() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "1 < 2"), "true");
+            assert_eq!(ev(js, "2 <= 2"), "true");
+            assert_eq!(ev(js, "1 > 2"), "false");
+            assert_eq!(ev(js, "2 >= 3"), "false");
+            assert_eq!(ev(js, "1 === 1"), "true");
+            assert_eq!(ev(js, "1 !== 1"), "false");
+            assert_eq!(ev(js, "1 === '1'"), "false");
+            assert_eq!(ev(js, "'ab' === 'ab'"), "true");
+            assert_eq!(ev(js, "'ab' < 'b'"), "true");
+            assert_eq!(ev(js, "null === null"), "true");
+            assert_eq!(ev(js, "null === undefined"), "false");
+            assert_eq!(ev(js, "0 / 0 === 0 / 0"), "false");
+            assert_eq!(ev(js, "1 < 2 === true"), "true");
+        }).unwrap();
+    }
+
+    #[test]
+    fn logical() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "!true"), "false");
+            assert_eq!(ev(js, "!0"), "true");
+            assert_eq!(ev(js, "!''"), "true");
+            assert_eq!(ev(js, "1 && 2"), "2");
+            assert_eq!(ev(js, "0 && 2"), "0");
+            assert_eq!(ev(js, "0 || 'x'"), "\"x\"");
+            assert_eq!(ev(js, "null || undefined || 3"), "3");
+            assert_eq!(ev(js, "1 || 0 && 0"), "1");
+            assert_eq!(ev(js, "let a = 0, b = 0; false && (a = 1); true || (b = 1); a + b"), "0");
+            assert_eq!(ev(js, "true && (a = 5); a"), "5");
+            assert_eq!(ev(js, "0 && nosuchvar"), "0");
+        }).unwrap();
+    }
+
+    #[test]
+    fn ternary() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "true ? 1 : 2"), "1");
+            assert_eq!(ev(js, "0 ? 1 : 2"), "2");
+            assert_eq!(ev(js, "1 ? 0 ? 'a' : 'b' : 'c'"), "\"b\"");
+            assert_eq!(ev(js, "let x = 0, y = 0; 1 ? (x = 1) : (y = 1); x * 10 + y"), "10");
+            assert_eq!(ev(js, "x = 0 ? 5 : 6; x"), "6");
+            assert_eq!(ev(js, "1 ? 2"), "ERROR: : expected");
+        }).unwrap();
+    }
+
+    #[test]
+    fn assignment() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let a = 10; a"), "10");
+            assert_eq!(ev(js, "a += 5"), "15");
+            assert_eq!(ev(js, "a -= 3; a"), "12");
+            assert_eq!(ev(js, "a *= 2; a"), "24");
+            assert_eq!(ev(js, "a /= 4; a"), "6");
+            assert_eq!(ev(js, "a %= 4; a"), "2");
+            assert_eq!(ev(js, "a <<= 3; a"), "16");
+            assert_eq!(ev(js, "a >>= 1; a"), "8");
+            assert_eq!(ev(js, "a >>>= 1; a"), "4");
+            assert_eq!(ev(js, "a |= 3; a"), "7");
+            assert_eq!(ev(js, "a &= 5; a"), "5");
+            assert_eq!(ev(js, "a ^= 1; a"), "4");
+            assert_eq!(ev(js, "let b, c; b = c = 3; b + c"), "6");
+            assert_eq!(ev(js, "a++"), "4");
+            assert_eq!(ev(js, "a--; a"), "4");
+            assert_eq!(ev(js, "++a"), "5");
+            assert_eq!(ev(js, "--a + a"), "8");
+            assert_eq!(ev(js, "1 = 2"), "ERROR: bad lhs");
+            assert_eq!(ev(js, "let a = 1"), "ERROR: 'a' already declared");
+        }).unwrap();
+    }
+
+    #[test]
+    fn strings() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "'a' + 'b'"), "\"ab\"");
+            assert_eq!(ev(js, "'n' + 1"), "\"n1\"");
+            assert_eq!(ev(js, "1 + 2 + 'x'"), "\"3x\"");
+            assert_eq!(ev(js, "'x' + true + null"), "\"xtruenull\"");
+            assert_eq!(ev(js, "let s = 'a'; s += 'b'; s"), "\"ab\"");
+            assert_eq!(ev(js, "typeof 1"), "\"number\"");
+            assert_eq!(ev(js, "typeof 'a'"), "\"string\"");
+            assert_eq!(ev(js, "typeof true"), "\"boolean\"");
+            assert_eq!(ev(js, "typeof undefined"), "\"undefined\"");
+            assert_eq!(ev(js, "typeof null"), "\"object\"");
+            assert_eq!(ev(js, "typeof typeof 1"), "\"string\"");
+            assert_eq!(ev(js, "void 1"), "undefined");
+        }).unwrap();
+    }
+
+    #[test]
+    fn escapes_and_templates() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, r"'a\tb' === 'a' + '\x09' + 'b'"), "true");
+            assert_eq!(ev(js, r"'A\u{42}\x43\'\\'"), r#""ABC'\""#);
+            assert_eq!(ev(js, r"'😀' === '😀'"), "true");
+            assert_eq!(ev(js, r"({'\x61': 1}).a"), "1");
+            assert_eq!(ev(js, r"'\x4g'"), "ERROR: bad escape");
+            assert_eq!(ev(js, r"if (false) { '\x4g'; } 1"), "1");
+
+            assert_eq!(ev(js, "``"), "\"\"");
+            assert_eq!(ev(js, "let n = 3, o = {t: 'x'}; `n=${n}, next=${n + 1}${o.t}`"), "\"n=3, next=4x\"");
+            assert_eq!(ev(js, "`${[1, 2]}|${null}|${undefined}|${true}|${{}}`"), "\"1,2|null|undefined|true|[object Object]\"");
+            assert_eq!(ev(js, "`a\nb` === 'a' + '\\n' + 'b'"), "true");
+            assert_eq!(ev(js, r"`\${n} \` ${'}'} ${`in${n}`} $ {n}`"), r#""${n} ` } in3 $ {n}""#);
+            assert_eq!(ev(js, "let f = (d) => `dev/${d}/temp`; f('a' + 1)"), "\"dev/a1/temp\"");
+            assert_eq!(ev(js, "`${n"), "ERROR: bad expr");
+            assert_eq!(ev(js, "`${n +}`"), "ERROR: bad expr");
+            assert_eq!(ev(js, r"`\u{zz}`"), "ERROR: bad escape");
+            assert_eq!(ev(js, "`${nope}`"), "ERROR: 'nope' not found");
+            assert_eq!(ev(js, "if (false) { `${nope}`; } `ok`"), "\"ok\"");
+            js.setgct(0);
+            assert_eq!(ev(js, "let g = x => `<${x + x}>`; `${g('a')}${g('b')}`"), "\"<aa><bb>\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn statements() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let a = 1; if (a) a = 2; a"), "2");
+            assert_eq!(ev(js, "if (a > 5) { a = 3; } else { a = 4; } a"), "4");
+            assert_eq!(ev(js, "if (0) 1; else if (0) 2; else 3"), "3");
+            assert_eq!(ev(js, "{ let a = 7; a }"), "7");
+            assert_eq!(ev(js, "a"), "4");
+            assert_eq!(ev(js, "{ a = 5; } a"), "5");
+            assert_eq!(ev(js, "{ let z = 1; } z"), "ERROR: 'z' not found");
+            assert_eq!(ev(js, ";;; 1"), "1");
+            assert_eq!(ev(js, "// comment\n1 /* inline */ + 1"), "2");
+        }).unwrap();
+    }
+
+    #[test]
+    fn scopes() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let a = 1; { let a = 2; { let a = 3; } a += 10; } a"), "1");
+            assert_eq!(ev(js, "{ let b = 2; { let c = b * 2; b = c; } b }"), "4");
+            assert_eq!(ev(js, "const k = 5, m = k + 1; m"), "6");
+            assert_eq!(ev(js, "k = 6"), "ERROR: assignment to constant 'k'");
+            assert_eq!(ev(js, "k += 1"), "ERROR: assignment to constant 'k'");
+            assert_eq!(ev(js, "k++"), "ERROR: assignment to constant 'k'");
+            assert_eq!(ev(js, "k"), "5");
+            assert_eq!(ev(js, "{ const k = 'x'; k }"), "\"x\"");
+            assert_eq!(ev(js, "{ let k = 1; k = 2; k }"), "2");
+            assert_eq!(ev(js, "const n;"), "ERROR: 'n' must be initialized");
+            assert_eq!(ev(js, "const k = 1;"), "ERROR: 'k' already declared");
+            assert_eq!(ev(js, "if (0) { const k = 1; k = 2; } k"), "5");
+            assert_eq!(js.eval("k = 1").unwrap_err().kind, ErrorKind::Type);
+            js.gc();
+            assert_eq!(ev(js, "k = 1"), "ERROR: assignment to constant 'k'");
+            assert_eq!(ev(js, "m = 1"), "ERROR: assignment to constant 'm'");
+            assert_eq!(ev(js, "a = 2"), "2");
+        }).unwrap();
+    }
+
+    #[test]
+    fn functions() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "function add(a, b) { return a + b; } add(1, 2)"), "3");
+            assert_eq!(ev(js, "let sq = function(x) { return x * x; }; sq(add(1, 2))"), "9");
+            assert_eq!(ev(js, "(function() { return; })()"), "undefined");
+            assert_eq!(ev(js, "(function(a) { a; })(1)"), "undefined");
+            assert_eq!(ev(js, "(function(a, b) { return b; })(1)"), "undefined");
+            assert_eq!(ev(js, "add(1, 2, 3)"), "3");
+            assert_eq!(ev(js, "function sign(x) { if (x < 0) return -1; else if (x > 0) { return 1; } return 0; }"), "undefined");
+            assert_eq!(ev(js, "sign(-5) + ' ' + sign(5) + ' ' + sign(0)"), "\"-1 1 0\"");
+            assert_eq!(ev(js, "function fact(n) { return n < 2 ? 1 : n * fact(n - 1); } fact(10)"), "3628800");
+            assert_eq!(ev(js, "typeof fact"), "\"function\"");
+            assert_eq!(ev(js, "sq"), "function(x) { return x * x; }");
+            // The code is kept where scripts can't reach it
+            assert_eq!(ev(js, "[sq.code, sq.__code]"), "[undefined,undefined]");
+            assert_eq!(ev(js, "sq.code = 1; delete sq.code; sq.__code = 2; sq(3)"), "9");
+            assert_eq!(ev(js, "return 1"), "ERROR: 'return' outside function");
+            assert_eq!(ev(js, "function (a b) {}"), "ERROR: ) expected");
+            assert_eq!(ev(js, "function f() { return nope; } f()"), "ERROR: 'nope' not found");
+            assert_eq!(ev(js, "function f() { return 1; } f()"), "1");
+            assert_eq!(ev(js, "const c = function() {}; function c() {}"), "ERROR: assignment to constant 'c'");
+        }).unwrap();
+    }
+
+    #[test]
+    fn closures() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            ev(js, "function counter(n) { return function() { n += 1; return n; }; }");
+            ev(js, "let a = counter(0), b = counter(10);");
+            assert_eq!(ev(js, "a(); a(); b(); a() + ' ' + b()"), "\"3 12\"");
+            js.gc();
+            assert_eq!(ev(js, "a()"), "4");
+            assert_eq!(ev(js, "let x = 'outer'; function get() { return x; } { let x = 'inner'; get() }"), "\"outer\"");
+            assert_eq!(ev(js, "function adder(a) { return function(b) { return function(c) { return a + b + c; }; }; } adder(1)(2)(3)"), "6");
+        }).unwrap();
+    }
+
+    #[test]
+    fn calls_survive_gc() {
+        // Collect on every statement, so that the code of running functions
+        // moves around
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            js.setgct(0);
+            ev(js, "let junk = 'a' + 'b'; junk = 0;");
+            ev(js, "function f(n) { let s = 'x' + n; junk = s + s; if (n > 0) { let r = f(n - 1); return s + r; } return s; }");
+            assert_eq!(ev(js, "junk = 'c' + 'd'; f(3)"), "\"x3x2x1x0\"");
+            assert!(js.stats().gc_runs > 10);
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            js.setmaxss(64 * 1024);
+            let e = js.eval("function r(n) { return r(n + 1); }\nr(0)").unwrap_err();
+            assert_eq!((e.kind, e.message(), e.line), (ErrorKind::StackOverflow, "stack overflow", 2));
+            assert_eq!(ev(js, "r"), "function(n) { return r(n + 1); }");
+            assert_eq!(ev(js, "function s(n) { return n ? s(n - 1) : 'ok'; } s(3)"), "\"ok\"");
+        }).unwrap();
+
+        // Limited by default, 0 turns the limit off
+        let mut buf = vec![0u8; 1 << 20];
+        Js::new(&mut buf, |js| {
+            let e = js.eval("function f(n) { return f(n + 1); } f(0)").unwrap_err();
+            assert_eq!(e.kind, ErrorKind::StackOverflow);
+            js.setmaxss(0);
+            assert_eq!(ev(js, "function g(n) { return n ? g(n - 1) : 'ok'; } g(100)"), "\"ok\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            js.setmaxss(100_000);
+            let deep = |open: &str, close: &str| format!("{}1{}", open.repeat(5000), close.repeat(5000));
+            for code in [deep("(", ")"), deep("[", "]"), deep("-", ""), deep("{", "}")] {
+                let e = js.eval(&code).unwrap_err();
+                assert_eq!((e.kind, e.message()), (ErrorKind::StackOverflow, "stack overflow"));
+            }
+            assert_eq!(ev(js, "[[[[[[[[[[(((((((((((1)))))))))))]]]]]]]]]][0][0][0][0][0][0][0][0][0][0]"), "1");
+        }).unwrap();
+    }
+
+    #[test]
+    fn loops() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let i = 0, s = 0; while (i < 5) { i++; s += i; } s"), "15");
+            assert_eq!(ev(js, "while (0) nope(); i"), "5");
+            assert_eq!(ev(js, "do { i--; } while (i > 2); i"), "2");
+            assert_eq!(ev(js, "do i = 10; while (0) i"), "10");
+            assert_eq!(ev(js, "s = ''; for (let j = 0; j < 3; j++) s += j; s"), "\"012\"");
+            assert_eq!(ev(js, "j"), "ERROR: 'j' not found");
+            assert_eq!(ev(js, "s = 0; for (i = 0; i < 10; i++) { if (i === 3) continue; if (i === 6) break; s += i; } s + ' ' + i"), "\"12 6\"");
+            assert_eq!(ev(js, "s = 0; for (;;) { if (++s > 3) break; } s"), "4");
+            assert_eq!(ev(js, "s = 0; do { s++; if (s < 5) continue; break; } while (1); s"), "5");
+            assert_eq!(ev(js, "s = 0; while (1) { s++; { if (s === 2) break; } } s"), "2");
+            assert_eq!(ev(js, "if (0) { while (1) {} for (;;) {} do {} while (1); } 1"), "1");
+            assert_eq!(ev(js, "function f(n) { for (let k = 0; ; k++) { if (k === n) return k * 2; } } f(4)"), "8");
+        }).unwrap();
+    }
+
+    #[test]
+    fn labels() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            ev(js, "let s = '';");
+            assert_eq!(ev(js, "outer: for (let i = 0; i < 3; i++) { for (let j = 0; j < 3; j++) { if (j === 1) continue outer; if (i === 2) break outer; s += i + '' + j + ' '; } } s"), "\"00 10 \"");
+            assert_eq!(ev(js, "s = 0; a: while (1) { b: while (1) { s++; if (s > 2) break a; break b; } } s"), "3");
+            assert_eq!(ev(js, "s = 0; blk: { s = 1; if (s) { break blk; } s = 2; } s"), "1");
+            assert_eq!(ev(js, "break"), "ERROR: 'break' outside loop");
+            assert_eq!(ev(js, "if (1) continue"), "ERROR: 'continue' outside loop");
+            assert_eq!(ev(js, "while (1) { function f() { break; } f(); }"), "ERROR: 'break' outside loop");
+            assert_eq!(ev(js, "x: 1; break y"), "ERROR: label 'y' not found");
+            assert_eq!(ev(js, "s"), "1");
+        }).unwrap();
+    }
+
+    #[test]
+    fn for_in() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_object(glob, "g", glob);
+            assert_eq!(ev(js, "let keys = ''; for (let k in g) keys += k + ','; keys"), "\"g,keys,\"");
+            assert_eq!(ev(js, "let name; for (name in g) if (name === 'keys') break; name"), "\"keys\"");
+            assert_eq!(ev(js, "keys = ''; for (const k in g) { keys += k; k = 1; } keys"), "ERROR: assignment to constant 'k'");
+            assert_eq!(ev(js, "keys = 0; for (let k in 1) keys++; keys"), "0");
+            assert_eq!(ev(js, "for (nope in g) {}"), "ERROR: 'nope' not found");
+            js.setgct(0);
+            assert_eq!(ev(js, "keys = ''; for (let k in g) { let junk = 'x' + k; keys += k; } keys"), "\"gkeysname\"");
+
+            // Arrays yield their indices, as strings, skipping holes
+            assert_eq!(ev(js, "keys = []; for (let k in ['a', 'b', 'c']) keys.push(k); keys"), "[\"0\",\"1\",\"2\"]");
+            assert_eq!(ev(js, "let sp = [1, 2, 3, 4]; delete sp[1]; keys = ''; for (let k in sp) keys += typeof k + k + ','; keys"), "\"string0,string2,string3,\"");
+            assert_eq!(ev(js, "let grow = [0]; for (let k in grow) if (grow.length < 3) grow.push(k); grow"), "[0,\"0\",\"1\"]");
+            assert_eq!(ev(js, "keys = 0; for (let k in []) keys++; keys"), "0");
+        }).unwrap();
+    }
+
+    #[test]
+    fn for_let_bindings() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            // Closures see the variables of the iteration they were made in
+            assert_eq!(ev(js, "let fs = []; for (let i = 0; i < 3; i++) fs.push(() => i); fs.map(f => f())"), "[0,1,2]");
+            assert_eq!(ev(js, "fs = []; for (let i = 0, j = 10; i < 3; i++) { j--; if (i === 1) continue; fs.push(() => i + ':' + j); } fs.map(f => f())"), "[\"0:9\",\"2:7\"]");
+            assert_eq!(ev(js, "fs = []; for (let k in {a: 1, b: 2}) fs.push(() => k); fs.map(f => f())"), "[\"a\",\"b\"]");
+            // A variable declared outside the loop is shared
+            assert_eq!(ev(js, "fs = []; let n; for (n = 0; n < 3; n++) fs.push(() => n); fs.map(f => f())"), "[3,3,3]");
+            assert_eq!(ev(js, "let last; for (let i = 0; i < 3; i++) last = i; last"), "2");
+            js.setgct(0);
+            assert_eq!(ev(js, "fs = []; for (let i = 0; i < 4; i++) { let s = 'x' + i; fs.push(() => s + i); } fs.map(f => f()).join()"), "\"x00,x11,x22,x33\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn switches() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            ev(js, "function name(n) { switch (n) { case 1: return 'one'; case 2: case 3: return 'few'; default: return 'many'; } }");
+            assert_eq!(ev(js, "name(1) + name(3) + name(7)"), "\"onefewmany\"");
+            ev(js, "function run(v) { let r = ''; switch (v) { case 'a': r += 'a'; case 'b': r += 'b'; break; default: r += 'd'; case 'c': r += 'c'; } return r; }");
+            assert_eq!(ev(js, "run('a') + ',' + run('b') + ',' + run('c') + ',' + run('x')"), "\"ab,b,c,dc\"");
+            assert_eq!(ev(js, "let n = 0; switch (1) { case '1': n = 1; } n"), "0");
+            assert_eq!(ev(js, "let calls = 0; function f(x) { calls++; return x; } switch (2) { case f(1): case f(2): case f(3): } calls"), "2");
+            assert_eq!(ev(js, "switch (1) { case 1: { let x = 1; break; } n = 5; } n"), "0");
+            assert_eq!(ev(js, "if (0) { switch (nope) { case nope: nope(); default: nope(); } } 1"), "1");
+            assert_eq!(ev(js, "switch (1) { case 1: let q = 2; } q"), "ERROR: 'q' not found");
+            assert_eq!(ev(js, "switch (1) { case 1 break; }"), "ERROR: : expected");
+            assert_eq!(ev(js, "switch (1) { case 1: "), "ERROR: } expected");
+            assert_eq!(ev(js, "switch (1) { case 1: continue; }"), "ERROR: 'continue' outside loop");
+        }).unwrap();
+    }
+
+    #[test]
+    fn switch_in_loops() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            let code = "let r = '';
+                for (let i = 0; i < 6; i++) {
+                    switch (i % 3) {
+                        case 0: r += 'z'; break;
+                        case 1:
+                            switch (i) { case 1: r += 'A'; break; default: r += 'B'; }
+                            continue;
+                        default: if (i > 4) break; r += 'd';
+                    }
+                    r += '.';
+                }
+                r";
+            assert_eq!(ev(js, code), "\"z.Ad.z.B.\"");
+            assert_eq!(ev(js, "r = 0; out: while (1) { switch (r) { case 3: break out; default: r++; } } r"), "3");
+            assert_eq!(ev(js, "r = 0; sw: switch (1) { case 1: for (;;) { r++; break sw; } r = 9; } r"), "1");
+        }).unwrap();
+    }
+
+    #[test]
+    fn arrays() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "[]"), "[]");
+            assert_eq!(ev(js, "[1, 'a', [true, null], ]"), "[1,\"a\",[true,null]]");
+            assert_eq!(ev(js, "let a = [1, , 3]; a"), "[1,,3]");
+            assert_eq!(ev(js, "a.length"), "3");
+            assert_eq!(ev(js, "a[1] === undefined"), "true");
+            assert_eq!(ev(js, "a[0] + a['2'] + a.length"), "7");
+            assert_eq!(ev(js, "a[3]"), "undefined");
+            assert_eq!(ev(js, "a[-1]"), "undefined");
+            assert_eq!(ev(js, "a[5] = 6; a"), "[1,,3,,,6]");
+            assert_eq!(ev(js, "a.length = 2; a"), "[1,]");
+            assert_eq!(ev(js, "a.length = 3; a[1] = a[0] += 10; a"), "[11,11,]");
+            assert_eq!(ev(js, "a[0]++; ++a[1]; a[0] + a[1]"), "24");
+            assert_eq!(ev(js, "let m = [[1, 2], [3]]; m[1][0] = m[0][1] * 10; m"), "[[1,2],[20]]");
+            assert_eq!(ev(js, "typeof m + typeof m.push"), "\"objectfunction\"");
+            assert_eq!(ev(js, "m === m"), "true");
+            assert_eq!(ev(js, "[] === []"), "false");
+            assert_eq!(ev(js, "'<' + [1, [2, 3], null] + '>'"), "\"<1,2,3,>\"");
+            assert_eq!(ev(js, "[] ? 1 : 2"), "1");
+            assert_eq!(ev(js, "let g = []; for (let i = 0; i < 100; i++) g[i] = i * i; g.length + g[99]"), "9901");
+            let nested = format!("{}...{}", "[".repeat(JS_PRINT_DEPTH + 1), "]".repeat(JS_PRINT_DEPTH + 1));
+            assert_eq!(ev(js, "let c = [1]; c[0] = c; c"), nested);
+            assert_eq!(ev(js, "'' + c"), "\"\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn array_methods() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let a = [1, 2]; a.push(3, 4)"), "4");
+            assert_eq!(ev(js, "a.pop() + a.pop() + a.length"), "9");
+            assert_eq!(ev(js, "[].pop()"), "undefined");
+            assert_eq!(ev(js, "let b = [1, 2, 3, 4, 5]; b.slice(1, 3)"), "[2,3]");
+            assert_eq!(ev(js, "b.slice(-2)"), "[4,5]");
+            assert_eq!(ev(js, "b.slice(3, 1)"), "[]");
+            assert_eq!(ev(js, "b.slice() === b"), "false");
+            assert_eq!(ev(js, "b.indexOf(3) + b.indexOf(6) + b.indexOf(1, 1)"), "0");
+            assert_eq!(ev(js, "['a', 'b'].indexOf('b')"), "1");
+            assert_eq!(ev(js, "b.join()"), "\"1,2,3,4,5\"");
+            assert_eq!(ev(js, "b.join(' - ')"), "\"1 - 2 - 3 - 4 - 5\"");
+            assert_eq!(ev(js, "[1, , null, undefined, 'x', true].join(0)"), "\"10000x0true\"");
+            assert_eq!(ev(js, "b.map(function(x, i) { return x * i; })"), "[0,2,6,12,20]");
+            assert_eq!(ev(js, "[1, , 3].map(function(x) { return x + 1; })"), "[2,,4]");
+            assert_eq!(ev(js, "b.filter(function(x) { return x % 2; })"), "[1,3,5]");
+            assert_eq!(ev(js, "let sum = 0; b.forEach(function(x) { sum += x; }); sum"), "15");
+            assert_eq!(ev(js, "b.reduce(function(acc, x) { return acc + x; })"), "15");
+            assert_eq!(ev(js, "b.reduce(function(acc, x) { return acc + x; }, '')"), "\"12345\"");
+            assert_eq!(ev(js, "b.map(function(x) { return [x]; }).slice(3)"), "[[4],[5]]");
+            assert_eq!(ev(js, "let f = b.push; f(1)"), "ERROR: not an array");
+            assert_eq!(ev(js, "b.map(1)"), "ERROR: callback is not a function");
+            assert_eq!(ev(js, "[].reduce(function() {})"), "ERROR: reduce of empty array with no initial value");
+            assert_eq!(ev(js, "b.forEach(function(x) { if (x > 2) throw 'stop'; })"), "ERROR: stop");
+            assert_eq!(ev(js, "b.nope"), "undefined");
+            assert_eq!(ev(js, "b.nope()"), "ERROR: calling non-function");
+        }).unwrap();
+    }
+
+    #[test]
+    fn string_methods() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let s = 'Hello, world'; s.length + s[0] + s['4'] + s[12]"), "\"12Houndefined\"");
+            assert_eq!(ev(js, "s.charCodeAt(1) + ':' + s.charCodeAt(99)"), "\"101:NaN\"");
+            assert_eq!(ev(js, "s.indexOf('o') + ',' + s.indexOf('o', 5) + ',' + s.indexOf('x') + ',' + s.indexOf('')"), "\"4,8,-1,0\"");
+            assert_eq!(ev(js, "s.slice(7) + '|' + s.slice(-5, -1) + '|' + s.slice(5, 2)"), "\"world|worl|\"");
+            assert_eq!(ev(js, "s.substring(5, 0) + '|' + s.substring(-3, 2) + '|' + s.substring(7)"), "\"Hello|He|world\"");
+            assert_eq!(ev(js, "'a,b,,c'.split(',')"), "[\"a\",\"b\",\"\",\"c\"]");
+            assert_eq!(ev(js, "'a, b, c'.split(', ', 2)"), "[\"a\",\"b\"]");
+            assert_eq!(ev(js, "'abc'.split('') + '|' + 'abc'.split() + '|' + ''.split(',').length + ''.split('').length"), "\"a,b,c|abc|10\"");
+            assert_eq!(ev(js, "s.toUpperCase() + s.toLowerCase()"), "\"HELLO, WORLDhello, world\"");
+            assert_eq!(ev(js, "'\\t x y \\n'.trim() + '|' + ' '.trim() + '|'"), "\"x y||\"");
+            assert_eq!(ev(js, "[s.startsWith('Hell'), s.startsWith('o', 4), s.endsWith('ld'), s.endsWith('o', 5)]"), "[true,true,true,true]");
+            assert_eq!(ev(js, "s.replace('o', '0') + '|' + s.replace('x', '?') + '|' + 'aaa'.replace('', '-')"), "\"Hell0, world|Hello, world|-aaa\"");
+            assert_eq!(ev(js, "s.replace('world', (m, i) => m.toUpperCase() + i)"), "\"Hello, WORLD7\"");
+            assert_eq!(ev(js, "String.fromCharCode(72, 105) + String(1.5) + String(null) + String()"), "\"Hi1.5null\"");
+            assert_eq!(ev(js, "typeof String + typeof String.fromCharCode + typeof String.nope"), "\"functionfunctionundefined\"");
+            assert_eq!(ev(js, "'x'.nope()"), "ERROR: calling non-function");
+            assert_eq!(ev(js, "let t = s.trim; t()"), "ERROR: not a string");
+            assert_eq!(ev(js, "{ let String = 1; String }"), "1");
+            js.setgct(0);
+            assert_eq!(ev(js, "('a' + 'b-c').replace('-', m => [m, m].join('')).split('--')"), "[\"ab\",\"c\"]");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn numbers() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "0xff + 0o17 + 0b11 + .5 + 1e2 + 2.5E-1"), "373.75");
+            assert_eq!(ev(js, "1..toString() + 1.5.toFixed(2)"), "\"11.50\"");
+            assert_eq!(ev(js, "3in"), "ERROR: bad expr");
+            assert_eq!(ev(js, "[parseInt('42px'), parseInt(' -0x1A'), parseInt('101', 2), parseInt('z', 36), parseInt('8', 8)]"), "[42,-26,5,35,NaN]");
+            assert_eq!(ev(js, "[parseInt(''), parseInt('12', 1), parseInt(15.99), parseInt('123456789012345678901234567890')]"), "[NaN,NaN,15,1.2345678901234568e+29]");
+            assert_eq!(ev(js, "[parseFloat('3.14abc'), parseFloat('  -.5e1x'), parseFloat('Infinityx'), parseFloat('e5')]"), "[3.14,-5,Infinity,NaN]");
+            assert_eq!(ev(js, "[Number('  12  '), Number(''), Number('0x10'), Number('1e3'), Number('12px'), Number()]"), "[12,0,16,1000,NaN,0]");
+            assert_eq!(ev(js, "[Number(true), Number(null), Number(undefined), Number([]), Number(['7']), Number({})]"), "[1,0,NaN,0,7,NaN]");
+            assert_eq!(ev(js, "[isNaN('abc'), isNaN('1'), isNaN(NaN), isFinite('1e308'), isFinite(Infinity), isFinite(null)]"), "[true,false,true,true,false,true]");
+            assert_eq!(ev(js, "[(1.005).toFixed(2), (1.25).toFixed(1), (2.5).toFixed(0), (-1.5).toFixed(0), (-0).toFixed(2)]"), "[\"1.00\",\"1.3\",\"3\",\"-2\",\"0.00\"]");
+            assert_eq!(ev(js, "[(123.456).toFixed(), (1e21).toFixed(2), (0.000001).toFixed(7), NaN.toFixed(1)]"), "[\"123\",\"1e+21\",\"0.0000010\",\"NaN\"]");
+            assert_eq!(ev(js, "[(255).toString(16), (255).toString(2), (-255).toString(36), (0.5).toString(2), (3.75).toString(16)]"), "[\"ff\",\"11111111\",\"-73\",\"0.1\",\"3.c\"]");
+            assert_eq!(ev(js, "[(0.1).toString(3), (2 ** 60).toString(16), (12.5).toString(), Infinity.toString(2)]"), "[\"0.0022002200220022002200220022002201\",\"1000000000000000\",\"12.5\",\"Infinity\"]");
+            assert_eq!(ev(js, "(1).toFixed(101)"), "ERROR: toFixed() digits must be between 0 and 100");
+            assert_eq!(ev(js, "(1).toString(37)"), "ERROR: toString() radix must be between 2 and 36");
+            assert_eq!(ev(js, "let f = (1).toFixed; f()"), "ERROR: not a number");
+            assert_eq!(ev(js, "[Number.MAX_SAFE_INTEGER, Number.EPSILON > 0, Number.MIN_VALUE / 2 === 0, -Infinity, NaN === NaN]"), "[9007199254740991,true,true,-Infinity,false]");
+            assert_eq!(ev(js, "typeof Number + typeof (5).toFixed + typeof NaN"), "\"functionfunctionnumber\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn math() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "typeof Math + ' ' + typeof Math.sin + ' ' + Math.PI"), "\"object function 3.141592653589793\"");
+            assert_eq!(ev(js, "[Math.E, Math.LN2, Math.SQRT2, Math.nope]"), "[2.718281828459045,0.6931471805599453,1.4142135623730951,undefined]");
+            assert_eq!(ev(js, "[Math.abs(-2), Math.floor(-1.5), Math.ceil(1.2), Math.trunc(-1.7), Math.sign(-3)]"), "[2,-2,2,-1,-1]");
+            assert_eq!(ev(js, "[Math.round(2.5), Math.round(-2.5), Math.round(0.49999999999999994), 1 / Math.round(-0.2)]"), "[3,-2,0,-Infinity]");
+            assert_eq!(ev(js, "[Math.sqrt(16), Math.cbrt(-27), Math.pow(2, 10), Math.pow(1, Infinity), Math.hypot(3, 4)]"), "[4,-3,1024,NaN,5]");
+            assert_eq!(ev(js, "[Math.log2(8), Math.log10(1000), Math.exp(0), Math.log(1), Math.sin(0), Math.cos(0)]"), "[3,3,1,0,0,1]");
+            assert_eq!(ev(js, "[Math.atan2(1, 1) * 4, Math.acos(-1), Math.asin(2)]"), "[3.141592653589793,3.141592653589793,NaN]");
+            assert_eq!(ev(js, "Math.abs(Math.sin(Math.PI / 6) - 0.5) < 1e-15 && Math.abs(Math.tan(1) - 1.5574077246549023) < 1e-15"), "true");
+            assert_eq!(ev(js, "[Math.max(1, '5', [3]), Math.min(), Math.max(), Math.max(1, NaN), 1 / Math.min(0, -0)]"), "[5,Infinity,-Infinity,NaN,-Infinity]");
+            assert_eq!(ev(js, "Math.abs('-2') + Math.floor()"), "NaN");
+            assert_eq!(ev(js, "let r = Math.random(), s = Math.random(); r >= 0 && r < 1 && r !== s"), "true");
+            assert_eq!(ev(js, "[1, 4, 9].map(Math.sqrt)"), "[1,2,3]");
+            assert_eq!(ev(js, "Math()"), "ERROR: calling non-function");
+            assert_eq!(ev(js, "Math.PI = 3"), "ERROR: can't set members of object");
+            assert_eq!(ev(js, "{ let Math = {PI: 3}; Math.PI }"), "3");
+        }).unwrap();
+    }
+
+    #[test]
+    fn json_parse() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, r#"JSON.parse(' {"a": [1, -2.5e1, true, null], "b": {"c": "d"}, "e": {}, "f": []} ')"#), r#"{"a":[1,-25,true,null],"b":{"c":"d"},"e":{},"f":[]}"#);
+            assert_eq!(ev(js, r#"JSON.parse('"\\u00e9\\ud83d\\ude00\\n\\"\\/\\\\"') === 'é😀\n"/\\'"#), "true");
+            assert_eq!(ev(js, r#"let o = JSON.parse('{"x": 1, "y": 2, "x": 3}'); o.x + o.y"#), "5");
+            assert_eq!(ev(js, "[JSON.parse('0'), 1 / JSON.parse('-0'), JSON.parse('1E2'), JSON.parse(' \"é\" ')]"), "[0,-Infinity,100,\"é\"]");
+            assert_eq!(ev(js, "JSON.parse(12)"), "12");
+            assert_eq!(ev(js, "JSON.parse('[1, 2'"), "ERROR: ) expected");
+            assert_eq!(ev(js, "JSON.parse('[1, 2')"), "ERROR: unexpected end of JSON input");
+            assert_eq!(ev(js, "JSON.parse('{\"a\": 1,}')"), "ERROR: unexpected '}' in JSON at position 8");
+            assert_eq!(ev(js, "JSON.parse('[01]')"), "ERROR: unexpected '1' in JSON at position 2");
+            assert_eq!(ev(js, "JSON.parse('[1.]')"), "ERROR: unexpected ']' in JSON at position 3");
+            assert_eq!(ev(js, "JSON.parse(\"['a']\")"), "ERROR: unexpected ''' in JSON at position 1");
+            assert_eq!(ev(js, "JSON.parse('\"é\\\\x41\"')"), "ERROR: unexpected 'x' in JSON at position 3");
+            assert_eq!(ev(js, "JSON.parse('\"\\\\u12g4\"')"), "ERROR: unexpected 'g' in JSON at position 5");
+            assert_eq!(ev(js, "JSON.parse('\"a\\nb\"')"), "ERROR: unexpected '\\n' in JSON at position 2");
+            assert_eq!(ev(js, "JSON.parse('{a: 1}')"), "ERROR: unexpected 'a' in JSON at position 1");
+            assert_eq!(ev(js, "JSON.parse('tru')"), "ERROR: unexpected 't' in JSON at position 0");
+            assert_eq!(ev(js, "JSON.parse('nullx')"), "ERROR: unexpected 'x' in JSON at position 4");
+            assert_eq!(ev(js, "JSON.parse('')"), "ERROR: unexpected end of JSON input");
+            assert_eq!(ev(js, "JSON.parse('[[[[[[1]]]]]]')[0][0][0][0][0][0] + 1"), "2");
+            assert_eq!(ev(js, "let deep = ''; for (let i = 0; i < 65; i++) deep = '[' + deep + ']'; JSON.parse(deep)"), "ERROR: JSON nested too deep");
+            assert_eq!(ev(js, "typeof JSON + ' ' + typeof JSON.parse"), "\"object function\"");
+            assert_eq!(ev(js, "let m; try { JSON.parse('{\"a\" 1}'); } catch (e) { m = e.name + ': ' + e.message; } m"), "\"SyntaxError: unexpected '1' in JSON at position 5\"");
+            assert_eq!(ev(js, "try { JSON.parse('{'); } finally { m = 1; }"), "ERROR: unexpected end of JSON input");
+            assert_eq!(ev(js, "m = 0; try { JSON.parse('['); } catch (e) { m = 1; } m"), "1");
+        }).unwrap();
+    }
+
+    #[test]
+    fn json_stringify() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            let s = |js: &mut Js, code: &str| js.scope(|js| match js.eval(code) {
+                Ok(res) if res.kind() == Kind::String => js.get_str(res).unwrap_or("").to_string(),
+                Ok(res) => js.str(res).to_string(),
+                Err(e) => format!("ERROR: {}", e.message()),
+            });
+            assert_eq!(s(js, "JSON.stringify({a: [1, 'x', true, null], b: {c: -0}, d: 1e21})"), r#"{"a":[1,"x",true,null],"b":{"c":0},"d":1e+21}"#);
+            assert_eq!(s(js, "JSON.stringify({u: undefined, f: function() {}, m: Math.max, n: NaN, i: -Infinity, a: [undefined, parseInt]})"), r#"{"n":null,"i":null,"a":[null,null]}"#);
+            assert_eq!(s(js, "JSON.stringify('q\"\\\\\\n\\t\\u0001é😀')"), r#""q\"\\\n\t\u0001é😀""#);
+            assert_eq!(s(js, "let h = [1, 2]; h[4] = 5; JSON.stringify([h, Math, {}, []])"), "[[1,2,null,null,5],{},{},[]]");
+            assert_eq!(s(js, "[JSON.stringify(undefined), JSON.stringify(function() {}), JSON.stringify()]"), "[undefined,undefined,undefined]");
+            assert_eq!(s(js, "JSON.stringify({a: 1, b: [1, {c: 2}], e: [], o: {}}, null, 2)"), "{\n  \"a\": 1,\n  \"b\": [\n    1,\n    {\n      \"c\": 2\n    }\n  ],\n  \"e\": [],\n  \"o\": {}\n}");
+            assert_eq!(s(js, "JSON.stringify([1, [2]], null, '--')"), "[\n--1,\n--[\n----2\n--]\n]");
+            assert_eq!(s(js, "JSON.stringify([1], null, 20) === JSON.stringify([1], null, '          ')"), "true");
+            assert_eq!(s(js, "JSON.stringify([1], null, 'abcdefghijkl')"), "[\nabcdefghij1\n]");
+            assert_eq!(s(js, "JSON.stringify({a: 1}, null, 0) + JSON.stringify({a: 1}, null, '')"), r#"{"a":1}{"a":1}"#);
+
+            assert_eq!(s(js, "JSON.stringify({a: 1, b: 2, c: {a: 3, d: 4}, 1: 5}, ['c', 'a', 1, 'c', 'z'])"), r#"{"c":{"a":3},"a":1,"1":5}"#);
+            assert_eq!(s(js, "JSON.stringify({a: 1, b: 'x', c: [1, 2]}, function(k, v) { return typeof v === 'number' ? v * 10 : v; })"), r#"{"a":10,"b":"x","c":[10,20]}"#);
+            assert_eq!(s(js, "JSON.stringify({a: 1, b: 2}, function(k, v) { return k === 'a' ? undefined : v; })"), r#"{"b":2}"#);
+            assert_eq!(s(js, "let keys = []; JSON.stringify({a: [5]}, function(k, v) { keys.push(k); return v; }); keys"), r#"["","a","0"]"#);
+            assert_eq!(s(js, "let holder; JSON.stringify(7, function(k, v) { holder = this; return v; }); holder['']"), "7");
+            assert_eq!(s(js, "JSON.stringify(1, function() { return {x: [1]}; })"), "ERROR: JSON nested too deep");
+
+            ev(js, "function Point(x, y) { this.x = x; this.y = y; } Point.prototype.toJSON = function(key) { return key + ':' + this.x + ',' + this.y; };");
+            assert_eq!(s(js, "JSON.stringify({p: new Point(1, 2), q: [new Point(3, 4)]})"), r#"{"p":"p:1,2","q":["0:3,4"]}"#);
+            assert_eq!(s(js, "JSON.stringify({toJSON: function() { return undefined; }})"), "undefined");
+            assert_eq!(s(js, "JSON.stringify({a: {toJSON: function() { return [1]; }}}, function(k, v) { return v; })"), r#"{"a":[1]}"#);
+            assert_eq!(s(js, "JSON.stringify({t: {toJSON: function() { throw 'no'; }}})"), "ERROR: no");
+
+            assert_eq!(s(js, "let c = {a: {}}; c.a.b = c; JSON.stringify(c)"), "ERROR: converting circular structure to JSON");
+            assert_eq!(s(js, "let r = [1]; r.push(r); JSON.stringify(r)"), "ERROR: converting circular structure to JSON");
+            assert_eq!(s(js, "let same = {v: 1}; JSON.stringify([same, same, {s: same}])"), r#"[{"v":1},{"v":1},{"s":{"v":1}}]"#);
+            assert_eq!(s(js, "let d = {}; for (let i = 0; i < 70; i++) d = {d: d}; JSON.stringify(d)"), "ERROR: JSON nested too deep");
+            assert_eq!(s(js, "JSON.parse(JSON.stringify({s: 'a\\u0000\\u001f\\\\', n: [0.1, -5]})).s === 'a\\u0000\\u001f\\\\'"), "true");
+        }).unwrap();
+    }
+
+    #[test]
+    fn json_gc() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            js.setgct(0);
+            ev(js, "function Box(v) { this.v = v; } Box.prototype.toJSON = function() { let junk = [1, 2, 3].map(function(x) { return 'j' + x; }); return {v: this.v, junk: junk.length}; };");
+            let text = r#"[{"v":1,"junk":3},{"k":{"v":"two","junk":3}},[{"v":3,"junk":3}],"end"]"#;
+            assert_eq!(ev(js, "JSON.stringify([new Box(1), {k: new Box('two')}, [new Box(3)], 'end'])"), format!("\"{}\"", text));
+            assert_eq!(ev(js, "JSON.stringify({a: 'x', b: ['y', 'z']}, function(k, v) { let junk = 'j' + k; return typeof v === 'string' ? v + v : v; })"), r#""{"a":"xx","b":["yy","zz"]}""#);
+            assert_eq!(ev(js, "let t = ''; for (let i = 0; i < 20; i++) t = JSON.stringify(JSON.parse('{\"n\": [' + i + ', \"s\\\\n\"]}')); t"), r#""{"n":[19,"s\n"]}""#);
+            assert!(js.heap().all(|e| e.corrupt.is_none()));
+        }).unwrap();
+    }
+
+    #[test]
+    fn json_api() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            let v = js.parse_json("{\"list\": [1, \"two\", {\"three\": 3}],\n \"ok\": true}").unwrap();
+            assert_eq!(js.str(v), "{\"list\":[1,\"two\",{\"three\":3}],\"ok\":true}");
+            let list = js.props(v).next().unwrap().1;
+            let two = js.get_elem(list, 1);
+            assert_eq!(js.get_str(two), Some("two"));
+
+            let e = js.parse_json("{\"a\": 1,\n  \"b\": 2\n  \"c\": 3}").unwrap_err();
+            assert_eq!((e.kind, e.message(), e.offset, e.line, e.column), (ErrorKind::Syntax, "unexpected '\"' in JSON at position 20", 20, 3, 3));
+            let e = js.parse_json("[\"é\", tru]").unwrap_err();
+            assert_eq!((e.message(), e.offset, e.column), ("unexpected 't' in JSON at position 6", 7, 7));
+
+            let json = js.to_json(v).unwrap();
+            assert_eq!(js.get_str(json), Some("{\"list\":[1,\"two\",{\"three\":3}],\"ok\":true}"));
+            let glob = js.glob();
+            js.set_object(glob, "v", v);
+            let cyc = js.eval("v.list.push(v); v").unwrap();
+            let e = js.to_json(cyc).unwrap_err();
+            assert_eq!((e.kind, e.message()), (ErrorKind::Type, "converting circular structure to JSON"));
+            assert_eq!((e.offset, e.line, e.column), (0, 0, 0));
+            assert_eq!(e.to_string(), "TypeError: converting circular structure to JSON");
+            let f = js.eval("(function() {})").unwrap();
+            assert_eq!(js.to_json(f).unwrap().kind(), Kind::Undefined);
+            let s = js.make_str("a\"b");
+            let json = js.to_json(s).unwrap();
+            assert_eq!(js.get_str(json), Some("\"a\\\"b\""));
+            let e = js.eval("let q = '\\u0001'; for (let i = 0; i < 10; i++) q += q; JSON.stringify(q)").unwrap_err();
+            assert_eq!(e.kind, ErrorKind::Oom);
+            let v = js.eval("q = 0; JSON.stringify([q])").unwrap();
+            assert_eq!(js.get_str(v), Some("[0]"));
+            let obj = js.eval("({toJSON: function() { return [1, 2]; }})").unwrap();
+            let json = js.to_json(obj).unwrap();
+            assert_eq!(js.get_str(json), Some("[1,2]"));
+        }).unwrap();
+    }
+
+    #[test]
+    fn unicode_strings() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let s = 'é😀z'; s.length"), "4");
+            assert_eq!(ev(js, "s[0] + s[3] + s.charCodeAt(1) + ',' + s.charCodeAt(2)"), "\"éz55357,56832\"");
+            assert_eq!(ev(js, "s[1] === '\\u{fffd}'"), "true");
+            assert_eq!(ev(js, "s.indexOf('z') + s.slice(1, 3) + s.slice(3)"), "\"3😀z\"");
+            assert_eq!(ev(js, "'ßé'.toUpperCase() + 'ÀÉ'.toLowerCase()"), "\"SSÉàé\"");
+            assert_eq!(ev(js, "'\\u00a0\\ufeffx\\u2028'.trim()"), "\"x\"");
+            assert_eq!(ev(js, "'aé'.split('')"), "[\"a\",\"é\"]");
+            assert_eq!(ev(js, "String.fromCharCode(55357, 56832, 233, 55296) === '😀é\\u{fffd}'"), "true");
+            assert_eq!(ev(js, "String.fromCharCode(65 + 65536)"), "\"A\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn array_errors() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let u; u[0]"), "ERROR: can't read members of undefined");
+            assert_eq!(ev(js, "null.x = 1"), "ERROR: can't set members of null");
+            assert_eq!(ev(js, "let n = 1; n.x = 1"), "ERROR: can't set members of number");
+            assert_eq!(ev(js, "n.x"), "undefined");
+            assert_eq!(ev(js, "let a = []; a.x = 1"), "ERROR: bad array index");
+            assert_eq!(ev(js, "a.length = -1"), "ERROR: invalid array length");
+            assert_eq!(ev(js, "a[0"), "ERROR: ] expected");
+            assert_eq!(ev(js, "a.'1'"), "ERROR: name expected");
+            assert_eq!(ev(js, "[1, 2"), "ERROR: ] expected");
+            assert_eq!(ev(js, "a[1e6] = 1"), "ERROR: oom");
+            assert_eq!(ev(js, "a.length"), "0");
+        }).unwrap();
+    }
+
+    #[test]
+    fn arrays_survive_gc() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            js.setgct(0);
+            ev(js, "let keep = ['a' + 'b', ['c' + 'd']];");
+            let code = "keep.map(function(x) { let junk = 'x' + x; return [junk, x + '!']; }).filter(function(p) { let junk = [p, p]; return p[1] !== 'ab!'; })";
+            assert_eq!(ev(js, code), "[[\"xcd\",\"cd!\"]]");
+            assert_eq!(ev(js, "keep.reduce(function(acc, x) { let junk = [acc]; return acc + x; }, '>')"), "\">abcd\"");
+            assert_eq!(ev(js, "let big = []; for (let i = 0; i < 50; i++) big.push('s' + i); big[49] + big.length"), "\"s4950\"");
+            assert!(js.stats().gc_runs > 10);
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn objects() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let o = {}; o"), "{}");
+            assert_eq!(ev(js, "o = {a: 1, 'b c': 'x', 2: true, if: null, }; o"), "{\"a\":1,\"b c\":\"x\",\"2\":true,\"if\":null}");
+            assert_eq!(ev(js, "o.a + o['a'] + (o[2] ? 1 : 0)"), "3");
+            assert_eq!(ev(js, "o['b' + ' c'] + o.if"), "\"xnull\"");
+            assert_eq!(ev(js, "o.nope"), "undefined");
+            assert_eq!(ev(js, "o.a = 5; o.z = [1]; o.z[1] = o.a++; o['k' + 1] = {}; o"), "{\"a\":6,\"b c\":\"x\",\"2\":true,\"if\":null,\"z\":[1,5],\"k1\":{}}");
+            assert_eq!(ev(js, "o.a += 4; o.a"), "10");
+            assert_eq!(ev(js, "({a: 1, a: 2}).a"), "2");
+            assert_eq!(ev(js, "let n = {x: {y: {z: 'deep'}}}; n.x.y.z"), "\"deep\"");
+            assert_eq!(ev(js, "n.x.y = 1; n"), "{\"x\":{\"y\":1}}");
+            assert_eq!(ev(js, "typeof o + typeof o.z"), "\"objectobject\"");
+            assert_eq!(ev(js, "let m = {f: function(x) { return x * 2; }}; m.f(21)"), "42");
+            assert_eq!(ev(js, "m['f'](1)"), "2");
+            assert_eq!(ev(js, "'' + o"), "\"[object Object]\"");
+            assert_eq!(ev(js, "let c = {}; c.c = c; c"), format!("{}{{...}}{}", "{\"c\":".repeat(JS_PRINT_DEPTH), "}".repeat(JS_PRINT_DEPTH)));
+            assert_eq!(ev(js, "{ let b = 1; }"), "undefined");
+            assert_eq!(ev(js, "({a: 1"), "ERROR: } expected");
+            assert_eq!(ev(js, "({a 1})"), "ERROR: : expected");
+            assert_eq!(ev(js, "({(: 1})"), "ERROR: bad property name");
+            js.setgct(0);
+            assert_eq!(ev(js, "let g = {s: 'a' + 'b', t: [1 + 1], u: {v: 'c' + 'd'}}; g"), "{\"s\":\"ab\",\"t\":[2],\"u\":{\"v\":\"cd\"}}");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn prototypes() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            ev(js, "let Point = function(x, y) { this.x = x; this.y = y; };");
+            ev(js, "Point.prototype.sum = function() { return this.x + this.y; };");
+            assert_eq!(ev(js, "let p = new Point(1, 2); p"), "{\"x\":1,\"y\":2}");
+            assert_eq!(ev(js, "p.sum()"), "3");
+            assert_eq!(ev(js, "p.constructor === Point"), "true");
+            assert_eq!(ev(js, "'sum' in p"), "true");
+            assert_eq!(ev(js, "p instanceof Point"), "true");
+            assert_eq!(ev(js, "({}) instanceof Point"), "false");
+            assert_eq!(ev(js, "1 instanceof Point"), "false");
+
+            // Reads follow the chain, writes go to the object itself
+            ev(js, "Point.prototype.z = 10;");
+            assert_eq!(ev(js, "p.z += 1; p.z + Point.prototype.z"), "21");
+            assert_eq!(ev(js, "delete p.z; p.z"), "10");
+            ev(js, "let Point3 = function(x, y, z) { this.x = x; this.y = y; this.z = z; };");
+            ev(js, "Point3.prototype = new Point(0, 0); Point3.prototype.sum3 = function() { return this.sum() + this.z; };");
+            assert_eq!(ev(js, "let q = new Point3(1, 2, 3); q.sum3()"), "6");
+            assert_eq!(ev(js, "(q instanceof Point3) + ':' + (q instanceof Point) + ':' + (p instanceof Point3)"), "\"true:true:false\"");
+
+            // A returned object replaces the new one, other results don't
+            assert_eq!(ev(js, "let F = function() { this.a = 1; return {b: 2}; }; new F()"), "{\"b\":2}");
+            assert_eq!(ev(js, "let G = function() { this.a = 1; return 5; }; new G"), "{\"a\":1}");
+            assert_eq!(ev(js, "let ns = {P: Point}; new ns.P(3, 4).sum()"), "7");
+            assert_eq!(ev(js, "new ns['P'](5, 5) instanceof Point"), "true");
+
+            // `this` is the object of a method call, undefined otherwise
+            assert_eq!(ev(js, "let o = {n: 5, get: function() { return this.n; }}; o.get()"), "5");
+            assert_eq!(ev(js, "let get = o.get; get()"), "ERROR: can't read members of undefined");
+            assert_eq!(ev(js, "this"), "undefined");
+            assert_eq!(ev(js, "let t = function() { return this; }; o.t = t; (t() === undefined) + ':' + (o.t() === o)"), "\"true:true\"");
+
+            assert_eq!(ev(js, "if (false) { new Point(1, 2).sum(); new ns.P; } this"), "undefined");
+            assert_eq!(ev(js, "new 1"), "ERROR: not a constructor");
+            assert_eq!(ev(js, "new o.n()"), "ERROR: not a constructor");
+            assert_eq!(ev(js, "p instanceof o"), "ERROR: 'instanceof' needs a constructor, got object");
+            js.setgct(0);
+            assert_eq!(ev(js, "let r = new Point3('a' + 'b', 'c', 'd'); r.sum3() + r.sum()"), "\"abcdabc\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn classes() {
+        let mut buf = [0u8; 16384];
+        Js::new(&mut buf, |js| {
+            ev(js, "class Animal { constructor(name) { this.name = name; } speak() { return this.name + ' makes a sound'; } static kind() { return 'animal'; } }");
+            assert_eq!(ev(js, "let a = new Animal('cat'); a.speak()"), "\"cat makes a sound\"");
+            assert_eq!(ev(js, "a"), "{\"name\":\"cat\"}");
+            assert_eq!(ev(js, "Animal.kind() + ':' + typeof Animal + ':' + (a instanceof Animal)"), "\"animal:function:true\"");
+            assert_eq!(ev(js, "a.constructor === Animal && Animal.prototype.speak === a.speak"), "true");
+
+            ev(js, "class Dog extends Animal { constructor(name, breed) { super(name); this.breed = breed; } speak() { return super.speak() + ', woof'; } static kind() { return 'dog, ' + super.kind(); } }");
+            assert_eq!(ev(js, "let d = new Dog('rex', 'pug'); d.speak()"), "\"rex makes a sound, woof\"");
+            assert_eq!(ev(js, "d"), "{\"name\":\"rex\",\"breed\":\"pug\"}");
+            assert_eq!(ev(js, "Dog.kind()"), "\"dog, animal\"");
+            assert_eq!(ev(js, "(d instanceof Dog) + ':' + (d instanceof Animal) + ':' + (a instanceof Dog)"), "\"true:true:false\"");
+
+            // The default constructor passes the arguments on to the base
+            ev(js, "class Puppy extends Dog { speak() { return super['speak']() + '!'; } }");
+            assert_eq!(ev(js, "let p = new Puppy('bo', 'lab'); p.speak() + ' ' + p.breed"), "\"bo makes a sound, woof! lab\"");
+            assert_eq!(ev(js, "Puppy.kind()"), "\"dog, animal\"");
+            assert_eq!(ev(js, "class Empty {}; new Empty()"), "{}");
+
+            // Expressions, static fields and methods named like keywords
+            assert_eq!(ev(js, "let C = class { static n = 40 + 2; static m; delete() { return C.n; } }; new C().delete()"), "42");
+            assert_eq!(ev(js, "'m' in C"), "true");
+            assert_eq!(ev(js, "let make = function(B) { return class extends B { hi() { return 'hi ' + this.name; } }; }; new (make(Animal))('ann').hi()"), "\"hi ann\"");
+            assert_eq!(ev(js, "if (false) { class X extends Nope { m() { super.m(); } } } 1"), "1");
+
+            // `super` doesn't go through variables scripts can name or shadow
+            ev(js, "class H extends Animal { speak() { let __home = {}, __super = 0; return super.speak(); } home() { return __home; } }");
+            assert_eq!(ev(js, "let h = new H('h'); h.speak()"), "\"h makes a sound\"");
+            assert_eq!(ev(js, "h.home()"), "ERROR: '__home' not found");
+
+            assert_eq!(ev(js, "class { }"), "ERROR: class name expected");
+            assert_eq!(ev(js, "class A extends 1 {}"), "ERROR: class extends value is not a constructor");
+            assert_eq!(ev(js, "class B { x = 1; }"), "ERROR: class fields must be static");
+            assert_eq!(ev(js, "class B { m }"), "ERROR: ( expected");
+            assert_eq!(ev(js, "class B { m() { return super.m(); } }; new B().m()"), "ERROR: 'super' outside of a derived class");
+            assert_eq!(ev(js, "super(1)"), "ERROR: 'super' outside of a derived class");
+
+            // Classes can only be called with `new`, which `super()` does
+            let no_new = "ERROR: Class constructor cannot be invoked without 'new'";
+            assert_eq!(ev(js, "Animal('x')"), no_new);
+            assert_eq!(ev(js, "Puppy()"), no_new);
+            assert_eq!(ev(js, "Empty()"), no_new);
+            assert_eq!(ev(js, "[1].map(Dog)"), no_new);
+            assert_eq!(ev(js, "let NoArgs = class { constructor() { this.k = 1; } }; new NoArgs"), "{\"k\":1}");
+
+            // Constructors and methods aren't enumerable
+            ev(js, "class Q { constructor() { this.x = 1; } m() { return 2; } static s() {} }; Q.prototype.e = 3; let ks;");
+            let keys = "ks = ''; for (let k in Q.prototype) ks += k + ','; for (let k in new Q()) ks += k + ','; ks";
+            assert_eq!(ev(js, keys), "\"e,x,\"");
+            assert_eq!(ev(js, "Q.prototype.constructor === Q && new Q().m() + Q.prototype.e"), "5");
+            js.setgct(0);
+            assert_eq!(ev(js, "class G extends Dog { constructor() { super('g' + 'g', 'b' + 'b'); } }; new G().speak() + new G().breed"), "\"gg makes a sound, woofbb\"");
+            assert_eq!(ev(js, keys), "\"e,x,\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn arrows_and_params() {
+        let mut buf = [0u8; 16384];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let sq = x => x * x; sq(7)"), "49");
+            assert_eq!(ev(js, "let add = (a, b) => a + b; add(2, 3)"), "5");
+            assert_eq!(ev(js, "let k = () => { let r = 1; return r + 1; }; k()"), "2");
+            assert_eq!(ev(js, "let nothing = () => {}; nothing()"), "undefined");
+            assert_eq!(ev(js, "[1, 2, 3].map(x => x * 2).filter((x, i) => i > 0)"), "[4,6]");
+            assert_eq!(ev(js, "let curry = a => b => c => a + b + c; curry(1)(2)(3)"), "6");
+            assert_eq!(ev(js, "let t = (1 + 2) * 3, u = (x => x)(t); u"), "9");
+            assert_eq!(ev(js, "typeof (() => 1)"), "\"function\"");
+
+            // Lexical `this`
+            ev(js, "let o = {n: 1, f: function() { return [1, 2].map(x => x + this.n); }, g: () => this};");
+            assert_eq!(ev(js, "o.f()"), "[2,3]");
+            assert_eq!(ev(js, "o.g()"), "undefined");
+            ev(js, "class Counter { constructor() { this.n = 0; this.inc = () => { this.n += 1; return this.n; }; } }");
+            assert_eq!(ev(js, "let c = new Counter(), inc = c.inc; inc(); inc()"), "2");
+            assert_eq!(ev(js, "new sq(1)"), "ERROR: not a constructor");
+            // The bound `this` isn't a property scripts can reach
+            assert_eq!(ev(js, "function pl() { return this; } pl.this = 5; pl.__this = 5; pl()"), "undefined");
+            assert_eq!(ev(js, "let ar = () => this, ks = ''; ar.this = 5; delete ar.this; for (let k in ar) ks += k; [ar(), ks]"), "[undefined,\"\"]");
+            assert_eq!(ev(js, "let fo = function() { return this; }; fo.this = 1; new fo() === fo.this"), "false");
+
+            // Defaults and rest parameters
+            assert_eq!(ev(js, "function f(a, b = a + 1, c = b * 2) { return [a, b, c]; } f(1)"), "[1,2,4]");
+            assert_eq!(ev(js, "f(1, 5) + ':' + f(1, undefined, 0)"), "\"1,5,10:1,2,0\"");
+            assert_eq!(ev(js, "let calls = 0; function g(x = calls++) { return x; } g(7) + calls"), "7");
+            assert_eq!(ev(js, "function r(a, ...rest) { return [a, rest.length, rest]; } r(1, 2, 3)"), "[1,2,[2,3]]");
+            assert_eq!(ev(js, "r()"), "[undefined,0,[]]");
+            assert_eq!(ev(js, "let sum = (...n) => n.reduce((s, x) => s + x, 0); sum(1, 2, 3, 4)"), "10");
+            assert_eq!(ev(js, "let d = (x, y = 10) => x + y; d(1) + d(1, 1)"), "13");
+            assert_eq!(ev(js, "let h = function(a = () => 5) { return a(); }; h() + h(() => 1)"), "6");
+
+            assert_eq!(ev(js, "function e(...a, b) {}"), "ERROR: rest parameter must be last");
+            assert_eq!(ev(js, "function e(1) {}"), "ERROR: identifier expected");
+            assert_eq!(ev(js, "(a, 1) => a"), "ERROR: identifier expected");
+            assert_eq!(ev(js, "let z = (a) => ;"), "ERROR: bad expr");
+            js.setgct(0);
+            assert_eq!(ev(js, "let j = (s = 'a' + 'b', ...more) => s + more.join(''); j() + j(undefined, 'c' + 'd')"), "\"ababcd\"");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+
+    #[test]
+    fn delete_and_in() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "let o = {a: 1, b: 2, c: 3}; delete o.b"), "true");
+            assert_eq!(ev(js, "o"), "{\"a\":1,\"c\":3}");
+            assert_eq!(ev(js, "delete o['a']; delete o.nope; o"), "{\"c\":3}");
+            assert_eq!(ev(js, "delete o.c; o.d = 4; o"), "{\"d\":4}");
+            assert_eq!(ev(js, "let a = [1, 2, 3]; delete a[1]; a"), "[1,,3]");
+            assert_eq!(ev(js, "delete a.length"), "false");
+            assert_eq!(ev(js, "delete o"), "false");
+            assert_eq!(ev(js, "delete 1"), "true");
+            assert_eq!(ev(js, "'d' in o"), "true");
+            assert_eq!(ev(js, "'a' in o"), "false");
+            assert_eq!(ev(js, "0 in a"), "true");
+            assert_eq!(ev(js, "1 in a"), "false");
+            assert_eq!(ev(js, "'2' in a"), "true");
+            assert_eq!(ev(js, "5 in a"), "false");
+            assert_eq!(ev(js, "'length' in a"), "true");
+            assert_eq!(ev(js, "'x' in 1"), "ERROR: 'in' needs an object, got number");
+            assert_eq!(ev(js, "let keys = ''; o.e = 5; delete o.d; for (let k in o) keys += k; keys"), "\"e\"");
+            assert_eq!(ev(js, "const k = 1; let g = {}; delete g.x"), "true");
+        }).unwrap();
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut buf = [0u8; 8192];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "fail", || Err::<f64, _>("host failure"));
+            ev(js, "let r = '';");
+            assert_eq!(ev(js, "try { throw 'x'; r = 'no'; } catch (e) { r = e; } r"), "\"x\"");
+            assert_eq!(ev(js, "try { nope; } catch (e) { r = e; } r"), "{\"name\":\"ReferenceError\",\"message\":\"'nope' not found\"}");
+            assert_eq!(ev(js, "try { 1 - 'a'; } catch (e) { r = e; } r"), "{\"name\":\"TypeError\",\"message\":\"type mismatch\"}");
+            assert_eq!(ev(js, "try { fail(); } catch (e) { r = e; } r"), "{\"name\":\"Error\",\"message\":\"host failure\"}");
+            assert_eq!(ev(js, "r = ''; try { r += 'a'; } catch (e) { r += 'b'; } finally { r += 'c'; } r"), "\"ac\"");
+            assert_eq!(ev(js, "r = ''; try { try { throw 1; } finally { r += 'f'; } } catch (e) { r += e; } r"), "\"f1\"");
+            assert_eq!(ev(js, "r = ''; try { throw 1; } catch { r += 'c'; throw 2; } finally { r += 'f'; }"), "ERROR: 2");
+            assert_eq!(ev(js, "r"), "\"cf\"");
+            assert_eq!(ev(js, "function f() { try { throw 'up'; } finally { r = 'fin'; } } try { f(); } catch (e) { r += e; } r"), "\"finup\"");
+            assert_eq!(ev(js, "function g() { try { return 1; } finally { r = 'g'; } } g() + r"), "\"1g\"");
+            assert_eq!(ev(js, "function h() { try { throw 1; } finally { return 2; } } h()"), "2");
+            assert_eq!(ev(js, "function k() { try { return 1; } finally { return 3; } } k()"), "3");
+            assert_eq!(ev(js, "r = 0; for (let i = 0; i < 5; i++) { try { if (i === 2) break; } finally { r++; } } r"), "3");
+            assert_eq!(ev(js, "try { { let z = 1; nope; } } catch (e) {} typeof z"), "ERROR: 'z' not found");
+            assert_eq!(ev(js, "try {} r"), "ERROR: catch or finally expected");
+            assert_eq!(ev(js, "if (0) { try { throw 1; } catch (e) { nope; } finally { nope; } } 1"), "1");
+        }).unwrap();
+    }
+
+    #[test]
+    fn uncaught() {
+        let mut buf = [0u8; 4096];
+        Js::new(&mut buf, |js| {
+            let e = js.eval("1;\n  throw 'boom';").unwrap_err();
+            assert_eq!((e.kind, e.message(), e.line, e.column), (ErrorKind::Thrown, "boom", 2, 3));
+            assert_eq!(e.to_string(), "Error: boom at 2:3");
+            ev(js, "let err; try { nope; } catch (e) { err = e; }");
+            assert_eq!(js.eval("throw err").unwrap_err().message(), "'nope' not found");
+            assert_eq!(js.eval("throw 1.5").unwrap_err().message(), "1.5");
+            // Syntax errors and exhausted resources can't be caught
+            assert_eq!(js.eval("try { 1 + ; } catch (e) {}").unwrap_err().kind, ErrorKind::Syntax);
+            js.setmaxss(32 * 1024);
+            assert_eq!(js.eval("function r() { return r(); } try { r(); } catch (e) {}").unwrap_err().kind, ErrorKind::StackOverflow);
+            let code = "try { let t = 'abcdefgh'; while (1) t = t + t; } catch (e) {} finally { 1; }";
+            assert_eq!(js.eval(code).unwrap_err().kind, ErrorKind::Oom);
+            assert_eq!(ev(js, "try { throw 1; } catch (e) { e + 1 }"), "undefined");
+        }).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            assert_eq!(ev(js, "nope"), "ERROR: 'nope' not found");
+            assert_eq!(ev(js, "1 2"), "ERROR: ; expected");
+            assert_eq!(ev(js, "(1"), "ERROR: ) expected");
+            assert_eq!(ev(js, "1 +"), "ERROR: bad expr");
+            assert_eq!(ev(js, "1 - 'a'"), "ERROR: type mismatch");
+            assert_eq!(ev(js, "{ 1"), "ERROR: } expected");
+            assert_eq!(ev(js, "with (1) {}"), "ERROR: 'with' not implemented");
+            assert_eq!(js.eval("1 +").unwrap_err().kind, ErrorKind::Syntax);
+            let e = js.eval("1 + 2 /* unterminated").unwrap_err();
+            assert_eq!((e.kind, e.offset), (ErrorKind::Syntax, 6));
+            assert_eq!(ev(js, "1 + 2 /* closed */"), "3");
+        }).unwrap();
+    }
+
+    #[test]
+    fn rust_values() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let v = js.eval("1 + 1").unwrap();
+            assert_eq!(v.kind(), Kind::Number);
+            assert_eq!(Js::get_num(v), 2.0);
+            assert!(Js::get_bool(js.eval("1 < 2").unwrap()));
+            let v = js.eval("'abc' + 'def'").unwrap();
+            assert_eq!(js.get_str(v), Some("abcdef"));
+            assert_eq!(js.get_str(Js::make_num(1.0)), None);
+            assert!(!Js::get_bool(Js::make_num(1.0)));
+            let s = js.make_str("hello");
+            assert_eq!(js.str(s), "\"hello\"");
+            assert_eq!(Js::get_num(Js::make_num(2.5)), 2.5);
+            assert_eq!(js.str(Js::make_null()), "null");
+
+            let arr = js.make_array();
+            assert_eq!(js.get_len(arr), Some(0));
+            let res = js.set_elem(arr, 2, s);
+            assert_eq!(js.get_str(res), Some("hello"));
+            assert_eq!(js.set_elem(arr, 0, Js::make_num(1.0)).kind(), Kind::Number);
+            assert_eq!(js.str(arr), "[1,,\"hello\"]");
+            assert_eq!(js.get_len(arr), Some(3));
+            let elem = js.get_elem(arr, 2);
+            assert_eq!(js.get_str(elem), Some("hello"));
+            assert_eq!(js.get_elem(arr, 1).kind(), Kind::Undefined);
+            assert_eq!(js.get_elem(arr, 3).kind(), Kind::Undefined);
+            assert_eq!(js.get_len(s), None);
+            assert!(js.set_elem(s, 0, s).is_err());
+            let v = js.eval("[1, 2, 3].map(function(x) { return x * 2; })").unwrap();
+            let elem = js.get_elem(v, 2);
+            assert_eq!(Js::get_num(elem), 6.0);
+
+            let obj = js.make_object();
+            assert_eq!(Js::get_num(js.set_object(obj, "n", Js::make_num(1.0))), 1.0);
+            js.set_object(obj, "s", s);
+            js.set_object(obj, "n", Js::make_num(2.0));
+            let glob = js.glob();
+            js.set_object(glob, "obj", obj);
+            assert_eq!(ev(js, "obj.n + obj.s"), "\"2hello\"");
+            let props: Vec<_> = js.props(obj).collect();
+            let props: Vec<_> = props.into_iter().map(|(k, v)| (js.str(k).to_string(), js.show(v).to_string())).collect();
+            assert_eq!(props, [("\"n\"".to_string(), "2".to_string()), ("\"s\"".to_string(), "\"hello\"".to_string())]);
+            assert_eq!(js.props(s).count(), 0);
+            assert!(js.set_object(s, "x", s).is_err());
+        }).unwrap();
+    }
+
+    #[test]
+    fn rooted_values() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let s = js.make_str("abc");
+            let arr = js.eval("let a = ['x' + 'y']; a").unwrap();
+            let obj = js.parse_json("{\"k\": [1]}").unwrap();
+            js.eval("let junk = 'garbage' + 'garbage'; a = 0; junk = 0;").unwrap();
+
+            // GC moves the values down over the garbage, but they stay alive
+            let brk = js.stats().brk;
+            js.gc();
+            assert!(js.stats().brk < brk);
+            assert_eq!(js.get_str(s), Some("abc"));
+            assert_eq!(js.str(arr), "[\"xy\"]");
+            assert_eq!(js.show(obj).to_string(), "{\"k\":[1]}");
+            let glob = js.glob();
+            js.set_object(glob, "s", s);
+            assert_eq!(ev(js, "s + a"), "\"abc0\"");
+            assert!(!js.dump().is_corrupt());
+
+            // Values made in a scope are released when it ends
+            let before = js.dump();
+            let (brk, free) = (before.brk, before.free);
+            js.scope(|js| {
+                for _ in 0..10 { js.eval("[]").unwrap(); }
+                let glob = js.glob();
+                let arr = js.make_array();
+                js.set_object(glob, "kept", arr);
+            });
+            js.gc();
+            let after = js.dump();
+            assert_eq!(free - after.free, after.brk - brk);
+            assert_eq!(ev(js, "kept"), "[]");
+        }).unwrap();
+    }
+
+    #[test]
+    fn stats() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let stats = js.stats();
+            assert_eq!((stats.total, stats.brk, stats.gc_runs), (js.mem.len(), 12, 0));
+            assert_eq!(stats.min_free, stats.total - 12);
+
+            js.eval("let s = 'abc' + 'def'; 'garbage' + s;").unwrap();
+            let before = js.stats();
+            assert!(before.brk > stats.brk && before.min_free <= before.total - before.brk);
+            assert!(before.peak_stack > 0);
+            js.gc();
+            let after = js.stats();
+            assert_eq!(after.gc_runs, 1);
+            assert_eq!(after.gc_reclaimed, before.brk - after.brk);
+            assert!(after.gc_reclaimed > 0);
+            assert_eq!(after.min_free, before.min_free);
+
+            // Deeper expressions need more native stack
+            ev(js, "1 + (2 + (3 + (4 + (5 + (6 + (7 + (8 + (9 + (10)))))))))");
+            assert!(js.stats().peak_stack > after.peak_stack);
+        }).unwrap();
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0u8; 64];
+        let err = Js::new(&mut buf, |_| ()).err().unwrap();
+        assert!(err.min > 64);
+        assert_eq!(err.to_string(), format!("buffer too small, need at least {} bytes", err.min));
+        let mut buf = vec![0u8; err.min];
+        assert!(Js::new(&mut buf, |_| ()).is_ok());
+    }
+
+    // Counts the heap allocations made by the current thread
+    struct Counter;
+
+    thread_local! {
+        static ALLOCS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for Counter {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            ALLOCS.with(|n| n.set(n.get() + 1));
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static COUNTER: Counter = Counter;
+
+    #[test]
+    fn eval_does_not_allocate() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "add", |a: f64, b: f64| a + b);
+            js.setgct(0);
+            let before = ALLOCS.with(|n| n.get());
+            for code in ["let s = 'a' + 1; s + s", "add(1, 2)", "add('x')", "nope", "{ let s = 1; let s = 2; }"] {
+                if let Ok(res) = js.eval(code) {
+                    js.str(res);
+                }
+            }
+            assert_eq!(ALLOCS.with(|n| n.get()), before);
+        }).unwrap();
+    }
+
+    #[test]
+    fn gc_reclaims_garbage() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            js.eval("let keep = 'abc';").unwrap();
+            let brk = js.brk;
+            for _ in 0..10 {
+                js.eval("'garbage' + 1; { let t = 'x' + 'y'; }").unwrap();
+            }
+            assert!(js.brk > brk);
+            js.gc();
+            assert_eq!(js.brk, brk);
+            assert_eq!(ev(js, "keep"), "\"abc\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn gc_keeps_live_values() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            js.eval("let a = 'x' + 'y'; 'trash'; let b = a + 'z'; 'more trash'; let c = 1;").unwrap();
+            js.gc();
+            assert_eq!(ev(js, "a + b + c"), "\"xyxyz1\"");
+            js.gc();
+            js.gc();
+            assert_eq!(ev(js, "let d = a; a = 2; d + a"), "\"xy2\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn gc_inside_nested_scopes() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            js.setgct(0);
+            let res = ev(js, "let s = 'o'; { let t = 'a' + 'b'; 'junk' + 'junk'; { let u = t + s; 'junk'; s = u; } t + s }");
+            assert_eq!(res, "\"ababo\"");
+            assert_eq!(ev(js, "s"), "\"abo\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn gc_stress_bounded_memory() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            js.eval("let s = '', n = 0;").unwrap();
+            for i in 0..5000 {
+                let res = js.eval("{ let t = 'abc' + 'def'; s = t + n; n += 1; }");
+                res.unwrap_or_else(|e| panic!("iteration {}: {}", i, e));
+                assert!(js.brk <= js.size);
+            }
+            assert_eq!(ev(js, "n"), "5000");
+            assert_eq!(ev(js, "s"), "\"abcdef4999\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn gc_without_room_for_relocation_table() {
+        // GC kicks in late, so the relocation table can't fit in the free
+        // memory and offsets get recomputed by walking the heap
+        let mut buf = [0u8; 536 + size_of::<Js<'static, 'static>>()];
+        Js::new(&mut buf, |js| {
+            js.setgct(js.size as isize - 20);
+            js.eval("let a = 'a', b = 'b', c = 'c', k = 0;").unwrap();
+            for _ in 0..2000 {
+                js.scope(|js| { js.eval("{ let t = a + b; 'x'; k = t + c; 'y'; }").unwrap(); });
+            }
+            assert_eq!(ev(js, "k + a + b + c"), "\"abcabc\"");
+        }).unwrap();
+    }
 }
@@ -0,0 +1,155 @@
+// Errors reported by `Js::eval`.
+//
+// While running, the engine propagates errors as `Type::ERR` values and
+// keeps the details in the Js header. `eval` turns them into a `JsError`
+// once the source is known, to compute the line and column.
+
+use core::fmt;
+
+use crate::core::*;
+
+/// What went wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The code can't be parsed
+    Syntax,
+    /// An operation got a value of the wrong type
+    Type,
+    /// An undeclared or invalid reference
+    Reference,
+    /// A value is out of the allowed range
+    Range,
+    /// The JS memory is exhausted
+    Oom,
+    /// The native stack limit set with `Js::setmaxss` is exceeded
+    StackOverflow,
+    /// An error raised by a script or by a Rust function
+    Thrown,
+}
+
+impl ErrorKind {
+    /// Name of the matching JS error constructor
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Syntax => "SyntaxError",
+            ErrorKind::Type => "TypeError",
+            ErrorKind::Reference => "ReferenceError",
+            ErrorKind::Range => "RangeError",
+            ErrorKind::Oom | ErrorKind::StackOverflow => "InternalError",
+            ErrorKind::Thrown => "Error",
+        }
+    }
+}
+
+/// Error returned by `Js::eval`, with the position of the offending token.
+/// Errors with no position in code, like those of `Js::to_json`, have 0
+/// for the offset, line and column
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct JsError {
+    pub kind: ErrorKind,
+    /// Byte offset in the evaluated code
+    pub offset: usize,
+    /// Line number, starting from 1, 0 if there is no position
+    pub line: usize,
+    /// Column number in characters, starting from 1, 0 if there is no
+    /// position
+    pub column: usize,
+    msg: [u8; JS_ERR_MAX],
+    msg_len: u8,
+}
+
+impl JsError {
+    pub(crate) fn new(kind: ErrorKind, msg: &str, code: &str, offset: usize) -> JsError {
+        let mut offset = offset.min(code.len());
+        while !code.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let before = &code[..offset];
+        let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+        JsError {
+            offset,
+            line: before.bytes().filter(|&c| c == b'\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            ..JsError::without_position(kind, msg)
+        }
+    }
+
+    // Error of an API call that isn't tied to any code
+    pub(crate) fn without_position(kind: ErrorKind, msg: &str) -> JsError {
+        let mut buf = [0u8; JS_ERR_MAX];
+        let len = msg.len().min(JS_ERR_MAX);
+        buf[..len].copy_from_slice(&msg.as_bytes()[..len]);
+        JsError { kind, offset: 0, line: 0, column: 0, msg: buf, msg_len: len as u8 }
+    }
+
+    /// Error message, without the kind or position
+    pub fn message(&self) -> &str {
+        // Messages are truncated at character boundaries, see `Js::mk_err`
+        core::str::from_utf8(&self.msg[..self.msg_len as usize]).unwrap_or("")
+    }
+}
+
+impl fmt::Display for JsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind.name(), self.message())?;
+        if self.line == 0 { return Ok(()) }
+        write!(f, " at {}:{}", self.line, self.column)
+    }
+}
+
+impl fmt::Debug for JsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsError")
+            .field("kind", &self.kind)
+            .field("message", &self.message())
+            .field("offset", &self.offset)
+            .field("line", &self.line)
+            .field("column", &self.column)
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for JsError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::elk::Js;
+
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let e = js.eval("1 +").unwrap_err();
+            assert_eq!((e.kind, e.message(), e.offset, e.line, e.column), (ErrorKind::Syntax, "bad expr", 3, 1, 4));
+            let e = js.eval("let a = 1;\nlet b = 'e' +;").unwrap_err();
+            assert_eq!((e.offset, e.line, e.column), (24, 2, 14));
+            let e = js.eval("let c = 'é';\nlet d = 'é' +;").unwrap_err();
+            assert_eq!((e.offset, e.line, e.column), (28, 2, 14));
+            let e = js.eval("let x = 1;\n  x = y;").unwrap_err();
+            assert_eq!((e.kind, e.message(), e.line, e.column), (ErrorKind::Reference, "'y' not found", 2, 7));
+            assert_eq!(e.to_string(), "ReferenceError: 'y' not found at 2:7");
+        }).unwrap();
+    }
+
+    #[test]
+    fn kinds() {
+        let mut buf = [0u8; 512];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "num", |x: f64| x);
+            js.set_fn(glob, "fail", || Err::<f64, _>("failed"));
+            let kind = |js: &mut Js, code: &str| js.eval(code).unwrap_err().kind;
+            assert_eq!(kind(js, "1 - 'a'"), ErrorKind::Type);
+            assert_eq!(kind(js, "num('a')"), ErrorKind::Type);
+            assert_eq!(kind(js, "1()"), ErrorKind::Type);
+            assert_eq!(kind(js, "fail()"), ErrorKind::Thrown);
+            assert_eq!(kind(js, "let q = 1; let q = 2;"), ErrorKind::Syntax);
+            assert_eq!(kind(js, "1 = 2"), ErrorKind::Syntax);
+            assert_eq!(kind(js, "let s = 'x'; with (s) {}"), ErrorKind::Syntax);
+            assert_eq!(kind(js, "let t = 'abcdefgh'; t = t + t + t + t + t + t + t + t + t + t"), ErrorKind::Oom);
+        }).unwrap();
+    }
+}
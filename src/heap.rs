@@ -0,0 +1,318 @@
+// Heap inspector, see `Js::heap` and `Js::dump`.
+//
+// Walks the used part of JS memory entity by entity, decoding each one by
+// the type tag in the two low bits of its first word, and checks that the
+// offsets stored in it point to entities of the right type. Checks don't
+// allocate: they walk the heap again from the start, so this is meant for
+// debugging rather than for hot paths.
+
+use core::fmt;
+
+use crate::core::*;
+use crate::value::RawValue;
+
+/// Decoded contents of a heap entity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntityKind<'j> {
+    /// Offsets of the first property (0 if none), of the parent scope and
+    /// of the prototype (0 if none)
+    Object { first_prop: usize, parent: usize, proto: usize },
+    /// Offsets of the next property (0 if last) and of the key string.
+    /// `constant` is set for variables declared with `const`, `hidden`
+    /// for properties `for..in` skips, like class methods, `internal` for
+    /// engine state scripts can't see, like the `this` of arrow functions
+    Prop { next: usize, key: usize, constant: bool, hidden: bool, internal: bool, value: RawValue },
+    /// String data without the terminating NUL. Native functions and
+    /// array elements are stored as strings too, so the data isn't always
+    /// text
+    Str { data: &'j [u8] },
+    /// Offset of the element storage string (0 if none) and the length
+    Array { data: usize, len: usize },
+    /// Entity that overruns the heap, the rest of it can't be decoded
+    Invalid { header: u32 },
+}
+
+/// An entity in the JS memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeapEntity<'j> {
+    /// Offset in the JS memory
+    pub offset: usize,
+    /// Size in bytes, including the header
+    pub size: usize,
+    pub kind: EntityKind<'j>,
+    /// What's wrong with the entity, if anything
+    pub corrupt: Option<&'static str>,
+}
+
+/// Iterator over the heap entities, returned by `Js::heap`
+pub struct Heap<'j> {
+    mem: &'j [u8],
+    off: usize,
+}
+
+impl<'j> Heap<'j> {
+    // `mem` is the used memory, up to `brk`
+    pub(crate) fn new(mem: &'j [u8]) -> Heap<'j> {
+        Heap { mem, off: 0 }
+    }
+
+    // Check that an entity of the given type starts at `off`
+    fn is_at(&self, off: usize, typ: Type) -> bool {
+        let mut n = 0;
+        while n < off && n + 4 <= self.mem.len() {
+            n += entity_size(load_off(self.mem, n) & !GC_MARK) as usize;
+        }
+        n == off && n + 4 <= self.mem.len() && entity_type(load_off(self.mem, n)) == typ
+    }
+
+    // Check that the entity a value points to, if any, is there
+    fn is_val_at(&self, v: JsVal) -> bool {
+        !is_entity(v) || self.is_at(v_data(v), entity_of(v))
+    }
+
+    // Check the offsets stored in the entity
+    fn check(&self, kind: &EntityKind<'j>) -> Option<&'static str> {
+        match *kind {
+            EntityKind::Object { first_prop, parent, proto } => {
+                if first_prop != 0 && !self.is_at(first_prop, Type::PROP) { return Some("bad first property offset") }
+                if parent != 0 && !self.is_at(parent, Type::OBJ) { return Some("bad parent offset") }
+                if proto != 0 && !self.is_at(proto, Type::OBJ) { return Some("bad prototype offset") }
+            },
+            EntityKind::Prop { next, key, value, .. } => {
+                if next != 0 && !self.is_at(next, Type::PROP) { return Some("bad next property offset") }
+                if !self.is_at(key, Type::STR) { return Some("bad key offset") }
+                if !self.is_val_at(value.0) { return Some("bad value offset") }
+            },
+            EntityKind::Array { data, len } => {
+                if data == 0 { return if len == 0 { None } else { Some("bad array length") } }
+                if !self.is_at(data, Type::STR) { return Some("bad array data offset") }
+                if (load_off(self.mem, data) >> 2) as usize <= len * 8 { return Some("bad array length") }
+                let bad = (0..len).any(|i| !self.is_val_at(load_val(self.mem, data + 4 + i * 8)));
+                if bad { return Some("bad element offset") }
+            },
+            EntityKind::Str { .. } | EntityKind::Invalid { .. } => (),
+        }
+        None
+    }
+}
+
+impl<'j> Iterator for Heap<'j> {
+    type Item = HeapEntity<'j>;
+
+    fn next(&mut self) -> Option<HeapEntity<'j>> {
+        let mem = self.mem;
+        let off = self.off;
+        if off + 4 > mem.len() {
+            if off >= mem.len() { return None }
+            self.off = mem.len();
+            let kind = EntityKind::Invalid { header: 0 };
+            return Some(HeapEntity { offset: off, size: mem.len() - off, kind, corrupt: Some("truncated entity") })
+        }
+
+        let header = load_off(mem, off);
+        let b = header & !GC_MARK;
+        let size = entity_size(b) as usize;
+        let mut corrupt = None;
+        let kind = if off + size > mem.len() {
+            corrupt = Some("entity overruns brk");
+            EntityKind::Invalid { header }
+        } else {
+            match entity_type(b) {
+                Type::OBJ => EntityKind::Object {
+                    first_prop: (b & !3) as usize,
+                    parent: (load_off(mem, off + 4) & !FN_FLAGS) as usize,
+                    proto: load_off(mem, off + 8) as usize,
+                },
+                Type::PROP => EntityKind::Prop {
+                    next: (b & !3) as usize,
+                    key: (load_off(mem, off + 4) & !PROP_FLAGS) as usize,
+                    constant: load_off(mem, off + 4) & CONST_PROP != 0,
+                    hidden: load_off(mem, off + 4) & HIDDEN_PROP != 0,
+                    internal: load_off(mem, off + 4) & INTERNAL_PROP != 0,
+                    value: RawValue(load_val(mem, off + 8)),
+                },
+                Type::ARR => EntityKind::Array {
+                    data: (b & !3) as usize,
+                    len: load_off(mem, off + 4) as usize,
+                },
+                _ => match ((b >> 2) as usize).checked_sub(1) {
+                    Some(len) => {
+                        if mem[off + 4 + len] != 0 { corrupt = Some("string not NUL-terminated") }
+                        EntityKind::Str { data: &mem[off + 4..off + 4 + len] }
+                    },
+                    None => {
+                        corrupt = Some("bad string length");
+                        EntityKind::Str { data: &[] }
+                    },
+                },
+            }
+        };
+
+        if let EntityKind::Invalid { .. } = kind {
+            // The size can't be trusted, so nothing after this is decodable
+            self.off = mem.len();
+            return Some(HeapEntity { offset: off, size: mem.len() - off, kind, corrupt })
+        }
+        if header & GC_MARK != 0 { corrupt = Some("stale GC mark") }
+        self.off = off + size;
+        Some(HeapEntity { offset: off, size, corrupt: corrupt.or_else(|| self.check(&kind)), kind })
+    }
+}
+
+impl fmt::Display for HeapEntity<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x} ", self.offset)?;
+        match self.kind {
+            EntityKind::Object { first_prop, parent, proto } => {
+                write!(f, "OBJ  first={:#06x} parent={:#06x} proto={:#06x}", first_prop, parent, proto)?;
+            },
+            EntityKind::Prop { next, key, constant, hidden, internal, value } => {
+                write!(f, "PROP next={:#06x} key={:#06x} value={:?}", next, key, value)?;
+                if constant { f.write_str(" const")? }
+                if hidden { f.write_str(" hidden")? }
+                if internal { f.write_str(" internal")? }
+            },
+            EntityKind::Str { data } => match core::str::from_utf8(data) {
+                Ok(s) => write!(f, "STR  len={} {:?}", data.len(), s)?,
+                Err(_) => write!(f, "STR  len={} <binary>", data.len())?,
+            },
+            EntityKind::Array { data, len } => write!(f, "ARR  data={:#06x} len={}", data, len)?,
+            EntityKind::Invalid { header } => write!(f, "???  header={:#010x} size={}", header, self.size)?,
+        }
+        if let Some(msg) = self.corrupt {
+            write!(f, "  !! {}", msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Heap listing returned by `Js::dump`. `Display` prints one entity per line
+#[cfg(any(feature = "std", test))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dump<'j> {
+    /// Used memory, in bytes
+    pub brk: usize,
+    /// Free memory between the heap and the native call stack, in bytes
+    pub free: usize,
+    pub entities: Vec<HeapEntity<'j>>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl Dump<'_> {
+    /// Check if any entity is corrupt
+    pub fn is_corrupt(&self) -> bool {
+        self.entities.iter().any(|e| e.corrupt.is_some())
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl fmt::Display for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "brk={:#06x} free={}", self.brk, self.free)?;
+        for e in &self.entities {
+            writeln!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elk::Js;
+
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            js.eval("let a = 1; const s = 'hi';").unwrap();
+            let dump = js.dump();
+            assert!(!dump.is_corrupt());
+            let lines = dump.to_string();
+            assert_eq!(lines.lines().collect::<Vec<_>>(), [
+                &format!("brk=0x0044 free={}", dump.free),
+                "0x0000 OBJ  first=0x0014 parent=0x0000 proto=0x0000",
+                "0x000c STR  len=1 \"a\"",
+                "0x0014 PROP next=0x0034 key=0x000c value=Number(1.0)",
+                "0x0024 STR  len=2 \"hi\"",
+                "0x002c STR  len=1 \"s\"",
+                "0x0034 PROP next=0x0000 key=0x002c value=String@0x24 const",
+            ][..]);
+        }).unwrap();
+    }
+
+    #[test]
+    fn arrays() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            js.eval("let a = [1, 'x'];").unwrap();
+            let dump = js.dump();
+            assert!(!dump.is_corrupt());
+            let lines = dump.to_string();
+            assert_eq!(lines.lines().collect::<Vec<_>>(), [
+                &format!("brk=0x005c free={}", dump.free),
+                "0x0000 OBJ  first=0x004c parent=0x0000 proto=0x0000",
+                "0x000c STR  len=1 \"x\"",
+                "0x0014 ARR  data=0x001c len=2",
+                "0x001c STR  len=32 <binary>",
+                "0x0044 STR  len=1 \"a\"",
+                "0x004c PROP next=0x0000 key=0x0044 value=Array@0x14",
+            ][..]);
+        }).unwrap();
+    }
+
+    #[test]
+    fn structure() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "f", || 1);
+            js.gc();
+            let entities: Vec<_> = js.heap().collect();
+            assert_eq!(entities.len(), 5);
+            assert_eq!(entities[0].kind, EntityKind::Object { first_prop: 0x30, parent: 0, proto: 0 });
+            assert!(matches!(entities[1].kind, EntityKind::Str { .. }));
+            // The native table, with the offset of the function
+            assert_eq!(entities[2].kind, EntityKind::Str { data: &[0x0c, 0, 0, 0] });
+            assert_eq!(entities[3].kind, EntityKind::Str { data: b"f" });
+            assert!(matches!(entities[4].kind, EntityKind::Prop { next: 0, key: 0x28, constant: false, hidden: false, internal: false, value } if value.kind() == crate::value::Kind::Function));
+            assert!(entities.iter().all(|e| e.corrupt.is_none()));
+            assert_eq!(entities.iter().map(|e| e.size).sum::<usize>(), js.stats().brk);
+        }).unwrap();
+    }
+
+    #[test]
+    fn corruption() {
+        // Build the entities by hand: a property pointing to garbage
+        let mut mem = [0u8; 52];
+        mem[..4].copy_from_slice(&12u32.to_le_bytes());
+        mem[8..12].copy_from_slice(&28u32.to_le_bytes());
+        mem[12..16].copy_from_slice(&(44u32 | 1).to_le_bytes());
+        mem[16..20].copy_from_slice(&28u32.to_le_bytes());
+        mem[20..28].copy_from_slice(&make_val(Type::STR, 4).to_le_bytes());
+        mem[28..32].copy_from_slice(&((2u32 << 2) | 2).to_le_bytes());
+        mem[32] = b'k';
+        mem[36..40].copy_from_slice(&((2u32 << 2) | 2).to_le_bytes());
+        mem[40..42].copy_from_slice(b"xy");
+        mem[44..48].copy_from_slice(&(12u32 | ARR_TAG).to_le_bytes());
+        mem[48..52].copy_from_slice(&1u32.to_le_bytes());
+        let entities: Vec<_> = Heap::new(&mem).collect();
+        let corrupt: Vec<_> = entities.iter().map(|e| (e.offset, e.corrupt)).collect();
+        assert_eq!(corrupt, [
+            (0, Some("bad prototype offset")),
+            (12, Some("bad next property offset")),
+            (28, None),
+            (36, Some("string not NUL-terminated")),
+            (44, Some("bad array data offset")),
+        ]);
+        assert_eq!(entities[4].to_string(), "0x002c ARR  data=0x000c len=1  !! bad array data offset");
+        let entities: Vec<_> = Heap::new(&mem[..48]).collect();
+        assert_eq!(entities[4].to_string(), "0x002c ???  header=0x0000000f size=4  !! entity overruns brk");
+
+        // A string without even the NUL
+        let mem = 2u32.to_le_bytes();
+        let corrupt: Vec<_> = Heap::new(&mem).map(|e| (e.offset, e.size, e.corrupt)).collect();
+        assert_eq!(corrupt, [(0, 4, Some("bad string length"))]);
+    }
+}
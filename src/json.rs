@@ -0,0 +1,608 @@
+// The `JSON` object: `JSON.parse` and `JSON.stringify`, also behind
+// `Js::parse_json` and `Js::to_json`.
+//
+// The parser builds objects, arrays and strings straight into JS memory.
+// It runs no JS code, so no GC, and the text can be read where it is even
+// when it is a JS string.
+//
+// The serializer writes its text right above `brk`, like string methods.
+// `toJSON` methods and a replacer function are JS code, which allocates
+// and may run GC: before calling one, the text so far is made a string
+// kept on the stack, and copied back above the new `brk` afterwards. The
+// objects and arrays being written are kept on the stack too, which is
+// also where cycles are looked for.
+
+use crate::builtin::{self, Member, Table};
+use crate::core::*;
+use crate::elk::Js;
+use crate::error::ErrorKind;
+
+pub(crate) const JSON: Table = Table { call: None, members: &[
+    ("parse", Member::Fn(parse)),
+    ("stringify", Member::Fn(stringify)),
+]};
+
+// Nesting of objects and arrays allowed in either direction
+const MAX_DEPTH: usize = 64;
+
+// Most characters `JSON.stringify` indents with, as in JS
+const MAX_GAP: usize = 10;
+
+fn oom(js: &mut Js<'_, '_>) -> JsVal {
+    js.mk_err(ErrorKind::Oom, "oom")
+}
+
+// `JSON.parse(text)`
+fn parse(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = js.stringify(js.arg(argv, argc, 0));
+    if is_err(s) { return s }
+    let (off, len) = js.v_str(s);
+    // Parsing only adds entities above the string and runs no GC, so it
+    // stays where it is
+    let bytes = unsafe { core::slice::from_raw_parts(js.mem_ptr(off), len as usize) };
+    let text = core::str::from_utf8(bytes).unwrap_or("");
+    // Bad data is no bug in the script, which can catch it
+    let res = parse_text(js, text, Some(off)).0;
+    if is_err(res) { js.throw_err(res) } else { res }
+}
+
+// Parse the JSON text, which is at offset `base` if it is in JS memory.
+// Returns the value, or the error and where in the text it was found
+pub(crate) fn parse_text(js: &mut Js<'_, '_>, text: &str, base: Option<JsOff>) -> (JsVal, usize) {
+    let mut p = Parser { text, base, pos: 0, depth: 0 };
+    let mut res = p.value(js);
+    if !is_err(res) {
+        p.skip_white();
+        if p.pos < text.len() { res = p.unexpected(js) }
+    }
+    (res, p.pos)
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    base: Option<JsOff>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_white(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    // SyntaxError for the character at the current position. Positions
+    // count characters, as in JS messages
+    fn unexpected(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        while !self.text.is_char_boundary(self.pos) {
+            self.pos -= 1;
+        }
+        match self.text[self.pos..].chars().next() {
+            None => js.mk_err(ErrorKind::Syntax, "unexpected end of JSON input"),
+            Some(c) => {
+                let at = self.text[..self.pos].chars().count();
+                let msg = "in JSON at position";
+                if c.is_control() {
+                    js.mk_err(ErrorKind::Syntax, format_args!("unexpected '{}' {} {}", c.escape_debug(), msg, at))
+                } else {
+                    js.mk_err(ErrorKind::Syntax, format_args!("unexpected '{}' {} {}", c, msg, at))
+                }
+            },
+        }
+    }
+
+    fn nest(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH { return js.mk_err(ErrorKind::Range, "JSON nested too deep") }
+        self.pos += 1;
+        self.skip_white();
+        make_undef()
+    }
+
+    fn value(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        self.skip_white();
+        match self.peek() {
+            Some(b'{') => self.object(js),
+            Some(b'[') => self.array(js),
+            Some(b'"') => self.string(js),
+            Some(b'-' | b'0'..=b'9') => self.number(js),
+            _ => self.literal(js),
+        }
+    }
+
+    fn literal(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        for (word, v) in [("true", make_bool(true)), ("false", make_bool(false)), ("null", make_null())] {
+            if self.text[self.pos..].starts_with(word) {
+                self.pos += word.len();
+                return v
+            }
+        }
+        self.unexpected(js)
+    }
+
+    // After a member or an element: a comma, or the closing `end`. Returns
+    // whether more follow
+    fn more(&mut self, js: &mut Js<'_, '_>, end: u8) -> Result<bool, JsVal> {
+        self.skip_white();
+        match self.peek() {
+            Some(b',') => {
+                self.pos += 1;
+                Ok(true)
+            },
+            Some(c) if c == end => {
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(false)
+            },
+            _ => Err(self.unexpected(js)),
+        }
+    }
+
+    fn object(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        let res = self.nest(js);
+        if is_err(res) { return res }
+        let obj = js.mk_obj(0);
+        if is_err(obj) { return obj }
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            self.depth -= 1;
+            return obj
+        }
+        loop {
+            self.skip_white();
+            if self.peek() != Some(b'"') { return self.unexpected(js) }
+            let k = self.string(js);
+            if is_err(k) { return k }
+            self.skip_white();
+            if self.peek() != Some(b':') { return self.unexpected(js) }
+            self.pos += 1;
+            let v = self.value(js);
+            if is_err(v) { return v }
+            // The last of repeated keys wins
+            let res = js.set_key(obj, k, v);
+            if is_err(res) { return res }
+            match self.more(js, b'}') {
+                Ok(true) => (),
+                Ok(false) => return obj,
+                Err(e) => return e,
+            }
+        }
+    }
+
+    fn array(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        let res = self.nest(js);
+        if is_err(res) { return res }
+        let arr = js.mk_arr(0);
+        if is_err(arr) { return arr }
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            self.depth -= 1;
+            return arr
+        }
+        loop {
+            let v = self.value(js);
+            if is_err(v) { return v }
+            let res = js.arr_set(arr, js.arr_len(arr), v);
+            if is_err(res) { return res }
+            match self.more(js, b']') {
+                Ok(true) => (),
+                Ok(false) => return arr,
+                Err(e) => return e,
+            }
+        }
+    }
+
+    // Check the string and its escapes, then copy it. Escapes are decoded
+    // right above `brk`, as the result is never longer than the text
+    fn string(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        self.pos += 1;
+        let start = self.pos;
+        let mut escaped = false;
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                None | Some(0..=0x1f) => return self.unexpected(js),
+                Some(b'\\') => {
+                    self.pos += 1;
+                    escaped = true;
+                    let n = match self.peek() {
+                        Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => 1,
+                        Some(b'u') => 5,
+                        _ => return self.unexpected(js),
+                    };
+                    for i in 1..n {
+                        if !self.text.as_bytes().get(self.pos + i).is_some_and(u8::is_ascii_hexdigit) {
+                            self.pos += i;
+                            return self.unexpected(js)
+                        }
+                    }
+                    self.pos += n;
+                },
+                _ => self.pos += 1,
+            }
+        }
+        let end = self.pos;
+        self.pos += 1;
+        if !escaped { return js.mk_str(&self.text[start..end]) }
+
+        let mut pos = js.str_start();
+        let from = pos;
+        let ok = match self.base {
+            Some(base) => js.put_mem(&mut pos, (base + start as JsOff, (end - start) as JsOff)),
+            None => js.put(&mut pos, &self.text.as_bytes()[start..end]),
+        };
+        if !ok { return oom(js) }
+        match unescape(&mut js.str_room()[..pos - from]) {
+            Ok(len) => js.take_str(from + len),
+            Err(msg) => js.mk_err(ErrorKind::Syntax, msg),
+        }
+    }
+
+    fn digits(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    // A number without leading zeros, and with digits on both sides of
+    // the dot
+    fn number(&mut self, js: &mut Js<'_, '_>) -> JsVal {
+        let start = self.pos;
+        if self.peek() == Some(b'-') { self.pos += 1 }
+        let int = if self.peek() == Some(b'0') {
+            self.pos += 1;
+            true
+        } else {
+            self.digits()
+        };
+        if !int { return self.unexpected(js) }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !self.digits() { return self.unexpected(js) }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() { self.pos += 1 }
+            if !self.digits() { return self.unexpected(js) }
+        }
+        tok_val(str_to_double(&self.text[start..self.pos]))
+    }
+}
+
+// `JSON.stringify(value, replacer, space)`
+fn stringify(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let (v, replacer, space) = (js.arg(argv, argc, 0), js.arg(argv, argc, 1), js.arg(argv, argc, 2));
+    to_json(js, v, replacer, space)
+}
+
+// The JSON text of the value, undefined if it has none. The replacer is a
+// function or a list of keys, `space` a number of spaces or a string to
+// indent with
+pub(crate) fn to_json(js: &mut Js<'_, '_>, v: JsVal, replacer: JsVal, space: JsVal) -> JsVal {
+    let mut out = Out { pos: 0, slot: 0, replacer: 0, holder: 0, keys: 0, path: 0, depth: 0, gap: [0; MAX_GAP * 4], gap_len: 0 };
+    match v_type(space) {
+        Type::NUM => {
+            // NaN becomes 0 too
+            out.gap_len = v_num(space).clamp(0.0, MAX_GAP as f64) as usize;
+            out.gap[..out.gap_len].fill(b' ');
+        },
+        Type::STR => {
+            let s = js.load_str(space);
+            let end = s.char_indices().nth(MAX_GAP).map_or(s.len(), |(i, _)| i);
+            out.gap[..end].copy_from_slice(&s.as_bytes()[..end]);
+            out.gap_len = end;
+        },
+        _ => (),
+    }
+
+    if v_type(replacer) == Type::ARR {
+        let keys = key_list(js, replacer);
+        let res = if is_err(keys) { keys } else { js.push(keys) };
+        if is_err(res) { return res }
+        out.keys = js.sp();
+    } else if callable(replacer) {
+        // The replacer first sees the value as the "" key of an object
+        let holder = js.mk_obj(0);
+        let k = if is_err(holder) { holder } else { js.mk_str("") };
+        let res = if is_err(k) { k } else { js.set_key(holder, k, v) };
+        if is_err(res) { return res }
+        let res = js.push(replacer);
+        if is_err(res) { return res }
+        out.replacer = js.sp();
+        let res = js.push(holder);
+        if is_err(res) { return res }
+        out.holder = js.sp();
+    }
+    let res = js.push(make_undef());
+    if is_err(res) { return res }
+    (out.slot, out.path, out.pos) = (js.sp(), js.sp(), js.str_start());
+
+    let v = out.resolve(js, out.holder, Key::Root, v);
+    if is_err(v) { return v }
+    if omitted(v) { return make_undef() }
+    let res = out.write(js, v);
+    if is_err(res) { return res }
+    js.take_str(out.pos)
+}
+
+// The property names of a replacer array: its strings and numbers, each
+// once
+fn key_list(js: &mut Js<'_, '_>, replacer: JsVal) -> JsVal {
+    let keys = js.mk_arr(0);
+    if is_err(keys) { return keys }
+    for i in 0..js.arr_len(replacer) {
+        let k = js.arr_get(replacer, i);
+        if !matches!(v_type(k), Type::STR | Type::NUM) { continue }
+        let k = js.stringify(k);
+        if is_err(k) { return k }
+        let len = js.arr_len(keys);
+        if (0..len).any(|j| js.strict_eq(js.arr_get(keys, j), k)) { continue }
+        let res = js.arr_set(keys, len, k);
+        if is_err(res) { return res }
+    }
+    keys
+}
+
+fn callable(v: JsVal) -> bool {
+    is_func(v) && (v_type(v) != Type::BUILTIN || builtin::is_callable(v))
+}
+
+// Values left out of objects, and written as null in arrays
+fn omitted(v: JsVal) -> bool {
+    v_type(v) == Type::UNDEF || callable(v)
+}
+
+// The key of the value being written, made a string only for callbacks
+#[derive(Clone, Copy)]
+enum Key {
+    Root,
+    Index(JsOff),
+    // The property on the stack at this slot
+    Prop(usize),
+    // Element of the replacer's key list
+    List(JsOff),
+}
+
+// The JSON text being written at `pos`
+struct Out {
+    pos: usize,
+    // Stack slot of the text while a callback runs
+    slot: usize,
+    // Stack slots of the replacer function and of the object holding the
+    // value, or of the key list, 0 if there are none
+    replacer: usize,
+    holder: usize,
+    keys: usize,
+    // Objects and arrays being written sit on the stack below `path`, each
+    // with a slot for the property being written
+    path: usize,
+    depth: usize,
+    gap: [u8; MAX_GAP * 4],
+    gap_len: usize,
+}
+
+impl Out {
+    // The value written for the key of the object or array on the stack
+    // at `holder`: what its `toJSON` method and the replacer make of it
+    fn resolve(&mut self, js: &mut Js<'_, '_>, holder: usize, key: Key, v: JsVal) -> JsVal {
+        let mut v = if v == HOLE { make_undef() } else { v };
+        if v_type(v) == Type::OBJ {
+            let f = js.get_prop(v, "toJSON");
+            if callable(f) { v = self.call(js, f, v, key, None) }
+            if is_err(v) { return v }
+        }
+        if self.replacer != 0 {
+            let (f, this) = (js.load_val(self.replacer), js.load_val(holder));
+            v = self.call(js, f, this, key, Some(v));
+        }
+        v
+    }
+
+    // Call `f` with the key and the value, if given. The text is kept on
+    // the stack meanwhile
+    fn call(&mut self, js: &mut Js<'_, '_>, f: JsVal, this: JsVal, key: Key, v: Option<JsVal>) -> JsVal {
+        let text = js.take_str(self.pos);
+        if is_err(text) { return text }
+        js.save_val(self.slot, text);
+        let k = match key {
+            Key::Root => js.mk_str(""),
+            Key::Index(i) => js.stringify(tok_val(i as f64)),
+            Key::Prop(slot) => js.prop_name(v_data(js.load_val(slot)) as JsOff),
+            Key::List(i) => js.arr_get(js.load_val(self.keys), i),
+        };
+        if is_err(k) { return k }
+        let res = match v {
+            Some(v) => js.call_fn(f, this, &[k, v]),
+            None => js.call_fn(f, this, &[k]),
+        };
+        if is_err(res) { return res }
+
+        self.pos = js.str_start();
+        let text = js.v_str(js.load_val(self.slot));
+        if !js.put_mem(&mut self.pos, text) { return oom(js) }
+        res
+    }
+
+    fn write(&mut self, js: &mut Js<'_, '_>, v: JsVal) -> JsVal {
+        let ok = match v_type(v) {
+            Type::NULL => js.put(&mut self.pos, b"null"),
+            Type::BOOL => js.put(&mut self.pos, if v_data(v) != 0 { b"true" } else { b"false" }),
+            Type::NUM if v_num(v).is_finite() => {
+                let mut buf = [0u8; 32];
+                let mut out = Buf::new(&mut buf);
+                let _ = fmt_num(v_num(v), &mut out);
+                let len = out.len();
+                js.put(&mut self.pos, &buf[..len])
+            },
+            Type::NUM => js.put(&mut self.pos, b"null"),
+            Type::STR => quote(js, &mut self.pos, v),
+            Type::ARR => return self.array(js, v),
+            Type::OBJ => return self.object(js, v),
+            // Built-in objects like `Math`
+            _ => js.put(&mut self.pos, b"{}"),
+        };
+        if ok { make_undef() } else { oom(js) }
+    }
+
+    // Put the object or array on the path, unless it is already there
+    fn enter(&mut self, js: &mut Js<'_, '_>, v: JsVal) -> JsVal {
+        if self.depth >= MAX_DEPTH { return js.mk_err(ErrorKind::Range, "JSON nested too deep") }
+        if (0..self.depth).any(|i| js.load_val(self.path - 8 - i * 16) == v) {
+            return js.mk_err(ErrorKind::Type, "converting circular structure to JSON")
+        }
+        // The stack must not grow into the text
+        if self.pos + 16 >= js.sp() { return oom(js) }
+        js.push(v);
+        js.push(make_undef());
+        self.depth += 1;
+        make_undef()
+    }
+
+    fn leave(&mut self, js: &mut Js<'_, '_>) {
+        js.pop();
+        js.pop();
+        self.depth -= 1;
+    }
+
+    // Start a line at the nesting depth, when indenting
+    fn newline(&mut self, js: &mut Js<'_, '_>, depth: usize) -> bool {
+        if self.gap_len == 0 { return true }
+        js.put(&mut self.pos, b"\n") && (0..depth).all(|_| js.put(&mut self.pos, &self.gap[..self.gap_len]))
+    }
+
+    fn array(&mut self, js: &mut Js<'_, '_>, arr: JsVal) -> JsVal {
+        let res = self.enter(js, arr);
+        if is_err(res) { return res }
+        let (slot, depth) = (js.sp() + 8, self.depth);
+        if !js.put(&mut self.pos, b"[") { return oom(js) }
+
+        // Callbacks may change the array
+        let mut i = 0;
+        while i < js.arr_len(js.load_val(slot)) {
+            let v = js.arr_get(js.load_val(slot), i);
+            let v = self.resolve(js, slot, Key::Index(i), v);
+            if is_err(v) { return v }
+            if !((i == 0 || js.put(&mut self.pos, b",")) && self.newline(js, depth)) { return oom(js) }
+            let res = if omitted(v) {
+                if js.put(&mut self.pos, b"null") { make_undef() } else { oom(js) }
+            } else {
+                self.write(js, v)
+            };
+            if is_err(res) { return res }
+            i += 1;
+        }
+        if !((i == 0 || self.newline(js, depth - 1)) && js.put(&mut self.pos, b"]")) { return oom(js) }
+        self.leave(js);
+        make_undef()
+    }
+
+    // Own properties in creation order, or the keys of the replacer list
+    // in its order
+    fn object(&mut self, js: &mut Js<'_, '_>, obj: JsVal) -> JsVal {
+        let res = self.enter(js, obj);
+        if is_err(res) { return res }
+        let (slot, depth) = (js.sp() + 8, self.depth);
+        if !js.put(&mut self.pos, b"{") { return oom(js) }
+
+        let mut n = 0;
+        if self.keys != 0 {
+            let mut i = 0;
+            while i < js.arr_len(js.load_val(self.keys)) {
+                let k = js.arr_get(js.load_val(self.keys), i);
+                let v = js.get_prop(js.load_val(slot), js.load_str(k));
+                let v = self.resolve(js, slot, Key::List(i), v);
+                if is_err(v) { return v }
+                if !omitted(v) {
+                    let res = self.member(js, depth, n, js.arr_get(js.load_val(self.keys), i), v);
+                    if is_err(res) { return res }
+                    n += 1;
+                }
+                i += 1;
+            }
+        } else {
+            // The property being written, where GC can move it
+            let cursor = slot - 8;
+            js.save_val(cursor, make_val(Type::PROP, js.first_prop(obj) as u64));
+            while v_data(js.load_val(cursor)) != 0 {
+                let v = js.load_val(v_data(js.load_val(cursor)) + 8);
+                let v = self.resolve(js, slot, Key::Prop(cursor), v);
+                if is_err(v) { return v }
+                if !omitted(v) {
+                    let k = js.prop_name(v_data(js.load_val(cursor)) as JsOff);
+                    let res = self.member(js, depth, n, k, v);
+                    if is_err(res) { return res }
+                    n += 1;
+                }
+                let next = js.next_prop(v_data(js.load_val(cursor)) as JsOff);
+                js.save_val(cursor, make_val(Type::PROP, next as u64));
+            }
+        }
+        if !((n == 0 || self.newline(js, depth - 1)) && js.put(&mut self.pos, b"}")) { return oom(js) }
+        self.leave(js);
+        make_undef()
+    }
+
+    // Write member `n` of an object
+    fn member(&mut self, js: &mut Js<'_, '_>, depth: usize, n: usize, k: JsVal, v: JsVal) -> JsVal {
+        let colon: &[u8] = if self.gap_len > 0 { b": " } else { b":" };
+        let ok = (n == 0 || js.put(&mut self.pos, b","))
+            && self.newline(js, depth)
+            && quote(js, &mut self.pos, k)
+            && js.put(&mut self.pos, colon);
+        if !ok { return oom(js) }
+        self.write(js, v)
+    }
+}
+
+// The escape of a byte in a JSON string, and its length, if it needs one
+fn escape(c: u8) -> Option<([u8; 6], usize)> {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let short = |c: u8| Some(([b'\\', c, 0, 0, 0, 0], 2));
+    match c {
+        b'"' | b'\\' => short(c),
+        b'\n' => short(b'n'),
+        b'\r' => short(b'r'),
+        b'\t' => short(b't'),
+        0x08 => short(b'b'),
+        0x0c => short(b'f'),
+        0..=0x1f => Some(([b'\\', b'u', b'0', b'0', HEX[c as usize >> 4], HEX[c as usize & 15]], 6)),
+        _ => None,
+    }
+}
+
+// Write the string in double quotes. It is copied first, then its escapes
+// are spread out in place from the end
+fn quote(js: &mut Js<'_, '_>, pos: &mut usize, s: JsVal) -> bool {
+    if !js.put(pos, b"\"") { return false }
+    let start = *pos;
+    if !js.put_mem(pos, js.v_str(s)) { return false }
+    let base = js.str_start();
+    let room = js.str_room();
+    let (from, to) = (start - base, *pos - base);
+    let extra: usize = room[from..to].iter().filter_map(|&c| escape(c)).map(|(_, n)| n - 1).sum();
+    if to + extra >= room.len() { return false }
+
+    let (mut r, mut w) = (to, to + extra);
+    while r > from && w > r {
+        r -= 1;
+        match escape(room[r]) {
+            Some((esc, n)) => {
+                w -= n;
+                room[w..w + n].copy_from_slice(&esc[..n]);
+            },
+            None => {
+                w -= 1;
+                room[w] = room[r];
+            },
+        }
+    }
+    *pos += extra;
+    js.put(pos, b"\"")
+}
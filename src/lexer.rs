@@ -0,0 +1,120 @@
+// The engine tokenizer, for tools like linters and syntax highlighters.
+//
+// It is the same code `Js::eval` runs, so tokens and keywords always match
+// what the engine accepts.
+
+use crate::core::*;
+
+pub use crate::core::Token;
+
+/// Byte range of a token in the source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Iterator over the tokens of a script, as `(Token, Span)` pairs. Tokens
+/// the engine can't parse, like an unterminated string or comment or a stray
+/// character, come back as `Token::ERR`
+pub struct Lexer<'c> {
+    code: &'c str,
+    pos: usize,
+    trivia: bool,
+}
+
+impl<'c> Lexer<'c> {
+    /// Create a lexer that skips whitespace and comments
+    pub fn new(code: &'c str) -> Lexer<'c> {
+        Lexer { code, pos: 0, trivia: false }
+    }
+
+    /// Create a lexer that also yields whitespace and comments, as
+    /// `Token::SPACE` and `Token::COMMENT`
+    pub fn with_trivia(code: &'c str) -> Lexer<'c> {
+        Lexer { code, pos: 0, trivia: true }
+    }
+
+    /// Source text of a token
+    pub fn text(&self, span: Span) -> &'c str {
+        &self.code[span.start..span.end]
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let code = self.code.as_bytes();
+        let start = if self.trivia { self.pos } else { skip_to_next(code, self.pos as JsOff) as usize };
+        let buf = &code[start..];
+        let (tok, len) = match scan_trivia(buf) {
+            Some(trivia) if self.trivia => trivia,
+            _ => scan(buf),
+        };
+        if tok == Token::EOF { return None }
+        self.pos = start + len as usize;
+        Some((tok, Span { start, end: self.pos }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(lexer: Lexer<'_>) -> Vec<(Token, &str)> {
+        let code = lexer.code;
+        lexer.map(|(tok, span)| (tok, &code[span.start..span.end])).collect()
+    }
+
+    #[test]
+    fn tokens_and_spans() {
+        let mut lexer = Lexer::new("let x = 'é'; // done");
+        assert_eq!(lexer.next(), Some((Token::LET, Span { start: 0, end: 3 })));
+        assert_eq!(lexer.next(), Some((Token::IDENTIFIER, Span { start: 4, end: 5 })));
+        assert_eq!(lexer.next(), Some((Token::ASSIGN, Span { start: 6, end: 7 })));
+        let (tok, span) = lexer.next().unwrap();
+        assert_eq!((tok, lexer.text(span)), (Token::STRING, "'é'"));
+        assert_eq!(lexer.next(), Some((Token::SEMICOLON, Span { start: 12, end: 13 })));
+        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.next(), None);
+    }
+
+    #[test]
+    fn punctuators() {
+        assert_eq!(tokens(Lexer::new("(...r) => a.b === c")), [
+            (Token::LPAREN, "("), (Token::ELLIPSIS, "..."), (Token::IDENTIFIER, "r"), (Token::RPAREN, ")"),
+            (Token::ARROW, "=>"), (Token::IDENTIFIER, "a"), (Token::DOT, "."), (Token::IDENTIFIER, "b"),
+            (Token::EQ, "==="), (Token::IDENTIFIER, "c"),
+        ]);
+        assert_eq!(tokens(Lexer::new("a..b")), [
+            (Token::IDENTIFIER, "a"), (Token::DOT, "."), (Token::DOT, "."), (Token::IDENTIFIER, "b"),
+        ]);
+    }
+
+    #[test]
+    fn trivia() {
+        assert_eq!(tokens(Lexer::with_trivia("if (a) /* b */ c;\n// d")), [
+            (Token::IF, "if"), (Token::SPACE, " "), (Token::LPAREN, "("), (Token::IDENTIFIER, "a"),
+            (Token::RPAREN, ")"), (Token::SPACE, " "), (Token::COMMENT, "/* b */"), (Token::SPACE, " "),
+            (Token::IDENTIFIER, "c"), (Token::SEMICOLON, ";"), (Token::SPACE, "\n"), (Token::COMMENT, "// d"),
+        ]);
+        assert_eq!(tokens(Lexer::with_trivia("a /* b")), [
+            (Token::IDENTIFIER, "a"), (Token::SPACE, " "), (Token::ERR, "/* b"),
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(tokens(Lexer::new("x = 'abc;\ny")), [
+            (Token::IDENTIFIER, "x"), (Token::ASSIGN, "="), (Token::ERR, "'abc;\ny"),
+        ]);
+        let mut lexer = Lexer::new("a # b");
+        lexer.next();
+        assert_eq!(lexer.next(), Some((Token::ERR, Span { start: 2, end: 3 })));
+        assert_eq!(lexer.next(), Some((Token::IDENTIFIER, Span { start: 4, end: 5 })));
+        assert_eq!(tokens(Lexer::new("while instanceof typeof")), [
+            (Token::WHILE, "while"), (Token::INSTANCEOF, "instanceof"), (Token::TYPEOF, "typeof"),
+        ]);
+    }
+}
@@ -15,6 +15,19 @@
 // Alternatively, you can license this software under a commercial
 // license, please contact us at https://cesanta.com/contact.html
 
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
 pub mod elk;
+pub mod error;
+pub mod heap;
+pub mod lexer;
+pub mod native;
+pub mod value;
+mod array;
+mod builtin;
 mod core;
+mod json;
+mod math;
+mod number;
+mod string;
 
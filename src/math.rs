@@ -0,0 +1,385 @@
+// Floating point functions that `core` doesn't provide.
+//
+// With the `std` feature they forward to the standard library, otherwise
+// the portable versions from `soft` are used. Those are accurate to a few
+// units in the last place, which is plenty for a scripting engine.
+
+#[cfg(feature = "std")]
+mod imp {
+    pub(crate) fn trunc(x: f64) -> f64 {
+        x.trunc()
+    }
+
+    pub(crate) fn pow(x: f64, y: f64) -> f64 {
+        x.powf(y)
+    }
+
+    pub(crate) fn floor(x: f64) -> f64 {
+        x.floor()
+    }
+
+    pub(crate) fn ceil(x: f64) -> f64 {
+        x.ceil()
+    }
+
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+
+    pub(crate) fn cbrt(x: f64) -> f64 {
+        x.cbrt()
+    }
+
+    pub(crate) fn exp(x: f64) -> f64 {
+        x.exp()
+    }
+
+    pub(crate) fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+
+    pub(crate) fn log2(x: f64) -> f64 {
+        x.log2()
+    }
+
+    pub(crate) fn log10(x: f64) -> f64 {
+        x.log10()
+    }
+
+    pub(crate) fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    pub(crate) fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+
+    pub(crate) fn tan(x: f64) -> f64 {
+        x.tan()
+    }
+
+    pub(crate) fn asin(x: f64) -> f64 {
+        x.asin()
+    }
+
+    pub(crate) fn acos(x: f64) -> f64 {
+        x.acos()
+    }
+
+    pub(crate) fn atan(x: f64) -> f64 {
+        x.atan()
+    }
+
+    pub(crate) fn atan2(y: f64, x: f64) -> f64 {
+        y.atan2(x)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+use soft as imp;
+
+pub(crate) use imp::{acos, asin, atan, atan2, cbrt, ceil, cos, exp, floor, ln, log10, log2, pow, sin, sqrt, tan, trunc};
+
+// `x ** y` as JS has it: unlike in IEEE pow, 1 ** NaN and 1 ** Infinity
+// are NaN
+pub(crate) fn js_pow(x: f64, y: f64) -> f64 {
+    if y.is_nan() || (x.abs() == 1.0 && y.is_infinite()) { f64::NAN } else { pow(x, y) }
+}
+
+#[cfg_attr(feature = "std", allow(dead_code))]
+mod soft {
+    use core::f64::consts::{FRAC_PI_2, FRAC_PI_4, FRAC_PI_6, LN_10, PI};
+
+    const LN2: f64 = core::f64::consts::LN_2;
+    const TWO52: f64 = 4503599627370496.0;
+    // pi / 2 in three parts short enough that multiples of them are exact
+    const PIO2_1: f64 = f64::from_bits(0x3ff9_21fb_5440_0000);
+    const PIO2_2: f64 = f64::from_bits(0x3dd0_b461_1a60_0000);
+    const PIO2_3: f64 = f64::from_bits(0x3ba3_198a_2e00_0000);
+
+    pub(crate) fn trunc(x: f64) -> f64 {
+        // Anything that big has no fraction, NaN and infinities included
+        if x.is_nan() || x.abs() >= TWO52 { return x }
+        (x as i64 as f64).copysign(x)
+    }
+
+    pub(crate) fn floor(x: f64) -> f64 {
+        let t = trunc(x);
+        if t > x { t - 1.0 } else { t }
+    }
+
+    pub(crate) fn ceil(x: f64) -> f64 {
+        let t = trunc(x);
+        if t < x { t + 1.0 } else { t }
+    }
+
+    fn is_int(x: f64) -> bool {
+        trunc(x) == x
+    }
+
+    fn is_odd(x: f64) -> bool {
+        x.abs() < 2.0 * TWO52 && is_int(x) && trunc(x / 2.0) * 2.0 != x
+    }
+
+    // x * 2^n, for n in the range of normal exponents
+    fn scale(x: f64, n: i32) -> f64 {
+        let mut x = x;
+        let mut n = n;
+        while n > 1023 { x *= f64::from_bits(0x7fe0_0000_0000_0000); n -= 1023; }
+        while n < -1022 { x *= f64::from_bits(0x0010_0000_0000_0000); n += 1022; }
+        x * f64::from_bits(((n + 1023) as u64) << 52)
+    }
+
+    pub(crate) fn exp(x: f64) -> f64 {
+        if x.is_nan() { return x }
+        if x > 709.8 { return f64::INFINITY }
+        if x < -745.2 { return 0.0 }
+        // exp(x) = 2^k * exp(r), |r| <= ln2 / 2
+        let k = trunc(x / LN2 + if x < 0.0 { -0.5 } else { 0.5 });
+        let r = x - k * LN2;
+        let (mut sum, mut term, mut i) = (1.0f64, 1.0f64, 1.0);
+        while term.abs() > 1e-17 * sum {
+            term *= r / i;
+            sum += term;
+            i += 1.0;
+        }
+        scale(sum, k as i32)
+    }
+
+    pub(crate) fn ln(x: f64) -> f64 {
+        if x.is_nan() || x < 0.0 { return f64::NAN }
+        if x == 0.0 { return f64::NEG_INFINITY }
+        if x.is_infinite() { return x }
+        // x = m * 2^e, sqrt(1/2) <= m < sqrt(2)
+        let (mut m, mut e) = (x, 0);
+        if m < f64::MIN_POSITIVE { m *= TWO52; e -= 52; }
+        let bits = m.to_bits();
+        e += ((bits >> 52) & 0x7ff) as i32 - 1023;
+        m = f64::from_bits((bits & !(0x7ff << 52)) | (1023 << 52));
+        if m > core::f64::consts::SQRT_2 { m /= 2.0; e += 1; }
+        // ln(m) = 2 * atanh(s) = 2 * (s + s^3 / 3 + s^5 / 5 + ...)
+        let s = (m - 1.0) / (m + 1.0);
+        let (s2, mut term, mut sum, mut i) = (s * s, s, 0.0, 1.0);
+        while term.abs() > 1e-17 {
+            sum += term / i;
+            term *= s2;
+            i += 2.0;
+        }
+        2.0 * sum + e as f64 * LN2
+    }
+
+    pub(crate) fn pow(x: f64, y: f64) -> f64 {
+        if y == 0.0 || x == 1.0 { return 1.0 }
+        if x.is_nan() || y.is_nan() { return f64::NAN }
+        if y.is_infinite() {
+            let a = x.abs();
+            return if a == 1.0 { 1.0 } else if (a > 1.0) == (y > 0.0) { f64::INFINITY } else { 0.0 };
+        }
+        if x.is_infinite() || x == 0.0 {
+            // Both are handled as their reciprocals
+            let big = x.is_infinite() == (y > 0.0);
+            let r = if big { f64::INFINITY } else { 0.0 };
+            return if x.is_sign_negative() && is_odd(y) { -r } else { r };
+        }
+        if x < 0.0 && !is_int(y) { return f64::NAN }
+
+        let r = if is_int(y) && y.abs() < 1073741824.0 {
+            // Exponentiation by squaring is exact for small integers
+            let (mut b, mut n, mut r) = (x.abs(), y.abs() as u32, 1.0);
+            while n > 0 {
+                if n & 1 == 1 { r *= b; }
+                b *= b;
+                n >>= 1;
+            }
+            if y < 0.0 { 1.0 / r } else { r }
+        } else {
+            exp(y * ln(x.abs()))
+        };
+        if x < 0.0 && is_odd(y) { -r } else { r }
+    }
+
+    // Newton's iterations from a guess made by dividing the exponent
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        if x.is_nan() || x < 0.0 { return f64::NAN }
+        if x == 0.0 || x.is_infinite() { return x }
+        if x < f64::MIN_POSITIVE { return sqrt(x * TWO52 * TWO52) / TWO52 }
+        let mut y = f64::from_bits((x.to_bits() >> 1) + (1023 << 51));
+        for _ in 0..6 {
+            y = 0.5 * (y + x / y);
+        }
+        y
+    }
+
+    pub(crate) fn cbrt(x: f64) -> f64 {
+        if x.is_nan() || x == 0.0 || x.is_infinite() { return x }
+        if x < 0.0 { return -cbrt(-x) }
+        if x < f64::MIN_POSITIVE { return cbrt(x * TWO52 * TWO52 * TWO52) / TWO52 }
+        let mut y = f64::from_bits(x.to_bits() / 3 + 0x2a9f_7893_782d_a1ce);
+        for _ in 0..6 {
+            y = (2.0 * y + x / (y * y)) / 3.0;
+        }
+        y
+    }
+
+    pub(crate) fn log2(x: f64) -> f64 {
+        if x.is_nan() || x <= 0.0 || x.is_infinite() { return ln(x) }
+        // Exact for powers of two
+        let bits = x.to_bits();
+        let e = ((bits >> 52) & 0x7ff) as i32 - 1023;
+        if e == -1023 { return ln(x) / LN2 }
+        let m = f64::from_bits((bits & !(0x7ff << 52)) | (1023 << 52));
+        e as f64 + ln(m) / LN2
+    }
+
+    pub(crate) fn log10(x: f64) -> f64 {
+        let r = ln(x) / LN_10;
+        // Exact for powers of ten
+        let n = trunc(r + if r < 0.0 { -0.5 } else { 0.5 });
+        if n.abs() <= 22.0 && pow(10.0, n) == x { n } else { r }
+    }
+
+    // x = r + k * pi / 2 with |r| <= pi / 4. Only the quadrant k mod 4
+    // matters. Precision drops for huge x
+    fn reduce(x: f64) -> (f64, i64) {
+        let k = trunc(x / FRAC_PI_2 + if x < 0.0 { -0.5 } else { 0.5 });
+        let r = ((x - k * PIO2_1) - k * PIO2_2) - k * PIO2_3;
+        (r, (k % 4.0) as i64 & 3)
+    }
+
+    // sin and cos of |r| <= pi / 4, by their Taylor series
+    fn sin_cos(r: f64) -> (f64, f64) {
+        let r2 = r * r;
+        let (mut s, mut c) = (r, 1.0);
+        let (mut ts, mut tc, mut i) = (r, 1.0f64, 1.0);
+        while tc.abs() > 1e-18 || ts.abs() > 1e-18 * s.abs() {
+            tc *= -r2 / (i * (i + 1.0));
+            ts *= -r2 / ((i + 1.0) * (i + 2.0));
+            c += tc;
+            s += ts;
+            i += 2.0;
+        }
+        (s, c)
+    }
+
+    pub(crate) fn sin(x: f64) -> f64 {
+        if x == 0.0 { return x }
+        if !x.is_finite() { return f64::NAN }
+        let (r, k) = reduce(x);
+        let (s, c) = sin_cos(r);
+        match k { 0 => s, 1 => c, 2 => -s, _ => -c }
+    }
+
+    pub(crate) fn cos(x: f64) -> f64 {
+        if !x.is_finite() { return f64::NAN }
+        let (r, k) = reduce(x);
+        let (s, c) = sin_cos(r);
+        match k { 0 => c, 1 => -s, 2 => -c, _ => s }
+    }
+
+    pub(crate) fn tan(x: f64) -> f64 {
+        if x == 0.0 { return x }
+        if !x.is_finite() { return f64::NAN }
+        let (r, k) = reduce(x);
+        let (s, c) = sin_cos(r);
+        if k & 1 == 0 { s / c } else { -c / s }
+    }
+
+    pub(crate) fn atan(x: f64) -> f64 {
+        if x.is_nan() || x == 0.0 { return x }
+        if x < 0.0 { return -atan(-x) }
+        if x == 1.0 { return FRAC_PI_4 }
+        if x > 1.0 { return FRAC_PI_2 - atan(1.0 / x) }
+        // atan(x) = pi / 6 + atan((x * sqrt(3) - 1) / (x + sqrt(3)))
+        const SQRT3: f64 = 1.7320508075688772;
+        if x > 0.2679491924311227 { return FRAC_PI_6 + atan((x * SQRT3 - 1.0) / (x + SQRT3)) }
+        let x2 = x * x;
+        let (mut sum, mut term, mut i) = (0.0, x, 1.0);
+        while term.abs() > 1e-18 * x {
+            sum += term / i;
+            term *= -x2;
+            i += 2.0;
+        }
+        sum
+    }
+
+    pub(crate) fn atan2(y: f64, x: f64) -> f64 {
+        if x.is_nan() || y.is_nan() { return f64::NAN }
+        if x.is_infinite() && y.is_infinite() {
+            let a = if x > 0.0 { FRAC_PI_4 } else { 3.0 * FRAC_PI_4 };
+            return a.copysign(y)
+        }
+        if y == 0.0 || x.is_infinite() {
+            return if x.is_sign_positive() { 0.0f64.copysign(y) } else { PI.copysign(y) }
+        }
+        if x == 0.0 || y.is_infinite() { return FRAC_PI_2.copysign(y) }
+        let a = atan(y / x);
+        if x > 0.0 { a } else if y > 0.0 { a + PI } else { a - PI }
+    }
+
+    pub(crate) fn asin(x: f64) -> f64 {
+        if x == 0.0 { return x }
+        atan2(x, sqrt((1.0 - x) * (1.0 + x)))
+    }
+
+    pub(crate) fn acos(x: f64) -> f64 {
+        atan2(sqrt((1.0 - x) * (1.0 + x)), x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::soft;
+
+    fn close(a: f64, b: f64) -> bool {
+        a == b || (a.is_nan() && b.is_nan()) || ((a - b) / b).abs() < 1e-13
+    }
+
+    #[test]
+    fn soft_matches_std() {
+        let xs = [0.0, -0.0, 0.5, -0.5, 1.0, -1.0, 1.5, -2.5, 3.0, 10.0, 1e-300, 1e300, 123.456, -7.0,
+                  f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 4503599627370497.0];
+        for x in xs {
+            assert!(close(soft::trunc(x), x.trunc()), "trunc {}", x);
+            assert_eq!(soft::trunc(x).is_sign_negative(), x.trunc().is_sign_negative(), "trunc {}", x);
+            for y in xs {
+                assert!(close(soft::pow(x, y), x.powf(y)), "pow {} {}: {} {}", x, y, soft::pow(x, y), x.powf(y));
+            }
+        }
+        for x in [1e-310, 0.1, 0.7, 1.0, 2.0, 2.5, 100.0, 1e10, 1e308] {
+            assert!(close(soft::ln(x), x.ln()), "ln {}", x);
+        }
+        for x in [-745.0, -20.0, -1.0, -0.1, 0.0, 0.3, 1.0, 10.0, 700.0] {
+            assert!(close(soft::exp(x), x.exp()), "exp {}", x);
+        }
+    }
+
+    #[test]
+    fn soft_functions() {
+        let xs = [0.0, -0.0, 1e-310, 1e-5, 0.1, -0.3, 0.5, 0.7, -1.0, 1.0, 1.5, -2.5, 3.0, 10.0, 100.0, -1234.5,
+                  1e10, 1e300, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+        for x in xs {
+            let one = [
+                ("floor", soft::floor(x), x.floor()), ("ceil", soft::ceil(x), x.ceil()),
+                ("sqrt", soft::sqrt(x), x.sqrt()), ("cbrt", soft::cbrt(x), x.cbrt()),
+                ("log2", soft::log2(x), x.log2()), ("log10", soft::log10(x), x.log10()),
+                ("atan", soft::atan(x), x.atan()), ("asin", soft::asin(x), x.asin()),
+                ("acos", soft::acos(x), x.acos()),
+            ];
+            for (name, a, b) in one {
+                assert!(close(a, b) && (a.is_nan() || a.is_sign_negative() == b.is_sign_negative()), "{} {}: {} {}", name, x, a, b);
+            }
+            if x.abs() < 1e6 || !x.is_finite() {
+                for (name, a, b) in [("sin", soft::sin(x), x.sin()), ("cos", soft::cos(x), x.cos()), ("tan", soft::tan(x), x.tan())] {
+                    assert!(close(a, b) || (a - b).abs() < 1e-15, "{} {}: {} {}", name, x, a, b);
+                }
+            }
+            for y in xs {
+                assert!(close(soft::atan2(y, x), y.atan2(x)), "atan2 {} {}: {} {}", y, x, soft::atan2(y, x), y.atan2(x));
+            }
+        }
+        for n in 0..=22 {
+            assert_eq!(soft::log10(10f64.powi(n)), n as f64);
+        }
+        assert_eq!((soft::sqrt(16.0), soft::cbrt(-27.0), soft::log2(1024.0)), (4.0, -3.0, 10.0));
+    }
+}
@@ -0,0 +1,346 @@
+// Rust functions callable from Js.
+//
+// A native function is stored in JS memory as an opaque string entity that
+// holds a trampoline pointer followed by the bytes of the Rust closure, and
+// is listed in the instance's native table. Its Js value is the index in
+// that table, so scripts can't forge one. The trampoline is monomorphized
+// for the closure type: it copies the closure out, converts the arguments
+// from the stack at the top of memory, calls it and converts the result
+// back.
+
+use core::fmt::Display;
+use core::mem::size_of;
+
+use crate::core::*;
+use crate::elk::Js;
+use crate::error::ErrorKind;
+use crate::value::*;
+
+pub(crate) type Trampoline<'a> = for<'id> fn(&mut Js<'a, 'id>, JsOff, JsOff, usize) -> JsVal;
+
+pub(crate) const TRAMPOLINE_SIZE: usize = size_of::<usize>();
+
+/// Conversion of a Js value into a Rust function argument
+pub trait FromJs: Sized {
+    /// What the argument should be, for error messages
+    const EXPECTED: &'static str;
+
+    /// The argument as passed to the function, borrowing the engine for
+    /// the duration of the call. Owned types are `Self`
+    type Arg<'c>;
+
+    fn from_js<'c, 'id>(js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<Self::Arg<'c>>;
+}
+
+/// Conversion of a Rust function result into a Js value
+pub trait IntoJs {
+    fn into_js<'id>(self, js: &mut Js<'_, 'id>) -> Value<'id>;
+}
+
+/// A Rust closure that can be called from Js, see `Js::set_fn`
+pub trait NativeFn<'a, Args>: Copy + 'a {
+    /// Call with `argc` arguments taken from the stack, the first one at `argv`
+    fn call(&self, js: &mut Js<'a, '_>, argv: JsOff, argc: usize) -> JsVal;
+}
+
+// A value argument is only valid for the call, like a borrowed one: the
+// function can look at it but not keep it
+impl FromJs for Value<'_> {
+    const EXPECTED: &'static str = "value";
+    type Arg<'c> = Value<'c>;
+
+    fn from_js<'c, 'id>(_js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<Value<'c>> {
+        Some(Value::from_raw(val.raw()))
+    }
+}
+
+impl FromJs for f64 {
+    const EXPECTED: &'static str = "number";
+    type Arg<'c> = Self;
+
+    fn from_js<'c, 'id>(_js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<Self> {
+        (val.kind() == Kind::Number).then(|| Js::get_num(val))
+    }
+}
+
+macro_rules! from_js_int {
+    ($($t:ty),*) => {
+        $(impl FromJs for $t {
+            const EXPECTED: &'static str = "number";
+            type Arg<'c> = Self;
+
+            fn from_js<'c, 'id>(_js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<Self> {
+                (val.kind() == Kind::Number).then(|| Js::get_num(val) as $t)
+            }
+        })*
+    };
+}
+
+from_js_int!(i32, u32, i64, usize);
+
+impl FromJs for bool {
+    const EXPECTED: &'static str = "boolean";
+    type Arg<'c> = Self;
+
+    fn from_js<'c, 'id>(_js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<Self> {
+        (val.kind() == Kind::Boolean).then(|| Js::get_bool(val))
+    }
+}
+
+// The string borrows the engine only for the call: the function must be
+// callable with any lifetime, so it can't keep the string past it, when GC
+// may move or free it
+impl FromJs for &str {
+    const EXPECTED: &'static str = "string";
+    type Arg<'c> = &'c str;
+
+    fn from_js<'c, 'id>(js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<&'c str> {
+        js.get_str(val)
+    }
+}
+
+impl<T: FromJs> FromJs for Option<T> {
+    const EXPECTED: &'static str = T::EXPECTED;
+    type Arg<'c> = Option<T::Arg<'c>>;
+
+    fn from_js<'c, 'id>(js: &'c Js<'c, 'id>, val: Value<'id>) -> Option<Self::Arg<'c>> {
+        match val.kind() {
+            Kind::Undefined | Kind::Null => Some(None),
+            _ => T::from_js(js, val).map(Some),
+        }
+    }
+}
+
+impl IntoJs for () {
+    fn into_js<'id>(self, _js: &mut Js<'_, 'id>) -> Value<'id> {
+        Js::make_undef()
+    }
+}
+
+impl IntoJs for bool {
+    fn into_js<'id>(self, _js: &mut Js<'_, 'id>) -> Value<'id> {
+        if self { Js::make_true() } else { Js::make_false() }
+    }
+}
+
+macro_rules! into_js_num {
+    ($($t:ty),*) => {
+        $(impl IntoJs for $t {
+            fn into_js<'id>(self, _js: &mut Js<'_, 'id>) -> Value<'id> {
+                Js::make_num(self as f64)
+            }
+        })*
+    };
+}
+
+into_js_num!(f64, f32, i32, u32, i64, usize);
+
+impl IntoJs for &str {
+    fn into_js<'id>(self, js: &mut Js<'_, 'id>) -> Value<'id> {
+        js.make_str(self)
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl IntoJs for String {
+    fn into_js<'id>(self, js: &mut Js<'_, 'id>) -> Value<'id> {
+        js.make_str(&self)
+    }
+}
+
+impl<T: IntoJs> IntoJs for Option<T> {
+    fn into_js<'id>(self, js: &mut Js<'_, 'id>) -> Value<'id> {
+        match self {
+            Some(v) => v.into_js(js),
+            None => Js::make_null(),
+        }
+    }
+}
+
+impl<T: IntoJs, E: Display> IntoJs for Result<T, E> {
+    fn into_js<'id>(self, js: &mut Js<'_, 'id>) -> Value<'id> {
+        match self {
+            Ok(v) => v.into_js(js),
+            Err(e) => Value::from_raw(js.mk_err(ErrorKind::Thrown, e)),
+        }
+    }
+}
+
+macro_rules! native_fn {
+    ($($arg:ident),*) => {
+        impl<'a, F, R, $($arg,)*> NativeFn<'a, ($($arg,)*)> for F
+        where
+            // The first bound lets the argument types be inferred from the
+            // closure, the second one makes it take borrows of any lifetime
+            F: Fn($($arg),*) -> R + for<'c> Fn($($arg::Arg<'c>),*) -> R + Copy + 'a,
+            R: IntoJs,
+            $($arg: FromJs,)*
+        {
+            #[allow(non_snake_case, unused_variables, unused_mut, unused_assignments)]
+            fn call(&self, js: &mut Js<'a, '_>, argv: JsOff, argc: usize) -> JsVal {
+                // Converts the arguments and calls `f` while `js` is borrowed,
+                // on error returns the argument index and what was expected
+                fn run<'c, 'id, F, R, $($arg: FromJs,)*>(
+                    f: &F, js: &'c Js<'c, 'id>, argv: JsOff, argc: usize,
+                ) -> Result<R, (usize, &'static str)>
+                where
+                    F: Fn($($arg::Arg<'c>),*) -> R,
+                {
+                    let mut i = 0;
+                    $(
+                        let $arg = $arg::from_js(js, js.arg_val(argv, argc, i)).ok_or((i, $arg::EXPECTED))?;
+                        i += 1;
+                    )*
+                    Ok(f($($arg),*))
+                }
+                match run::<F, R, $($arg,)*>(self, js, argv, argc) {
+                    Ok(res) => {
+                        let res = res.into_js(js);
+                        js.rooted(res)
+                    },
+                    Err((i, expected)) => js.mk_err(ErrorKind::Type, format_args!("argument {}: {} expected", i + 1, expected)),
+                }
+            }
+        }
+    };
+}
+
+native_fn!();
+native_fn!(A);
+native_fn!(A, B);
+native_fn!(A, B, C);
+native_fn!(A, B, C, D);
+native_fn!(A, B, C, D, E);
+native_fn!(A, B, C, D, E, G);
+
+pub(crate) fn trampoline<'a, Args, F: NativeFn<'a, Args>>(js: &mut Js<'a, '_>, data: JsOff, argv: JsOff, argc: usize) -> JsVal {
+    // Closures are Copy, so a bitwise copy out of the JS memory is a valid
+    // value of its own. The bytes are not aligned, hence read_unaligned
+    let f: F = unsafe { core::ptr::read_unaligned(js.mem_ptr(data) as *const F) };
+    f.call(js, argv, argc)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    fn check(x: f64, s: &str) -> bool {
+        x > 1.0 && s == "ok"
+    }
+
+    fn ev(js: &mut Js, code: &str) -> String {
+        js.scope(|js| match js.eval(code) {
+            Ok(res) => js.str(res).to_string(),
+            Err(e) => format!("ERROR: {}", e.message()),
+        })
+    }
+
+    #[test]
+    fn plain_functions() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "check", check);
+            js.set_fn(glob, "add", |a: f64, b: f64| a + b);
+            js.set_fn(glob, "hello", || "hello");
+            assert_eq!(ev(js, "check(2, 'ok')"), "true");
+            assert_eq!(ev(js, "check(0, 'ok')"), "false");
+            assert_eq!(ev(js, "add(1, 2) * 2"), "6");
+            assert_eq!(ev(js, "add(add(1, 2), add(3, 4))"), "10");
+            assert_eq!(ev(js, "hello() + '!'"), "\"hello!\"");
+            assert_eq!(ev(js, "typeof add"), "\"function\"");
+            assert_eq!(ev(js, "add"), "function");
+        }).unwrap();
+    }
+
+    #[test]
+    fn closures() {
+        let mut buf = [0u8; 2048];
+        let calls = Cell::new(0);
+        Js::new(&mut buf, |js| {
+            let scale = 3.0;
+            let glob = js.glob();
+            js.set_fn(glob, "mul", move |x: f64| x * scale);
+            js.set_fn(glob, "count", |n: i32| calls.set(calls.get() + n));
+            js.set_fn(glob, "upper", |s: &str| s.to_uppercase());
+            assert_eq!(ev(js, "mul(2)"), "6");
+            assert_eq!(ev(js, "count(2); count(3)"), "undefined");
+            assert_eq!(calls.get(), 5);
+            assert_eq!(ev(js, "upper('abc')"), "\"ABC\"");
+        }).unwrap();
+    }
+
+    #[test]
+    fn argument_errors() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "check", check);
+            js.set_fn(glob, "opt", |a: Option<f64>| a.unwrap_or(-1.0));
+            js.set_fn(glob, "fail", |ok: bool| if ok { Ok(1) } else { Err("nope") });
+            assert_eq!(ev(js, "check('a', 'ok')"), "ERROR: argument 1: number expected");
+            assert_eq!(ev(js, "check(2, 3)"), "ERROR: argument 2: string expected");
+            assert_eq!(ev(js, "check(2)"), "ERROR: argument 2: string expected");
+            assert_eq!(ev(js, "check(2, 'ok', 'extra')"), "true");
+            assert_eq!(ev(js, "opt()"), "-1");
+            assert_eq!(ev(js, "opt(null)"), "-1");
+            assert_eq!(ev(js, "opt(5)"), "5");
+            assert_eq!(ev(js, "fail(true)"), "1");
+            assert_eq!(ev(js, "fail(false)"), "ERROR: nope");
+            assert_eq!(ev(js, "let x = 1; x()"), "ERROR: calling non-function");
+            assert_eq!(ev(js, "check(1, 'ok'"), "ERROR: ) expected");
+        }).unwrap();
+    }
+
+    #[test]
+    fn survives_gc() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.setgct(0);
+            js.eval("let s = 'x' + 'y';").unwrap();
+            js.set_fn(glob, "cat", |a: &str, b: &str| format!("{}{}", a, b));
+            for _ in 0..100 {
+                assert_eq!(ev(js, "'junk' + 1; { let t = cat(s, 'z'); s = cat('x', 'y'); t }"), "\"xyz\"");
+            }
+        }).unwrap();
+    }
+
+    #[test]
+    fn arguments_kept_across_gc() {
+        let mut buf = [0u8; 1024];
+        let kept = core::cell::RefCell::new(Vec::new());
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            js.set_fn(glob, "keep", |s: &str| kept.borrow_mut().push(s.to_string()));
+            for i in 0..20 {
+                ev(js, &format!("keep('k' + {}); 'junk' + {};", i, i));
+                js.gc();
+            }
+        }).unwrap();
+        let kept = kept.into_inner();
+        assert_eq!(kept.len(), 20);
+        assert!(kept.iter().enumerate().all(|(i, s)| *s == format!("k{}", i)));
+    }
+
+    #[test]
+    fn unforgeable() {
+        let mut buf = [0u8; 2048];
+        Js::new(&mut buf, |js| {
+            let glob = js.glob();
+            let f = js.make_fun(|x: f64| x + 1.0);
+            js.gc();
+            // Strings made after GC can't pass for the function
+            js.eval("let s = 'AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA' + 'AAAAAAAAAAAAAAAA';").unwrap();
+            js.set_object(glob, "f", f);
+            assert_eq!(ev(js, "f(1)"), "2");
+            assert_eq!(ev(js, "s(1)"), "ERROR: calling non-function");
+            js.set_fn(glob, "g", |s: &str| s.len());
+            js.gc();
+            assert_eq!(ev(js, "f(g('abc'))"), "4");
+            assert!(!js.dump().is_corrupt());
+        }).unwrap();
+    }
+}
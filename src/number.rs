@@ -0,0 +1,335 @@
+// Number built-ins: the `Number` and `Math` objects, number methods like
+// `x.toFixed(2)`, and the global `parseInt`, `parseFloat`, `isNaN` and
+// `isFinite`.
+//
+// Arguments are converted to numbers as JS does, see `Js::number_of`, so
+// `Math.max('2', [3])` works even though arithmetic operators don't mix
+// types. New strings are written right above `brk`, like string methods.
+
+use core::f64::consts;
+use core::fmt::{self, Write};
+
+use crate::builtin::{Member, Table};
+use crate::core::*;
+use crate::elk::Js;
+use crate::error::ErrorKind;
+use crate::math;
+
+pub(crate) const METHODS: Table = Table { call: None, members: &[
+    ("toFixed", Member::Fn(to_fixed)),
+    ("toString", Member::Fn(to_string)),
+]};
+
+pub(crate) const NUMBER: Table = Table { call: Some(number), members: &[
+    ("MAX_SAFE_INTEGER", Member::Num(9007199254740991.0)),
+    ("MIN_SAFE_INTEGER", Member::Num(-9007199254740991.0)),
+    ("EPSILON", Member::Num(f64::EPSILON)),
+    ("MAX_VALUE", Member::Num(f64::MAX)),
+    ("MIN_VALUE", Member::Num(f64::from_bits(1))),
+    ("POSITIVE_INFINITY", Member::Num(f64::INFINITY)),
+    ("NEGATIVE_INFINITY", Member::Num(f64::NEG_INFINITY)),
+    ("NaN", Member::Num(f64::NAN)),
+]};
+
+pub(crate) const MATH: Table = Table { call: None, members: &[
+    ("E", Member::Num(consts::E)),
+    ("LN10", Member::Num(consts::LN_10)),
+    ("LN2", Member::Num(consts::LN_2)),
+    ("LOG10E", Member::Num(consts::LOG10_E)),
+    ("LOG2E", Member::Num(consts::LOG2_E)),
+    ("PI", Member::Num(consts::PI)),
+    ("SQRT1_2", Member::Num(consts::FRAC_1_SQRT_2)),
+    ("SQRT2", Member::Num(consts::SQRT_2)),
+    ("abs", Member::Unary(f64::abs)),
+    ("acos", Member::Unary(math::acos)),
+    ("asin", Member::Unary(math::asin)),
+    ("atan", Member::Unary(math::atan)),
+    ("atan2", Member::Fn(atan2)),
+    ("cbrt", Member::Unary(math::cbrt)),
+    ("ceil", Member::Unary(math::ceil)),
+    ("cos", Member::Unary(math::cos)),
+    ("exp", Member::Unary(math::exp)),
+    ("floor", Member::Unary(math::floor)),
+    ("hypot", Member::Fn(hypot)),
+    ("log", Member::Unary(math::ln)),
+    ("log10", Member::Unary(math::log10)),
+    ("log2", Member::Unary(math::log2)),
+    ("max", Member::Fn(max)),
+    ("min", Member::Fn(min)),
+    ("pow", Member::Fn(pow)),
+    ("random", Member::Fn(random)),
+    ("round", Member::Unary(round)),
+    ("sign", Member::Unary(sign)),
+    ("sin", Member::Unary(math::sin)),
+    ("sqrt", Member::Unary(math::sqrt)),
+    ("tan", Member::Unary(math::tan)),
+    ("trunc", Member::Unary(math::trunc)),
+]};
+
+const DIGITS: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+// The number `this`, or a TypeError
+fn this(js: &mut Js<'_, '_>, argv: JsOff) -> JsVal {
+    let x = js.this_arg(argv);
+    if v_type(x) == Type::NUM { x } else { js.mk_err(ErrorKind::Type, "not a number") }
+}
+
+fn num_arg(js: &mut Js<'_, '_>, argv: JsOff, argc: usize, i: usize) -> f64 {
+    let v = js.arg(argv, argc, i);
+    js.number_of(v)
+}
+
+// Integer argument, `default` if it is missing
+fn int_arg(js: &mut Js<'_, '_>, argv: JsOff, argc: usize, i: usize, default: f64) -> f64 {
+    if v_type(js.arg(argv, argc, i)) == Type::UNDEF { return default }
+    let d = num_arg(js, argv, argc, i);
+    if d.is_nan() { 0.0 } else { math::trunc(d) }
+}
+
+// `Number(v)`
+fn number(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    if argc == 0 { return tok_val(0.0) }
+    tok_val(num_arg(js, argv, argc, 0))
+}
+
+pub(crate) fn is_nan(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    make_bool(num_arg(js, argv, argc, 0).is_nan())
+}
+
+pub(crate) fn is_finite(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    make_bool(num_arg(js, argv, argc, 0).is_finite())
+}
+
+// The number at the beginning of the string, ignoring what follows
+pub(crate) fn parse_float(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = js.stringify(js.arg(argv, argc, 0));
+    if is_err(s) { return s }
+    let s = js.load_str(s).trim_start_matches(is_white);
+    let b = s.as_bytes();
+    let sign = matches!(b.first(), Some(b'+' | b'-')) as usize;
+    if b[sign..].starts_with(b"Infinity") {
+        return tok_val(if b[0] == b'-' { f64::NEG_INFINITY } else { f64::INFINITY })
+    }
+    match decimal_len(&b[sign..]) {
+        0 => tok_val(f64::NAN),
+        n => tok_val(s[..sign + n].parse().unwrap_or(f64::NAN)),
+    }
+}
+
+// The integer at the beginning of the string, in the radix given or
+// guessed from a `0x` prefix
+pub(crate) fn parse_int(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = js.stringify(js.arg(argv, argc, 0));
+    if is_err(s) { return s }
+    let mut radix = to_i32(num_arg(js, argv, argc, 1)) as u32;
+    let s = js.load_str(s).trim_start_matches(is_white);
+    let (neg, mut s) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let hex = matches!(s.as_bytes(), [b'0', b'x' | b'X', ..]);
+    if hex && (radix == 0 || radix == 16) {
+        radix = 16;
+        s = &s[2..];
+    }
+    if radix == 0 { radix = 10 }
+    if !(2..=36).contains(&radix) { return tok_val(f64::NAN) }
+
+    let d = match parse_digits(s.as_bytes(), radix) {
+        (_, 0) => f64::NAN,
+        // Decimals are rounded correctly, however long
+        (_, n) if radix == 10 => s[..n].parse().unwrap_or(f64::NAN),
+        (d, _) => d,
+    };
+    tok_val(if neg { -d } else { d })
+}
+
+// Whether the digits written are exactly halfway between two numbers with
+// `f` fraction digits, as in 0.125 for 2. JS rounds those up while Rust
+// rounds them to even
+struct Halfway {
+    f: usize,
+    frac: Option<usize>,
+    tie: bool,
+}
+
+impl Write for Halfway {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            match self.frac {
+                None => self.frac = (c == b'.').then_some(0),
+                Some(i) => {
+                    if i == self.f { self.tie = c == b'5' } else if i > self.f { self.tie &= c == b'0' }
+                    self.frac = Some(i + 1);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_fixed(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let x = this(js, argv);
+    if is_err(x) { return x }
+    let f = int_arg(js, argv, argc, 0, 0.0);
+    if !(0.0..=100.0).contains(&f) { return js.mk_err(ErrorKind::Range, "toFixed() digits must be between 0 and 100") }
+    let (x, f) = (v_num(x), f as usize);
+    if !x.is_finite() || x.abs() >= 1e21 { return js.stringify(tok_val(x)) }
+
+    // -0 prints as 0, and halfway numbers are nudged up in magnitude
+    let x = if x == 0.0 { 0.0 } else { x };
+    let mut half = Halfway { f, frac: None, tie: false };
+    let _ = write!(half, "{:.1100}", x);
+    let x = if half.tie { f64::from_bits(x.to_bits() + 1) } else { x };
+
+    let mut buf = [0u8; 128];
+    let mut out = Buf::new(&mut buf);
+    let _ = write!(out, "{:.*}", f, x);
+    let len = out.len();
+    js.mk_str(core::str::from_utf8(&buf[..len]).unwrap_or(""))
+}
+
+fn to_string(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let x = this(js, argv);
+    if is_err(x) { return x }
+    let radix = int_arg(js, argv, argc, 0, 10.0);
+    if !(2.0..=36.0).contains(&radix) { return js.mk_err(ErrorKind::Range, "toString() radix must be between 2 and 36") }
+    if radix == 10.0 || !v_num(x).is_finite() { return js.stringify(x) }
+    match radix_digits(v_num(x), radix, js.str_room()) {
+        Some(len) => js.take_str(js.str_start() + len),
+        None => js.mk_err(ErrorKind::Oom, "oom"),
+    }
+}
+
+fn put(out: &mut [u8], n: &mut usize, c: u8) -> Option<()> {
+    *out.get_mut(*n)? = c;
+    *n += 1;
+    Some(())
+}
+
+// Write a finite number in a radix other than 10 and return the length,
+// None if it doesn't fit. Fractions get as many digits as it takes to tell
+// the number from its neighbours. The fraction is written first, as
+// rounding it may carry into the integer part
+fn radix_digits(x: f64, radix: f64, out: &mut [u8]) -> Option<usize> {
+    let (neg, x) = (x < 0.0, x.abs());
+    let mut int = math::floor(x);
+    let mut frac = x - int;
+    let mut n = 0;
+
+    let mut delta = (0.5 * (f64::from_bits(x.to_bits() + 1) - x)).max(f64::from_bits(1));
+    if frac >= delta {
+        put(out, &mut n, b'.')?;
+        loop {
+            frac *= radix;
+            delta *= radix;
+            let digit = frac as usize;
+            put(out, &mut n, DIGITS[digit])?;
+            frac -= digit as f64;
+            if (frac > 0.5 || (frac == 0.5 && digit & 1 == 1)) && frac + delta > 1.0 {
+                // Round up, carrying over the digits written
+                loop {
+                    n -= 1;
+                    if n == 0 {
+                        int += 1.0;
+                        break
+                    }
+                    let d = DIGITS.iter().position(|&c| c == out[n]).unwrap_or(0);
+                    if d + 1 < radix as usize {
+                        out[n] = DIGITS[d + 1];
+                        n += 1;
+                        break
+                    }
+                }
+                break
+            }
+            if frac < delta { break }
+        }
+    }
+
+    // Integer digits, from the last one. Those beyond the precision of a
+    // double are zeros
+    let point = n;
+    while int / radix >= 9007199254740992.0 {
+        int /= radix;
+        put(out, &mut n, b'0')?;
+    }
+    loop {
+        let d = int % radix;
+        put(out, &mut n, DIGITS[d as usize])?;
+        int = (int - d) / radix;
+        if int < 1.0 { break }
+    }
+    out[..n].rotate_left(point);
+    out[..n - point].reverse();
+    if neg {
+        put(out, &mut n, b'-')?;
+        out[..n].rotate_right(1);
+    }
+    Some(n)
+}
+
+// JS rounds halves up, toward +Infinity, and keeps the sign of zero
+fn round(x: f64) -> f64 {
+    let f = math::floor(x);
+    let r = if x - f >= 0.5 { f + 1.0 } else { f };
+    if r == 0.0 && x < 0.0 { -0.0 } else { r }
+}
+
+fn sign(x: f64) -> f64 {
+    if x.is_nan() || x == 0.0 { x } else { 1.0f64.copysign(x) }
+}
+
+fn pow(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let (x, y) = (num_arg(js, argv, argc, 0), num_arg(js, argv, argc, 1));
+    tok_val(math::js_pow(x, y))
+}
+
+fn atan2(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let (y, x) = (num_arg(js, argv, argc, 0), num_arg(js, argv, argc, 1));
+    tok_val(math::atan2(y, x))
+}
+
+// Largest or smallest argument, NaN if any is. +0 is above -0
+fn extreme(js: &mut Js<'_, '_>, argv: JsOff, argc: usize, max: bool) -> JsVal {
+    let mut r = if max { f64::NEG_INFINITY } else { f64::INFINITY };
+    for i in 0..argc {
+        let d = num_arg(js, argv, argc, i);
+        if d.is_nan() { return tok_val(d) }
+        let zeros = d == 0.0 && r == 0.0 && d.is_sign_negative() != max;
+        if (max && d > r) || (!max && d < r) || zeros { r = d }
+    }
+    tok_val(r)
+}
+
+fn max(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    extreme(js, argv, argc, true)
+}
+
+fn min(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    extreme(js, argv, argc, false)
+}
+
+// Scaled by the largest argument so that squares don't overflow
+fn hypot(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let (mut m, mut nan) = (0.0f64, false);
+    for i in 0..argc {
+        let d = num_arg(js, argv, argc, i).abs();
+        if d.is_infinite() { return tok_val(d) }
+        nan |= d.is_nan();
+        m = m.max(d);
+    }
+    if nan { return tok_val(f64::NAN) }
+    if m == 0.0 { return tok_val(0.0) }
+    let mut sum = 0.0;
+    for i in 0..argc {
+        let d = num_arg(js, argv, argc, i) / m;
+        sum += d * d;
+    }
+    tok_val(math::sqrt(sum) * m)
+}
+
+fn random(js: &mut Js<'_, '_>, _argv: JsOff, _argc: usize) -> JsVal {
+    tok_val(js.random())
+}
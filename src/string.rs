@@ -0,0 +1,294 @@
+// String methods: `s.slice(1)` and friends, and the `String` object.
+//
+// Strings are UTF-8 in JS memory, while lengths and positions count UTF-16
+// code units as in JS. Methods read the string where it is and map
+// positions to byte offsets by walking it, which is direct for ASCII.
+// Results are cut out of the string, or written right above `brk` and
+// made an entity there.
+
+use core::iter::once;
+
+use crate::array::Key;
+use crate::builtin::{self, Member, Table};
+use crate::core::*;
+use crate::elk::Js;
+use crate::error::ErrorKind;
+use crate::math::trunc;
+
+pub(crate) const METHODS: Table = Table { call: None, members: &[
+    ("charCodeAt", Member::Fn(char_code_at)),
+    ("indexOf", Member::Fn(index_of)),
+    ("slice", Member::Fn(slice)),
+    ("substring", Member::Fn(substring)),
+    ("split", Member::Fn(split)),
+    ("toUpperCase", Member::Fn(to_upper_case)),
+    ("toLowerCase", Member::Fn(to_lower_case)),
+    ("trim", Member::Fn(trim)),
+    ("startsWith", Member::Fn(starts_with)),
+    ("endsWith", Member::Fn(ends_with)),
+    ("replace", Member::Fn(replace)),
+]};
+
+pub(crate) const STRING: Table = Table { call: Some(string), members: &[
+    ("fromCharCode", Member::Fn(from_char_code)),
+]};
+
+// Number of UTF-16 code units
+pub(crate) fn units(s: &str) -> usize {
+    if s.is_ascii() { return s.len() }
+    s.chars().map(char::len_utf16).sum()
+}
+
+// Byte offset of code unit `i`, the length if it is past the end. The
+// second unit of a surrogate pair maps to the start of its character
+fn offset(s: &str, i: usize) -> usize {
+    if s.is_ascii() { return i.min(s.len()) }
+    let mut n = 0;
+    for (pos, c) in s.char_indices() {
+        n += c.len_utf16();
+        if n > i { return pos }
+    }
+    s.len()
+}
+
+fn unit_at(s: &str, i: usize) -> Option<u16> {
+    if s.is_ascii() { return s.as_bytes().get(i).map(|&b| b as u16) }
+    s.encode_utf16().nth(i)
+}
+
+// Member of a string: a one unit string, the length or a method
+pub(crate) fn member(js: &mut Js<'_, '_>, s: JsVal, key: Key) -> JsVal {
+    match key {
+        Key::Index(i) => match unit_at(js.load_str(s), i as usize) {
+            Some(u) => {
+                let mut pos = js.str_start();
+                if !put_units(js, &mut pos, u, None).0 { return js.mk_err(ErrorKind::Oom, "oom") }
+                js.take_str(pos)
+            },
+            None => make_undef(),
+        },
+        Key::Length => tok_val(units(js.load_str(s)) as f64),
+        Key::Method(m) => builtin::member(builtin::STRING_METHODS, m),
+        Key::None => make_undef(),
+    }
+}
+
+// Write the character of code unit `u`, followed by `next` if they make a
+// surrogate pair, else lone surrogates become U+FFFD. Returns false if it
+// doesn't fit, and the number of units used
+fn put_units(js: &mut Js<'_, '_>, pos: &mut usize, u: u16, next: Option<u16>) -> (bool, usize) {
+    let (c, n) = match char::decode_utf16(once(u).chain(next)).next() {
+        Some(Ok(c)) => (c, c.len_utf16()),
+        _ => (char::REPLACEMENT_CHARACTER, 1),
+    };
+    (js.put(pos, c.encode_utf8(&mut [0; 4]).as_bytes()), n)
+}
+
+// The string `this`, or a TypeError
+fn this(js: &mut Js<'_, '_>, argv: JsOff) -> JsVal {
+    let s = js.this_arg(argv);
+    if v_type(s) == Type::STR { s } else { js.mk_err(ErrorKind::Type, "not a string") }
+}
+
+// Integer from a position argument, 0 if it is not a number
+fn integer(v: JsVal) -> f64 {
+    if v_type(v) != Type::NUM || v_num(v).is_nan() { 0.0 } else { trunc(v_num(v)) }
+}
+
+fn clamp(d: f64, len: usize) -> usize {
+    d.max(0.0).min(len as f64) as usize
+}
+
+// Position from a relative argument: negative ones count from the end
+fn relative(v: JsVal, len: usize, default: usize) -> usize {
+    if v_type(v) == Type::UNDEF { return default }
+    let d = integer(v);
+    if d < 0.0 { clamp(len as f64 + d, len) } else { clamp(d, len) }
+}
+
+fn char_code_at(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let i = integer(js.arg(argv, argc, 0));
+    let u = if i < 0.0 { None } else { unit_at(js.load_str(s), i as usize) };
+    tok_val(u.map_or(f64::NAN, |u| u as f64))
+}
+
+fn index_of(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let pat = js.stringify(js.arg(argv, argc, 0));
+    if is_err(pat) { return pat }
+    let (s, pat) = (js.load_str(s), js.load_str(pat));
+    let from = offset(s, clamp(integer(js.arg(argv, argc, 1)), units(s)));
+    tok_val(s[from..].find(pat).map_or(-1.0, |i| units(&s[..from + i]) as f64))
+}
+
+fn slice(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let str = js.load_str(s);
+    let len = units(str);
+    let start = relative(js.arg(argv, argc, 0), len, 0);
+    let end = relative(js.arg(argv, argc, 1), len, len).max(start);
+    let (start, end) = (offset(str, start), offset(str, end));
+    js.mk_substr(s, start, end)
+}
+
+fn substring(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let str = js.load_str(s);
+    let len = units(str);
+    let start = clamp(integer(js.arg(argv, argc, 0)), len);
+    let end = match js.arg(argv, argc, 1) {
+        v if v_type(v) == Type::UNDEF => len,
+        v => clamp(integer(v), len),
+    };
+    let (start, end) = (offset(str, start.min(end)), offset(str, start.max(end)));
+    js.mk_substr(s, start, end)
+}
+
+// Split on a separator, or into characters if it is empty. A limit caps
+// the number of parts
+fn split(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let res = js.mk_arr(0);
+    if is_err(res) { return res }
+    let limit = match js.arg(argv, argc, 1) {
+        v if v_type(v) == Type::UNDEF => JsOff::MAX,
+        v => to_i32(v_num(v)) as JsOff,
+    };
+    let sep = js.arg(argv, argc, 0);
+    if limit == 0 { return res }
+    if v_type(sep) == Type::UNDEF { return js.arr_set(res, 0, s) }
+    let sep = js.stringify(sep);
+    if is_err(sep) { return sep }
+
+    let (len, sep_len) = (js.load_str(s).len(), js.load_str(sep).len());
+    let mut start = 0;
+    while js.arr_len(res) < limit {
+        let str = js.load_str(s);
+        let (end, next) = match sep_len {
+            0 if start == len => break,
+            0 => (start + str[start..].chars().next().map_or(1, char::len_utf8), 0),
+            _ => match str[start..].find(js.load_str(sep)) {
+                Some(i) => (start + i, sep_len),
+                None => (len, 0),
+            },
+        };
+        let part = js.mk_substr(s, start, end);
+        if is_err(part) { return part }
+        let r = js.arr_set(res, js.arr_len(res), part);
+        if is_err(r) { return r }
+        if next == 0 && sep_len > 0 { break }
+        start = end + next;
+    }
+    res
+}
+
+// Map every character, e.g. to upper case
+fn convert<I: Iterator<Item = char>>(js: &mut Js<'_, '_>, argv: JsOff, f: fn(char) -> I) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let (mut i, len) = (0, js.load_str(s).len());
+    let mut pos = js.str_start();
+    while i < len {
+        let c = js.load_str(s)[i..].chars().next().unwrap_or_default();
+        i += c.len_utf8();
+        for c in f(c) {
+            if !js.put(&mut pos, c.encode_utf8(&mut [0; 4]).as_bytes()) { return js.mk_err(ErrorKind::Oom, "oom") }
+        }
+    }
+    js.take_str(pos)
+}
+
+fn to_upper_case(js: &mut Js<'_, '_>, argv: JsOff, _argc: usize) -> JsVal {
+    convert(js, argv, char::to_uppercase)
+}
+
+fn to_lower_case(js: &mut Js<'_, '_>, argv: JsOff, _argc: usize) -> JsVal {
+    convert(js, argv, char::to_lowercase)
+}
+
+fn trim(js: &mut Js<'_, '_>, argv: JsOff, _argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let str = js.load_str(s);
+    let rest = str.trim_start_matches(is_white);
+    let start = str.len() - rest.len();
+    let end = start + rest.trim_end_matches(is_white).len();
+    js.mk_substr(s, start, end)
+}
+
+fn starts_with(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let pat = js.stringify(js.arg(argv, argc, 0));
+    if is_err(pat) { return pat }
+    let (s, pat) = (js.load_str(s), js.load_str(pat));
+    let start = offset(s, clamp(integer(js.arg(argv, argc, 1)), units(s)));
+    make_bool(s[start..].starts_with(pat))
+}
+
+fn ends_with(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let pat = js.stringify(js.arg(argv, argc, 0));
+    if is_err(pat) { return pat }
+    let (s, pat) = (js.load_str(s), js.load_str(pat));
+    let end = match js.arg(argv, argc, 1) {
+        v if v_type(v) == Type::UNDEF => s.len(),
+        v => offset(s, clamp(integer(v), units(s))),
+    };
+    make_bool(s[..end].ends_with(pat))
+}
+
+// Replace the first match of a string pattern. The replacement is inserted
+// as is, or it is a function called with the match, its position and the
+// string, whose result is inserted
+fn replace(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let s = this(js, argv);
+    if is_err(s) { return s }
+    let pat = js.stringify(js.arg(argv, argc, 0));
+    if is_err(pat) { return pat }
+    let (str, pat_str) = (js.load_str(s), js.load_str(pat));
+    let Some(at) = str.find(pat_str) else { return s };
+    let (idx, end) = (units(&str[..at]), at + pat_str.len());
+
+    let rep = js.arg(argv, argc, 1);
+    let rep = if is_func(rep) { js.call_fn(rep, make_undef(), &[pat, tok_val(idx as f64), s]) } else { rep };
+    if is_err(rep) { return rep }
+    let rep = js.stringify(rep);
+    if is_err(rep) { return rep }
+
+    // The callback may have run GC, `this` is up to date on the stack
+    let (off, len) = js.v_str(js.this_arg(argv));
+    let mut pos = js.str_start();
+    let ok = js.put_mem(&mut pos, (off, at as JsOff))
+        && js.put_mem(&mut pos, js.v_str(rep))
+        && js.put_mem(&mut pos, (off + end as JsOff, len - end as JsOff));
+    if !ok { return js.mk_err(ErrorKind::Oom, "oom") }
+    js.take_str(pos)
+}
+
+// `String(v)`
+fn string(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    if argc == 0 { return js.mk_str("") }
+    js.stringify(js.arg(argv, argc, 0))
+}
+
+// `String.fromCharCode(...codes)`, from UTF-16 code units
+fn from_char_code(js: &mut Js<'_, '_>, argv: JsOff, argc: usize) -> JsVal {
+    let code = |js: &Js<'_, '_>, i: usize| to_i32(v_num(js.arg(argv, argc, i))) as u16;
+    let mut pos = js.str_start();
+    let mut i = 0;
+    while i < argc {
+        let next = (i + 1 < argc).then(|| code(js, i + 1));
+        let (ok, n) = put_units(js, &mut pos, code(js, i), next);
+        if !ok { return js.mk_err(ErrorKind::Oom, "oom") }
+        i += n;
+    }
+    js.take_str(pos)
+}
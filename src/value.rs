@@ -0,0 +1,205 @@
+// Js values as seen from Rust.
+//
+// A `Value` is a NaN-boxed word like the ones the engine uses internally,
+// wrapped so that it can't be confused with a plain number or a memory
+// offset. Primitives are stored as is. Strings, objects and functions are
+// rooted: the engine keeps them in a stack slot, where GC finds and updates
+// them, and the value holds the slot offset. Values carry the brand of the
+// instance they came from, a lifetime unique to the closure given to
+// `Js::new` or `Js::scope`, so that they can't be used with another
+// instance nor outlive their slot.
+
+use core::fmt;
+use core::marker::PhantomData;
+
+use crate::core::*;
+use crate::elk::Js;
+
+/// Kind of a Js value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Object,
+    String,
+    Undefined,
+    Null,
+    Number,
+    Boolean,
+    Function,
+    Error,
+    Array,
+}
+
+impl From<Type> for Kind {
+    fn from(typ: Type) -> Kind {
+        match typ {
+            Type::OBJ | Type::PROP | Type::REF => Kind::Object,
+            Type::STR => Kind::String,
+            Type::UNDEF => Kind::Undefined,
+            Type::NULL => Kind::Null,
+            Type::NUM => Kind::Number,
+            Type::BOOL => Kind::Boolean,
+            Type::FUNC | Type::CODEREF | Type::RFUNC | Type::BUILTIN => Kind::Function,
+            Type::ERR => Kind::Error,
+            Type::ARR => Kind::Array,
+        }
+    }
+}
+
+/// A value owned by a `Js` instance. Strings, objects and functions stay
+/// alive, and valid across garbage collections, until the closure that
+/// created them returns: the one passed to `Js::new`, or to `Js::scope`
+/// to release them earlier. Using a value with another instance or after
+/// that doesn't compile
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct Value<'id> {
+    val: JsVal,
+    // Invariant, so that a value can't be passed to an instance with
+    // another brand
+    _js: PhantomData<fn(&'id ()) -> &'id ()>,
+}
+
+impl<'id> Value<'id> {
+    pub(crate) fn from_raw(val: JsVal) -> Value<'id> {
+        Value { val, _js: PhantomData }
+    }
+
+    pub(crate) fn raw(self) -> JsVal {
+        self.val
+    }
+
+    /// Return the value kind
+    pub fn kind(self) -> Kind {
+        v_type(self.val).into()
+    }
+
+    /// Check if the value is an error
+    pub fn is_err(self) -> bool {
+        is_err(self.val)
+    }
+}
+
+impl fmt::Debug for Value<'_> {
+    // Primitives are printed in full, heap values only by their kind:
+    // reading them needs the owning instance, see `Js::show`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match v_type(self.val) {
+            Type::NUM => f.debug_tuple("Number").field(&v_num(self.val)).finish(),
+            Type::BOOL => f.debug_tuple("Boolean").field(&(v_data(self.val) != 0)).finish(),
+            _ => write!(f, "{:?}", self.kind()),
+        }
+    }
+}
+
+/// A value as stored in JS memory, shown by the heap inspector. Heap
+/// values hold the offset of their entity rather than a stack slot, so
+/// unlike `Value` they can't be passed to the API
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RawValue(pub(crate) JsVal);
+
+impl RawValue {
+    /// Return the value kind
+    pub fn kind(self) -> Kind {
+        v_type(self.0).into()
+    }
+}
+
+impl fmt::Debug for RawValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match v_type(self.0) {
+            Type::NUM => f.debug_tuple("Number").field(&v_num(self.0)).finish(),
+            Type::BOOL => f.debug_tuple("Boolean").field(&(v_data(self.0) != 0)).finish(),
+            Type::UNDEF | Type::NULL | Type::ERR => write!(f, "{:?}", self.kind()),
+            _ => write!(f, "{:?}@{:#x}", self.kind(), v_data(self.0)),
+        }
+    }
+}
+
+/// Iterator over the properties of an object, as `(key, value)` pairs,
+/// returned by `Js::props`. Keys are strings
+pub struct Props<'j, 'a, 'id> {
+    js: &'j mut Js<'a, 'id>,
+    next: usize,
+}
+
+impl<'j, 'a, 'id> Props<'j, 'a, 'id> {
+    // `next` is the offset of the first property. GC can't run while the
+    // iterator borrows the instance, so offsets stay put
+    pub(crate) fn new(js: &'j mut Js<'a, 'id>, next: usize) -> Props<'j, 'a, 'id> {
+        Props { js, next }
+    }
+}
+
+impl<'id> Iterator for Props<'_, '_, 'id> {
+    type Item = (Value<'id>, Value<'id>);
+
+    fn next(&mut self) -> Option<(Value<'id>, Value<'id>)> {
+        if self.next == 0 { return None }
+        let off = self.next;
+        let (next, key, val) = self.js.prop_at(off);
+        self.next = next;
+        let key = self.js.root(make_val(Type::STR, key as u64));
+        Some((key, self.js.root(val)))
+    }
+}
+
+/// Formatting adapter returned by `Js::show`, prints a value like `Js::str`
+pub struct Show<'j, 'a, 'id> {
+    pub(crate) js: &'j Js<'a, 'id>,
+    pub(crate) val: Value<'id>,
+}
+
+impl fmt::Display for Show<'_, '_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.js.fmt_val(self.js.rooted(self.val), f)
+    }
+}
+
+impl fmt::Debug for Show<'_, '_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            let cases = [
+                ("1 + 2", Kind::Number),
+                ("'a'", Kind::String),
+                ("undefined", Kind::Undefined),
+                ("null", Kind::Null),
+                ("1 < 2", Kind::Boolean),
+                ("[1, 2]", Kind::Array),
+                ("[].push", Kind::Function),
+            ];
+            for (code, kind) in cases {
+                assert_eq!(js.eval(code).unwrap().kind(), kind, "{}", code);
+            }
+            assert_eq!(js.make_err("x").kind(), Kind::Error);
+            assert_eq!(js.glob().kind(), Kind::Object);
+            let f = js.make_fun(|| 1);
+            assert_eq!(f.kind(), Kind::Function);
+        }).unwrap();
+    }
+
+    #[test]
+    fn formatting() {
+        let mut buf = [0u8; 1024];
+        Js::new(&mut buf, |js| {
+            assert_eq!(format!("{:?}", Js::make_num(1.5)), "Number(1.5)");
+            assert_eq!(format!("{:?}", Js::make_true()), "Boolean(true)");
+            assert_eq!(format!("{:?}", Js::make_null()), "Null");
+            assert_eq!(format!("{:?}", js.glob()), "Object");
+            let v = js.eval("let s = 'x' + 'y'; s").unwrap();
+            assert_eq!(format!("{}", js.show(v)), "\"xy\"");
+            assert_eq!(format!("{:?}", js.show(js.glob())), "{\"s\":\"xy\"}");
+            assert_eq!(js.show(Js::make_num(1e21)).to_string(), js.str(Js::make_num(1e21)));
+        }).unwrap();
+    }
+}